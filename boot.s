@@ -0,0 +1,53 @@
+; The boot ROM: loads sector 0 of the disk to address 0 and runs it.
+; Needs the disk at 0x50 (its default address) and the ROM control register at 0x5F.
+;
+; The sector is copied to 00..FB, writes into the ROM go to the RAM under it.
+; FC..FF get a little trampoline which unmaps the ROM and jumps to 0,
+; so the program starts like after a reset, with the registers and the flags cleared
+; and without the interrupt the disk requested when the read was done.
+; If the disk can't be read the ROM halts with r0 = FF.
+
+        LDI r0, 0
+        OUT r0, 0x52        ; sector 0
+        LDI r0, 1
+        OUT r0, 0x51        ; read it into the buffer
+        LDI r1, 1
+wait:   IN r0, 0x50
+        AND r1, r0          ; busy?
+        JMP NZ, wait
+
+        IN r0, 0x50
+        LDI r1, 2
+        AND r1, r0          ; error?
+        JMP NZ, failed
+
+        LDI r0, 0
+        OUT r0, 0x53        ; from the start of the buffer
+        LDI r1, 0           ; to address 0
+        LDI r2, 0
+        LDI r3, 0xFD
+copy:   IN r0, 0x54
+        STR r0, r1
+        SUB r2, r1          ; r1 - 0 + 1
+        CMP r3, r1          ; zero at r1 = FC
+        JMP NZ, copy
+
+        ; OUT r0, 0x5F and JMP 0, with r0 = 0 that clears the ROM enable bit
+        LDI r0, 0x18
+        ST r0, 0xFC
+        LDI r0, 0x5F
+        ST r0, 0xFD
+        LDI r0, 0xE0
+        ST r0, 0xFE
+        LDI r0, 0
+        ST r0, 0xFF
+
+        LDI r1, 0           ; r0 and r2 are 0 already
+        LDI r3, 0
+        FSWAP r3            ; clears the flags
+        LDI r3, 0
+        CI                  ; the disk interrupt, EI in the program would enter it
+        JMP 0xFC
+
+failed: LDI r0, 0xFF
+        HLT
@@ -0,0 +1,11 @@
+; Booted by boot_interrupt_test.toml, enables interrupts right away.
+; The boot ROM must not leave the interrupt of the disk read pending, so the handler is never entered.
+        LDI r0, handler
+        SIA r0
+        EI
+        NOP
+        NOP
+        HLT
+
+handler: LDI r1, 0x55
+        HLT
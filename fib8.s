@@ -0,0 +1,6 @@
+; Fibonacci numbers in r2 until the 8 bit carry.
+        LDI r0, 1
+loop:   MOV r1, r2      ; r2 = previous
+        ADD r0, r1      ; r1 = r0 + r1
+        MOV r2, r0      ; r0 = previous
+        JMP NC, loop
@@ -0,0 +1,17 @@
+use crate::tools::assembler::Assembler;
+
+/// The source of the boot ROM, `boot.s` in the repository.
+pub const SOURCE: &str = include_str!("../boot.s");
+
+/// The IO address the boot ROM expects the disk at, the default one of the disk device.
+pub const DISK_ADDRESS: u8 = 0x50;
+
+/// The IO address the boot ROM expects the ROM control register at.
+pub const ROM_CONTROL: u8 = 0x5F;
+
+/// Assembles the boot ROM. It loads sector 0 of the disk to address 0 and jumps there with the ROM unmapped,
+/// so any program of up to 252 bytes boots like it was the ROM, see `boot.s` for the details.
+/// The machine needs the disk at [`DISK_ADDRESS`] and the ROM control register at [`ROM_CONTROL`].
+pub fn rom() -> Vec<u8> {
+    Assembler::assemble(SOURCE).expect("the boot ROM assembles")
+}
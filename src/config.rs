@@ -0,0 +1,250 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use clap::ValueEnum;
+use serde::Deserialize;
+use crate::boot;
+use crate::devices::block_storage::BlockStorage;
+use crate::devices::device::Device;
+use crate::devices::interrupt_controller::InterruptController;
+use crate::devices::stdout_ascii_buffer::{CharIOBuffer, BUFFER_SIZE};
+use crate::devices::telnet_terminal::TelnetTerminal;
+use crate::devices::timer::IntervalTimer;
+use crate::helium::faults::FaultPolicy;
+use crate::helium::io_controller::IOController;
+use crate::helium::memory::{BankLayout, MemoryRegion};
+use crate::scheduler::check_rate;
+
+/// This enum holds all available devices for use
+#[derive(ValueEnum, Deserialize, Copy, Clone, Debug, PartialOrd, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum DeviceType {
+    TermLink,
+    CharBuffer,
+    Timer,
+    /// The interrupt controller, it gives the other devices their own interrupt lines.
+    Pic,
+    /// A sector addressed disk, backed by a disk image file.
+    Disk,
+}
+
+impl DeviceType {
+    /// Where and how the device gets mounted by `--devices`.
+    pub fn default_config(self) -> DeviceConfig {
+        let (address, interrupt_code) = match self {
+            DeviceType::CharBuffer => (0, None),
+            DeviceType::TermLink => (51, Some(1)),
+            DeviceType::Timer => (54, Some(2)),
+            DeviceType::Pic => (64, None),
+            DeviceType::Disk => (80, Some(4)),
+        };
+
+        DeviceConfig { kind: self, address, size: None, interrupt_code, port: None, buffer_size: None, image: None, read_only: None }
+    }
+}
+
+/// A machine, read from a TOML file like this:
+/// ```toml
+/// rom = "hello.bin"        # relative to the config file
+/// step_rate = 100          # or clock_hz = 250
+/// stack = true             # enables the stack extension of the ISA
+/// nested_interrupts = 4    # up to 4 interrupts can interrupt each other
+/// rom_control = 0x5F       # the IO address of the ROM control register, see MemoryControl
+/// # boot = true            # instead of rom, boots from the disk with the boot ROM, rom_control is then 0x5F
+///
+/// [banks]                  # optional, see BankLayout
+/// size = 0x40
+/// count = 8
+/// select = 0x50
+///
+/// [faults]                 # optional, see FaultPolicy
+/// illegal_opcode = "halt"
+///
+/// [[region]]               # optional memory map, see MemoryRegion
+/// type = "device"
+/// start = 0xF0
+/// end = 0xF3
+/// io = 0x36
+///
+/// [[device]]
+/// type = "char-buffer"
+/// address = 0x00
+/// buffer_size = 50
+///
+/// [[device]]
+/// type = "term-link"
+/// address = 0x33
+/// interrupt_code = 1
+/// port = 5555
+///
+/// [[device]]
+/// type = "disk"
+/// address = 0x58          # the bank select register is at 0x50
+/// interrupt_code = 4
+/// image = "disk.img"       # relative to the config file
+/// read_only = true
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MachineConfig {
+    pub rom: Option<PathBuf>,
+    /// Uses the boot ROM, see [`boot`](crate::boot).
+    #[serde(default)]
+    pub boot: bool,
+    pub step_rate: Option<f32>,
+    /// Paces by clock cycles instead of instructions.
+    pub clock_hz: Option<f32>,
+    /// Enables the stack extension (SP, PUSH, POP, CALL, RET, XSP).
+    #[serde(default)]
+    pub stack: bool,
+    /// The depth of the interrupt return stack, nested interrupts are off without it.
+    pub nested_interrupts: Option<u8>,
+    /// Banked memory, the rom can then be bigger than 256 bytes.
+    pub banks: Option<BankLayout>,
+    /// Lets the program unmap the ROM through this IO address.
+    pub rom_control: Option<u8>,
+    /// The memory map, addresses outside of every region are ROM or RAM as usual.
+    #[serde(default, rename = "region")]
+    pub regions: Vec<MemoryRegion>,
+    /// What happens when the program misbehaves.
+    #[serde(default)]
+    pub faults: FaultPolicy,
+    #[serde(default, rename = "device")]
+    pub devices: Vec<DeviceConfig>,
+}
+
+/// A device and where it's mounted in the IO address space.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceConfig {
+    #[serde(rename = "type")]
+    pub kind: DeviceType,
+    /// The first IO address of the device.
+    pub address: u8,
+    /// How many IO addresses the device takes, defaults to its address space.
+    pub size: Option<u8>,
+    pub interrupt_code: Option<u8>,
+    /// The TCP port of a term-link, 5555 by default.
+    pub port: Option<u16>,
+    /// The size of a char-buffer, 50 by default.
+    pub buffer_size: Option<u8>,
+    /// The disk image file of a disk.
+    pub image: Option<PathBuf>,
+    /// Rejects the writes of a disk, false by default.
+    pub read_only: Option<bool>,
+}
+
+impl MachineConfig {
+    /// Reads the config, the rom path is made relative to the config file.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Could not read machine config: {}", e))?;
+
+        let mut config: Self = toml::from_str(&text)
+            .map_err(|e| format!("Invalid machine config: {}", e))?;
+
+        if config.step_rate.is_some() && config.clock_hz.is_some() {
+            return Err("Invalid machine config: step_rate and clock_hz can't be used together".to_string());
+        }
+        for rate in [config.step_rate, config.clock_hz].into_iter().flatten() {
+            check_rate(rate).map_err(|e| format!("Invalid machine config: {}", e))?;
+        }
+        if config.boot && config.rom.is_some() {
+            return Err("Invalid machine config: rom and boot can't be used together".to_string());
+        }
+        if config.boot && config.rom_control.is_some_and(|address| address != boot::ROM_CONTROL) {
+            return Err(format!("Invalid machine config: the boot ROM needs the ROM control register at {:02X}", boot::ROM_CONTROL));
+        }
+        if config.nested_interrupts == Some(0) {
+            return Err("Invalid machine config: nested_interrupts has to be at least 1".to_string());
+        }
+        config.faults.check().map_err(|e| format!("Invalid machine config: {}", e))?;
+        if let Some(banks) = &config.banks {
+            banks.check().map_err(|e| format!("Invalid machine config: {}", e))?;
+        }
+
+        if let Some(dir) = path.parent() {
+            config.rom = config.rom.map(|rom| dir.join(rom));
+            for device in &mut config.devices {
+                device.image = device.image.as_ref().map(|image| dir.join(image));
+            }
+        }
+        Ok(config)
+    }
+}
+
+impl DeviceConfig {
+    /// Creates the device and mounts it.
+    pub fn mount(&self, io_ctl: &mut IOController) -> Result<(), String> {
+        self.check_options()?;
+        let interrupt_code = self.interrupt_code.unwrap_or(0);
+
+        match self.kind {
+            DeviceType::TermLink => {
+                let port = self.port.unwrap_or(5555);
+                self.mount_as(io_ctl, TelnetTerminal::bind(interrupt_code, port)?)
+            }
+            DeviceType::CharBuffer => {
+                let size = self.buffer_size.map_or(BUFFER_SIZE, |size| size as usize);
+                self.mount_as(io_ctl, CharIOBuffer::with_size(size))
+            }
+            DeviceType::Timer => self.mount_as(io_ctl, IntervalTimer::new(interrupt_code)),
+            DeviceType::Pic => self.mount_as(io_ctl, InterruptController::new()),
+            DeviceType::Disk => {
+                let image = self.image.as_ref().ok_or("A disk needs an image file")?;
+                self.mount_as(io_ctl, BlockStorage::open(image, self.read_only.unwrap_or(false), interrupt_code)?)
+            }
+        }
+    }
+
+    /// Like mount, but a term-link gets its input from a replay instead of a TCP server.
+    pub fn mount_replayed(&self, io_ctl: &mut IOController) -> Result<(), String> {
+        match self.kind {
+            DeviceType::TermLink => {
+                self.check_options()?;
+                self.mount_as(io_ctl, TelnetTerminal::replayed(self.interrupt_code.unwrap_or(0)))
+            }
+            _ => self.mount(io_ctl),
+        }
+    }
+
+    /// Mounts an already created device at the configured address.
+    pub fn mount_as<D>(&self, io_ctl: &mut IOController, device: D) -> Result<(), String>
+    where D: Device + 'static {
+        let size = self.size
+            .or(device.get_address_space())
+            .ok_or_else(|| format!("{:?} needs a size", self.kind))?;
+
+        let end = self.address.checked_add(size)
+            .ok_or_else(|| format!("{:?} at {:02X} doesn't fit into the IO address space", self.kind, self.address))?;
+
+        io_ctl.mount_device(self.address..end, device)
+    }
+
+    /// Rejects options which mean nothing to the device.
+    fn check_options(&self) -> Result<(), String> {
+        let disk_options = self.image.as_ref().map(|_| "image")
+            .or(self.read_only.map(|_| "read_only"));
+
+        let unused = match self.kind {
+            DeviceType::TermLink => self.buffer_size.map(|_| "buffer_size")
+                .or(disk_options),
+            DeviceType::CharBuffer => self.port.map(|_| "port")
+                .or(self.interrupt_code.map(|_| "interrupt_code"))
+                .or(disk_options),
+            DeviceType::Timer => self.port.map(|_| "port")
+                .or(self.buffer_size.map(|_| "buffer_size"))
+                .or(disk_options),
+            DeviceType::Pic => self.port.map(|_| "port")
+                .or(self.buffer_size.map(|_| "buffer_size"))
+                .or(self.interrupt_code.map(|_| "interrupt_code"))
+                .or(disk_options),
+            DeviceType::Disk => self.port.map(|_| "port")
+                .or(self.buffer_size.map(|_| "buffer_size")),
+        };
+
+        match unused {
+            Some(option) => Err(format!("{:?} has no {} option", self.kind, option)),
+            None => Ok(()),
+        }
+    }
+}
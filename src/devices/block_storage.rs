@@ -0,0 +1,332 @@
+use std::any::Any;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use crate::devices::device::Device;
+
+/// The size of a sector, the byte offset register covers exactly one.
+pub const SECTOR_SIZE: usize = 256;
+
+const STATUS_BUSY: u8 = 0b01;
+const STATUS_ERROR: u8 = 0b10;
+const STATUS_READ_ONLY: u8 = 0b100;
+
+const COMMAND_READ: u8 = 1;
+const COMMAND_WRITE: u8 = 2;
+
+/// How many steps a command takes, the program has to wait for the interrupt (or poll the busy bit).
+const COMMAND_STEPS: u8 = 4;
+
+/// A disk of up to 256 sectors, backed by a disk image file on the host.
+/// The image doesn't need to be full size, missing sectors read as zeros and writes make the file grow.
+///
+/// # Behaviour docs:
+/// The program fills or empties the sector buffer through the data port and issues commands to copy the buffer
+/// from or into a sector of the disk. A command keeps the disk busy for a few steps, commands issued while busy are ignored.
+/// The disk contents are not part of snapshots, only the registers and the buffer are.
+/// ## Address space:
+/// 00: status (read only, bit 0: busy, bit 1: the last command failed, bit 2: read only disk)
+/// 01: command (1: read the sector into the buffer, 2: write the buffer into the sector), reads the last command
+/// 02: sector number
+/// 03: byte offset in the buffer
+/// 04: data port, reads and writes the buffer at the byte offset, which then moves to the next byte
+///
+/// ## Interrupts: The device will send an interrupt every time a command finished (or failed),
+/// The interrupt code will be the configured one.
+#[derive(Debug)]
+pub struct BlockStorage {
+    interrupt_code: u8,
+    interrupt_queued: bool,
+
+    image: File,
+    read_only: bool,
+
+    status: u8,
+    command: u8,
+    sector: u8,
+    offset: u8,
+    buffer: [u8; SECTOR_SIZE],
+
+    /// Steps until the command is done, 0 if there is none.
+    steps_left: u8,
+}
+
+impl BlockStorage {
+    /// Opens the disk image, a writable one is created if it doesn't exist.
+    pub fn open(path: &Path, read_only: bool, code: u8) -> Result<Self, String> {
+        let image = OpenOptions::new()
+            .read(true)
+            .write(!read_only)
+            .create(!read_only)
+            .truncate(false)
+            .open(path)
+            .map_err(|e| format!("Could not open disk image {}: {}", path.display(), e))?;
+
+        Ok(Self {
+            interrupt_code: code,
+            interrupt_queued: false,
+
+            image,
+            read_only,
+
+            status: if read_only { STATUS_READ_ONLY } else { 0 },
+            command: 0,
+            sector: 0,
+            offset: 0,
+            buffer: [0; SECTOR_SIZE],
+
+            steps_left: 0,
+        })
+    }
+
+    /// Runs the command on the image, returns false if it failed.
+    fn execute(&mut self) -> bool {
+        let position = SeekFrom::Start(self.sector as u64 * SECTOR_SIZE as u64);
+
+        match self.command {
+            COMMAND_READ => {
+                self.buffer = [0; SECTOR_SIZE];
+                self.image.seek(position).is_ok() && Self::read_sector(&mut self.image, &mut self.buffer)
+            }
+            COMMAND_WRITE if !self.read_only => {
+                self.image.seek(position).is_ok()
+                    && self.image.write_all(&self.buffer).is_ok()
+                    && self.image.flush().is_ok()
+            }
+            _ => false,
+        }
+    }
+
+    /// Reads until the buffer is full or the image ends.
+    fn read_sector(image: &mut File, buffer: &mut [u8]) -> bool {
+        let mut filled = 0;
+        while filled < buffer.len() {
+            match image.read(&mut buffer[filled..]) {
+                Ok(0) => break,
+                Ok(read) => filled += read,
+                Err(_) => return false,
+            }
+        }
+        true
+    }
+}
+
+impl Device for BlockStorage {
+    fn init_device(&mut self) {
+        /* Pass */
+    }
+
+    fn startup(&mut self) {
+        /* Pass */
+    }
+
+    fn update_device(&mut self) {
+        if self.steps_left == 0 {
+            return;
+        }
+
+        self.steps_left -= 1;
+        if self.steps_left == 0 {
+            let succeeded = self.execute();
+
+            self.status &= !(STATUS_BUSY | STATUS_ERROR);
+            if !succeeded {
+                self.status |= STATUS_ERROR;
+            }
+            self.interrupt_queued = true;
+        }
+    }
+
+    fn draw_ui(&mut self, _no_gui: bool, debug: bool) -> Option<String> {
+        if debug {
+            Some(format!("Disk status: {:03b} command: {} sector: {:02X} offset: {:02X}",
+                         self.status, self.command, self.sector, self.offset))
+        } else {
+            None
+        }
+    }
+
+    fn has_interrupt_request(&mut self) -> Option<(u8, String)> {
+        if self.interrupt_queued {
+            self.interrupt_queued = false;
+            Some((self.interrupt_code, "Disk command done".to_string()))
+        } else {
+            None
+        }
+    }
+
+    fn reset_device(&mut self) {
+        self.interrupt_queued = false;
+        self.status = if self.read_only { STATUS_READ_ONLY } else { 0 };
+        self.command = 0;
+        self.sector = 0;
+        self.offset = 0;
+        self.buffer = [0; SECTOR_SIZE];
+        self.steps_left = 0;
+    }
+
+    fn read(&mut self, address: u8) -> u8 {
+        match address {
+            0 => self.status,
+            1 => self.command,
+            2 => self.sector,
+            3 => self.offset,
+            4 => {
+                let value = self.buffer[self.offset as usize];
+                self.offset = self.offset.wrapping_add(1);
+                value
+            }
+
+            _ => 0
+        }
+    }
+
+    fn write(&mut self, address: u8, value: u8) {
+        match address {
+            1 if self.status & STATUS_BUSY == 0 => {
+                self.command = value;
+                self.status |= STATUS_BUSY;
+                self.steps_left = COMMAND_STEPS;
+            }
+            2 => self.sector = value,
+            3 => self.offset = value,
+            4 => {
+                self.buffer[self.offset as usize] = value;
+                self.offset = self.offset.wrapping_add(1);
+            }
+
+            _ => {}
+        }
+    }
+
+    fn get_address_space(&self) -> Option<u8> { Some(5) }
+
+    fn save_state(&self) -> Vec<u8> {
+        let mut state = vec![self.status, self.command, self.sector, self.offset, self.steps_left, self.interrupt_queued as u8];
+        state.extend(self.buffer);
+        state
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), String> {
+        let [status, command, sector, offset, steps_left, interrupt_queued, buffer @ ..] = state else {
+            return Err(format!("expected {} bytes of state, got {}", 6 + SECTOR_SIZE, state.len()));
+        };
+        self.buffer = buffer.try_into()
+            .map_err(|_| format!("expected {} bytes of state, got {}", 6 + SECTOR_SIZE, state.len()))?;
+
+        // The read only bit belongs to the image, not to the snapshot.
+        self.status = status & !STATUS_READ_ONLY | if self.read_only { STATUS_READ_ONLY } else { 0 };
+        self.command = *command;
+        self.sector = *sector;
+        self.offset = *offset;
+        self.steps_left = *steps_left;
+        self.interrupt_queued = *interrupt_queued != 0;
+        Ok(())
+    }
+
+    fn as_any(&self) -> &dyn Any { self }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+
+    /// A disk image in the temp directory which doesn't exist yet.
+    fn image_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("helium_disk_{}_{}.img", std::process::id(), name));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    /// Issues the command and steps until it's done, returns the status.
+    fn run_command(disk: &mut BlockStorage, sector: u8, command: u8) -> u8 {
+        disk.write(2, sector);
+        disk.write(1, command);
+        for _ in 1..COMMAND_STEPS {
+            disk.update_device();
+            assert_eq!(disk.read(0) & STATUS_BUSY, STATUS_BUSY);
+            assert!(disk.has_interrupt_request().is_none());
+        }
+        disk.update_device();
+        assert_eq!(disk.has_interrupt_request().map(|(code, _)| code), Some(4));
+        disk.read(0)
+    }
+
+    fn fill_buffer(disk: &mut BlockStorage, bytes: &[u8]) {
+        disk.write(3, 0);
+        for byte in bytes {
+            disk.write(4, *byte);
+        }
+    }
+
+    fn read_buffer(disk: &mut BlockStorage, count: usize) -> Vec<u8> {
+        disk.write(3, 0);
+        (0..count).map(|_| disk.read(4)).collect()
+    }
+
+    #[test]
+    fn a_written_sector_reads_back() {
+        let path = image_path("write_read");
+        let mut disk = BlockStorage::open(&path, false, 4).unwrap();
+
+        fill_buffer(&mut disk, b"DISK");
+        assert_eq!(run_command(&mut disk, 2, COMMAND_WRITE), 0);
+        assert_eq!(fs::metadata(&path).unwrap().len(), 3 * SECTOR_SIZE as u64);
+
+        fill_buffer(&mut disk, b"XXXX");
+        assert_eq!(run_command(&mut disk, 2, COMMAND_READ), 0);
+        assert_eq!(read_buffer(&mut disk, 4), b"DISK");
+
+        // The sectors before it were never written.
+        assert_eq!(run_command(&mut disk, 0, COMMAND_READ), 0);
+        assert_eq!(read_buffer(&mut disk, 4), [0; 4]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn commands_while_busy_are_ignored() {
+        let path = image_path("busy");
+        let mut disk = BlockStorage::open(&path, false, 4).unwrap();
+
+        disk.write(1, COMMAND_READ);
+        disk.write(1, COMMAND_WRITE);
+        assert_eq!(disk.read(1), COMMAND_READ);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn a_read_only_disk_rejects_writes() {
+        let path = image_path("read_only");
+        assert!(BlockStorage::open(&path, true, 4).is_err());
+
+        fs::write(&path, [7; SECTOR_SIZE]).unwrap();
+        let mut disk = BlockStorage::open(&path, true, 4).unwrap();
+        assert_eq!(disk.read(0), STATUS_READ_ONLY);
+
+        fill_buffer(&mut disk, b"DISK");
+        assert_eq!(run_command(&mut disk, 0, COMMAND_WRITE), STATUS_READ_ONLY | STATUS_ERROR);
+        assert_eq!(fs::read(&path).unwrap(), [7; SECTOR_SIZE]);
+
+        // The next command clears the error.
+        assert_eq!(run_command(&mut disk, 0, COMMAND_READ), STATUS_READ_ONLY);
+        assert_eq!(read_buffer(&mut disk, 2), [7, 7]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn sectors_past_the_end_read_as_zeros() {
+        let path = image_path("past_end");
+        fs::write(&path, [7; SECTOR_SIZE + 2]).unwrap();
+        let mut disk = BlockStorage::open(&path, false, 4).unwrap();
+
+        assert_eq!(run_command(&mut disk, 1, COMMAND_READ), 0);
+        assert_eq!(read_buffer(&mut disk, 4), [7, 7, 0, 0]);
+
+        fill_buffer(&mut disk, &[1; 4]);
+        assert_eq!(run_command(&mut disk, 9, COMMAND_READ), 0);
+        assert_eq!(read_buffer(&mut disk, 4), [0; 4]);
+        fs::remove_file(&path).unwrap();
+    }
+}
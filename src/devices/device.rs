@@ -1,6 +1,17 @@
 use std::any::Any;
 use std::fmt::Debug;
 
+/// Input which reached a device from outside the machine, see [`Device::take_input`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DeviceInput {
+    /// A client connected.
+    Connected,
+    /// The client went away.
+    Disconnected,
+    /// A byte was received.
+    Byte(u8),
+}
+
 /// A trait designed to hold all behaviour required for each device.
 pub trait Device: Debug {
     /// Only gets called when the device is registered into the IO Controller
@@ -12,6 +23,9 @@ pub trait Device: Debug {
     /// Should handle all updates, like UI/state
     fn update_device(&mut self);
     
+    /// Called after update_device with the clock cycles the instruction took, for devices which count time.
+    fn cycles_elapsed(&mut self, _cycles: u8) {}
+
     /// After each iteration of the CPU a UI will be drawn, 
     /// this can be turned off but some components are UI so that's optional.
     /// basically if you want a cool UI you can have it.
@@ -21,11 +35,18 @@ pub trait Device: Debug {
     /// Interrupt signals also take an "interrupt code" which is just a byte of info.
     /// **Note:** interrupt codes get OR-ed into one byte,
     /// so if 2 devices interrupt their codes will be codeA | codeB.
-    /// This should be taken into design consideration.
+    /// This should be taken into design consideration, or an interrupt controller has to be mounted,
+    /// then the code is ignored and the CPU gets the line of the device instead.
     ///
     /// a Log message can be given too.
     fn has_interrupt_request(&mut self) -> Option<(u8, String)>;
 
+    /// Only for interrupt controllers: called with the line of every device which requested an interrupt.
+    fn raise_line(&mut self, _line: u8) {}
+
+    /// Only for interrupt controllers: called when the CPU enters the interrupt they requested.
+    fn acknowledge_interrupt(&mut self) {}
+
     /// Called when a system-wide reset occurs
     fn reset_device(&mut self);
 
@@ -40,6 +61,20 @@ pub trait Device: Debug {
     /// If its unknown or there is no need for this, just leave it be.
     fn get_address_space(&self) -> Option<u8> { None }
 
+    /// Returns everything the device needs to continue from where it is, used by snapshots.
+    /// Devices without state can leave it be.
+    fn save_state(&self) -> Vec<u8> { Vec::new() }
+
+    /// Restores the state returned by save_state.
+    fn load_state(&mut self, _state: &[u8]) -> Result<(), String> { Ok(()) }
+
+    /// Returns and forgets the input which came from outside the machine since the last call, used for recording runs.
+    /// Only devices which talk to the outside world need this.
+    fn take_input(&mut self) -> Vec<DeviceInput> { Vec::new() }
+
+    /// Feeds recorded input to the device, as if it came from the outside world.
+    fn replay_input(&mut self, _input: DeviceInput) {}
+
     /// Required for finding systems, just return self.
     fn as_any(&self) -> &dyn Any;
 
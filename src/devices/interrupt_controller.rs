@@ -0,0 +1,232 @@
+use std::any::Any;
+use crate::devices::device::Device;
+
+/// How many interrupt lines there are, one bit of the registers each.
+pub const LINES: usize = 8;
+
+/// Read by the EOI register when nothing is in service.
+const NO_LINE: u8 = 0xFF;
+
+/// A programmable interrupt controller, every other device gets its own interrupt line (in mounting order),
+/// so interrupts don't get OR-ed together anymore. The interrupt code the CPU gets (and GIC reads) is the line number.
+///
+/// # Behaviour docs:
+/// A request of a device sets its pending bit. The unmasked pending line with the best priority is requested from the CPU,
+/// unless a line with the same or a better priority is in service. When the CPU enters the interrupt, the line moves
+/// from pending to in service, until the handler writes the EOI register.
+/// ## Address space:
+/// 00: pending (read, writing 1 bits clears them)
+/// 01: in service (read only)
+/// 02: mask (1 bits mask the lines)
+/// 03: EOI (writing ends the best in-service line, reading gives that line or FF)
+/// 04 - 0B: the priority of line 0 - 7, lower wins, ties go to the lower line (defaults to the line number)
+#[derive(Debug)]
+pub struct InterruptController {
+    pending: u8,
+    in_service: u8,
+    mask: u8,
+    priorities: [u8; LINES],
+}
+
+impl InterruptController {
+    pub fn new() -> Self {
+        Self {
+            pending: 0,
+            in_service: 0,
+            mask: 0,
+            priorities: Self::default_priorities(),
+        }
+    }
+
+    fn default_priorities() -> [u8; LINES] {
+        std::array::from_fn(|line| line as u8)
+    }
+
+    /// The lines set in the bits, best priority first.
+    fn by_priority(&self, bits: u8) -> impl Iterator<Item = u8> {
+        let mut lines = (0..LINES as u8).filter(|line| bits & 1 << line != 0).collect::<Vec<u8>>();
+        lines.sort_by_key(|line| (self.priorities[*line as usize], *line));
+        lines.into_iter()
+    }
+
+    /// The line which should interrupt the CPU now.
+    fn candidate(&self) -> Option<u8> {
+        let line = self.by_priority(self.pending & !self.mask).next()?;
+
+        match self.by_priority(self.in_service).next() {
+            Some(serving) if self.priorities[serving as usize] <= self.priorities[line as usize] => None,
+            _ => Some(line),
+        }
+    }
+}
+
+impl Default for InterruptController {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Device for InterruptController {
+    fn init_device(&mut self) {
+        /* Pass */
+    }
+
+    fn startup(&mut self) {
+        /* Pass */
+    }
+
+    fn update_device(&mut self) {
+        /* Pass */
+    }
+
+    fn draw_ui(&mut self, _no_gui: bool, debug: bool) -> Option<String> {
+        if debug {
+            Some(format!("PIC pending: {:08b} in service: {:08b} mask: {:08b} priorities: {:?}",
+                         self.pending, self.in_service, self.mask, self.priorities))
+        } else {
+            None
+        }
+    }
+
+    fn has_interrupt_request(&mut self) -> Option<(u8, String)> {
+        self.candidate().map(|line| (line, format!("Line {}", line)))
+    }
+
+    fn reset_device(&mut self) {
+        self.pending = 0;
+        self.in_service = 0;
+        self.mask = 0;
+        self.priorities = Self::default_priorities();
+    }
+
+    fn read(&mut self, address: u8) -> u8 {
+        match address {
+            0 => self.pending,
+            1 => self.in_service,
+            2 => self.mask,
+            3 => self.by_priority(self.in_service).next().unwrap_or(NO_LINE),
+            4..=11 => self.priorities[address as usize - 4],
+
+            _ => 0
+        }
+    }
+
+    fn write(&mut self, address: u8, value: u8) {
+        match address {
+            0 => self.pending &= !value,
+            2 => self.mask = value,
+            3 => {
+                if let Some(line) = self.by_priority(self.in_service).next() {
+                    self.in_service &= !(1 << line);
+                }
+            }
+            4..=11 => self.priorities[address as usize - 4] = value,
+
+            _ => {}
+        }
+    }
+
+    fn get_address_space(&self) -> Option<u8> { Some(4 + LINES as u8) }
+
+    fn raise_line(&mut self, line: u8) {
+        self.pending |= 1 << line;
+    }
+
+    fn acknowledge_interrupt(&mut self) {
+        if let Some(line) = self.candidate() {
+            self.pending &= !(1 << line);
+            self.in_service |= 1 << line;
+        }
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let mut state = vec![self.pending, self.in_service, self.mask];
+        state.extend(self.priorities);
+        state
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), String> {
+        let [pending, in_service, mask, priorities @ ..] = state else {
+            return Err(format!("expected {} bytes of state, got {}", 3 + LINES, state.len()));
+        };
+        self.priorities = priorities.try_into()
+            .map_err(|_| format!("expected {} bytes of state, got {}", 3 + LINES, state.len()))?;
+
+        self.pending = *pending;
+        self.in_service = *in_service;
+        self.mask = *mask;
+        Ok(())
+    }
+
+    fn as_any(&self) -> &dyn Any { self }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn requested(pic: &mut InterruptController) -> Option<u8> {
+        pic.has_interrupt_request().map(|(line, _)| line)
+    }
+
+    #[test]
+    fn the_best_priority_wins_and_blocks_worse_lines() {
+        let mut pic = InterruptController::new();
+        pic.raise_line(1);
+        pic.raise_line(3);
+        assert_eq!(requested(&mut pic), Some(1));
+
+        pic.write(4 + 3, 0);
+        assert_eq!(requested(&mut pic), Some(3));
+
+        pic.acknowledge_interrupt();
+        assert_eq!((pic.read(0), pic.read(1), pic.read(3)), (0b0010, 0b1000, 3));
+        assert_eq!(requested(&mut pic), None);
+
+        // EOI
+        pic.write(3, 0);
+        assert_eq!((pic.read(1), pic.read(3)), (0, NO_LINE));
+        assert_eq!(requested(&mut pic), Some(1));
+    }
+
+    #[test]
+    fn ties_go_to_the_lower_line() {
+        let mut pic = InterruptController::new();
+        pic.write(4 + 2, 5);
+        pic.write(4 + 6, 5);
+        pic.raise_line(6);
+        pic.raise_line(2);
+        assert_eq!(requested(&mut pic), Some(2));
+    }
+
+    #[test]
+    fn masked_lines_stay_pending() {
+        let mut pic = InterruptController::new();
+        pic.write(2, 0b0100);
+        pic.raise_line(2);
+        assert_eq!(requested(&mut pic), None);
+        assert_eq!(pic.read(0), 0b0100);
+
+        pic.write(2, 0);
+        assert_eq!(requested(&mut pic), Some(2));
+
+        // Writing 1 bits clears pending lines.
+        pic.write(0, 0b0100);
+        assert_eq!(requested(&mut pic), None);
+    }
+
+    #[test]
+    fn the_state_round_trips() {
+        let mut pic = InterruptController::new();
+        pic.write(4, 7);
+        pic.write(2, 0b1000_0000);
+        pic.raise_line(0);
+        pic.acknowledge_interrupt();
+        pic.raise_line(5);
+
+        let mut restored = InterruptController::new();
+        restored.load_state(&pic.save_state()).unwrap();
+        assert_eq!(restored.save_state(), pic.save_state());
+        assert!(restored.load_state(&[0; 3]).is_err());
+    }
+}
@@ -1,3 +1,6 @@
 pub mod device;
+pub mod block_storage;
+pub mod interrupt_controller;
 pub mod stdout_ascii_buffer;
-pub mod telnet_terminal;
\ No newline at end of file
+pub mod telnet_terminal;
+pub mod timer;
\ No newline at end of file
@@ -1,13 +1,14 @@
 use std::any::Any;
 use crate::devices::device::Device;
 
-const BUFFER_SIZE: usize = 50;
+/// The size of the buffer if none is configured.
+pub const BUFFER_SIZE: usize = 50;
 
 
-/// A Basic device which has a BUFFER_SIZE long buffer where the CPU can write/read things, and it will get displayed.
+/// A Basic device which has a BUFFER_SIZE (or configured) long buffer where the CPU can write/read things, and it will get displayed.
 #[derive(Debug)]
 pub struct CharIOBuffer {
-    pub buffer: [u8; BUFFER_SIZE],
+    pub buffer: Vec<u8>,
 }
 
 // Helper func stolen from stack overflow
@@ -26,8 +27,13 @@ fn get_ascii_str(buffer: &[u8]) -> Result<&str, ()> {
 
 impl CharIOBuffer {
     pub fn new() -> Self {
+        Self::with_size(BUFFER_SIZE)
+    }
+
+    /// Creates a buffer of the given size, it takes up as many IO addresses.
+    pub fn with_size(size: usize) -> Self {
         Self {
-            buffer: [0; BUFFER_SIZE]
+            buffer: vec![0; size]
         }
     }
     
@@ -67,7 +73,7 @@ impl Device for CharIOBuffer {
     }
 
     fn reset_device(&mut self) {
-        self.buffer = [0; BUFFER_SIZE];
+        self.buffer.fill(0);
     }
 
     fn read(&mut self, address: u8) -> u8 {
@@ -75,7 +81,7 @@ impl Device for CharIOBuffer {
     }
 
     fn write(&mut self, address: u8, value: u8) {
-        if address + 1 > self.buffer.len() as u8 {
+        if address as usize >= self.buffer.len() {
             return;
         }
         
@@ -83,7 +89,19 @@ impl Device for CharIOBuffer {
     }
 
     fn get_address_space(&self) -> Option<u8> {
-        Some(BUFFER_SIZE as u8)
+        Some(self.buffer.len() as u8)
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        self.buffer.clone()
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), String> {
+        if state.len() != self.buffer.len() {
+            return Err(format!("expected a {} byte buffer, got {} bytes", self.buffer.len(), state.len()));
+        }
+        self.buffer.copy_from_slice(state);
+        Ok(())
     }
 
     fn as_any(&self) -> &dyn Any {
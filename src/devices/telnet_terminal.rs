@@ -1,8 +1,9 @@
 use std::any::Any;
+use std::collections::VecDeque;
 use std::io::{Read, Write};
 use std::io::ErrorKind::WouldBlock;
 use std::net::{TcpListener, TcpStream};
-use crate::devices::device::Device;
+use crate::devices::device::{Device, DeviceInput};
 
 /// The goal of this device is to have the ability to connect to a port and act like a terminal
 /// The CPU will be able to get information about the connection, like is_connected or last char, etc
@@ -16,42 +17,148 @@ use crate::devices::device::Device;
 ///
 /// ## Interrupts: The device will send an interrupt if the connection_state or last_char changes
 /// The interrupt code will be the configured one.
+///
+/// Instead of a TCP server the terminal can also run from a script (see [`TelnetTerminal::scripted`]),
+/// then a client is connected from the start, the input is typed by the script and the output is kept.
+/// A replayed terminal (see [`TelnetTerminal::replayed`]) has neither, it only gets the input of a recording.
 #[derive(Debug)]
 pub struct TelnetTerminal {
     interrupt_code: u8,
     interrupt_queued: bool,
     interrupt_log: String,
 
-    tcp_listener: TcpListener,
+    tcp_listener: Option<TcpListener>,
+    script: Option<Script>,
 
     connection_state: u8, // 1: connected, 0: not
     last_char: u8,
     char_to_write: Option<u8>,
 
-    active_connection: Option<TcpStream>
+    active_connection: Option<TcpStream>,
+    /// The input since the last take_input call.
+    input: Vec<DeviceInput>,
+}
+
+/// The scripted client of the terminal.
+#[derive(Debug)]
+struct Script {
+    input: VecDeque<u8>,
+    output: Vec<u8>,
+    /// The client waits with the next byte until the CPU read the last one.
+    char_read: bool,
 }
 
 impl TelnetTerminal {
-    pub fn new(code: u8, port: u16) -> Self {
+    /// Starts the TCP server on the port, fails if the port is taken.
+    pub fn bind(code: u8, port: u16) -> Result<Self, String> {
+        let listener = TcpListener::bind(format!("127.0.0.1:{}", port))
+            .map_err(|e| format!("Could not start the terminal server on port {}: {}", port, e))?;
+
+        Ok(Self {
+            interrupt_code: code,
+            interrupt_queued: false,
+            tcp_listener: Some(listener),
+            script: None,
+            interrupt_log: String::new(),
+
+            connection_state: 0,
+            last_char: 0,
+            char_to_write: None,
+
+            active_connection: None,
+            input: Vec::new(),
+        })
+    }
+
+    /// Creates a terminal without a TCP server, the given input gets typed in one byte at a time,
+    /// each as soon as the CPU read the previous one from last_char.
+    pub fn scripted(code: u8, input: &[u8]) -> Self {
         Self {
             interrupt_code: code,
             interrupt_queued: false,
-            tcp_listener: TcpListener::bind(format!("127.0.0.1:{}", port)).unwrap(),
+            tcp_listener: None,
+            script: Some(Script { input: input.iter().copied().collect(), output: Vec::new(), char_read: true }),
             interrupt_log: String::new(),
 
             connection_state: 0,
             last_char: 0,
             char_to_write: None,
 
-            active_connection: None
+            active_connection: None,
+            input: Vec::new(),
+        }
+    }
+
+    /// Creates a terminal without a TCP server, which only gets the input fed by [`Device::replay_input`].
+    /// The output goes nowhere.
+    pub fn replayed(code: u8) -> Self {
+        Self {
+            interrupt_code: code,
+            interrupt_queued: false,
+            tcp_listener: None,
+            script: None,
+            interrupt_log: String::new(),
+
+            connection_state: 0,
+            last_char: 0,
+            char_to_write: None,
+
+            active_connection: None,
+            input: Vec::new(),
+        }
+    }
+
+    /// Everything the CPU wrote to a scripted terminal, empty with a real connection.
+    pub fn output(&self) -> &[u8] {
+        self.script.as_ref().map_or(&[], |script| &script.output)
+    }
+
+    fn update_scripted(&mut self) {
+        if self.connection_state == 0 {
+            self.connected();
+        }
+
+        let script = self.script.as_mut().expect("only called for scripted terminals");
+
+        if let Some(ch) = self.char_to_write.take() {
+            script.output.push(ch);
+        }
 
+        if script.char_read {
+            if let Some(ch) = script.input.pop_front() {
+                script.char_read = false;
+                self.received(ch);
+            }
         }
     }
+
+    fn connected(&mut self) {
+        self.connection_state = 1;
+        self.interrupt_queued = true;
+        self.interrupt_log.push_str("Connection Acquired ");
+        self.input.push(DeviceInput::Connected);
+    }
+
+    fn disconnected(&mut self, reason: &str) {
+        self.connection_state = 0;
+        self.interrupt_queued = true;
+        self.interrupt_log.push_str(reason);
+        self.input.push(DeviceInput::Disconnected);
+    }
+
+    fn received(&mut self, ch: u8) {
+        self.last_char = ch;
+        self.interrupt_queued = true;
+        self.interrupt_log.push_str(&format!("Byte received: {} ", self.last_char));
+        self.input.push(DeviceInput::Byte(ch));
+    }
 }
 
 impl Device for TelnetTerminal {
     fn init_device(&mut self) {
-        self.tcp_listener.set_nonblocking(true).expect("TCP Listener decided to not co-operate :/");
+        if let Some(listener) = &self.tcp_listener {
+            listener.set_nonblocking(true).expect("TCP Listener decided to not co-operate :/");
+        }
         self.update_device();
     }
 
@@ -60,16 +167,23 @@ impl Device for TelnetTerminal {
     }
 
     fn update_device(&mut self) {
+        if self.script.is_some() {
+            self.update_scripted();
+            return;
+        }
+
+        let Some(listener) = self.tcp_listener.as_ref() else {
+            // Replayed, the input gets fed from the outside.
+            self.char_to_write = None;
+            return;
+        };
 
         // IF no connections are present, try getting one
         if self.active_connection.is_none() {
-            if let Some(Ok(conn)) = self.tcp_listener.incoming().next() {
+            if let Some(Ok(conn)) = listener.incoming().next() {
                 conn.set_nonblocking(true).expect("E");
                 self.active_connection = Some(conn);
-                self.connection_state = 1;
-                self.interrupt_queued = true;
-
-                self.interrupt_log.push_str("Connection Acquired ");
+                self.connected();
             }
         }
 
@@ -86,10 +200,7 @@ impl Device for TelnetTerminal {
                         conn.shutdown(std::net::Shutdown::Both).unwrap();
                         self.active_connection = None;
 
-                        self.interrupt_queued = true;
-                        self.connection_state = 0;
-
-                        self.interrupt_log.push_str(&format!("Failed To Write: {} ", e));
+                        self.disconnected(&format!("Failed To Write: {} ", e));
                     },
                     Ok(_) => {}
                 }
@@ -109,10 +220,7 @@ impl Device for TelnetTerminal {
                     conn.shutdown(std::net::Shutdown::Both).unwrap();
                     self.active_connection = None;
 
-                    self.interrupt_queued = true;
-                    self.connection_state = 0;
-
-                    self.interrupt_log.push_str(&format!("Connection Error: {} ", e));
+                    self.disconnected(&format!("Connection Error: {} ", e));
 
                     2 // means that the connection died
                 }
@@ -121,9 +229,7 @@ impl Device for TelnetTerminal {
 
             if num_bytes == 1 {
                 // This should be the normal case
-                self.last_char = buffer[0];
-                self.interrupt_queued = true;
-                self.interrupt_log.push_str(&format!("Byte received: {} ", self.last_char));
+                self.received(buffer[0]);
             }
         }
     }
@@ -152,6 +258,10 @@ impl Device for TelnetTerminal {
     fn reset_device(&mut self) {}
 
     fn read(&mut self, address: u8) -> u8 {
+        if let (1, Some(script)) = (address, self.script.as_mut()) {
+            script.char_read = true;
+        }
+
         match address {
             0 => self.connection_state,
             1 => self.last_char,
@@ -170,5 +280,32 @@ impl Device for TelnetTerminal {
 
     fn get_address_space(&self) -> Option<u8> { Some(3) }
 
+    fn take_input(&mut self) -> Vec<DeviceInput> {
+        std::mem::take(&mut self.input)
+    }
+
+    fn replay_input(&mut self, input: DeviceInput) {
+        match input {
+            DeviceInput::Connected => self.connected(),
+            DeviceInput::Disconnected => self.disconnected("Connection Lost "),
+            DeviceInput::Byte(ch) => self.received(ch),
+        }
+    }
+
+    /// Only the last char is saved, the connection stays as it is.
+    fn save_state(&self) -> Vec<u8> {
+        vec![self.last_char]
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), String> {
+        match state {
+            [last_char] => {
+                self.last_char = *last_char;
+                Ok(())
+            }
+            _ => Err(format!("expected 1 byte of state, got {}", state.len())),
+        }
+    }
+
     fn as_any(&self) -> &dyn Any { self }
 }
\ No newline at end of file
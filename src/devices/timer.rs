@@ -0,0 +1,261 @@
+use std::any::Any;
+use crate::devices::device::Device;
+
+const CONTROL_ENABLED: u8 = 0b01;
+const CONTROL_PERIODIC: u8 = 0b10;
+const CONTROL_CYCLES: u8 = 0b100;
+
+/// A programmable interval timer, it counts CPU steps (every update is one step) or clock cycles,
+/// so it behaves the same at every step rate.
+///
+/// # Behaviour docs:
+/// The counter is loaded from reload and counts down every (prescaler + 1) steps,
+/// when it reaches 0 an interrupt is raised. A periodic timer reloads itself, a one-shot one turns off.
+/// ## Address space:
+/// 00: control (bit 0: enabled, bit 1: periodic, bit 2: count cycles), enabling a stopped timer loads the counter
+/// 01: reload (0 counts as 256)
+/// 02: prescaler
+/// 03: counter (read only)
+///
+/// ## Interrupts: The device will send an interrupt every time the counter runs out,
+/// The interrupt code will be the configured one.
+#[derive(Debug)]
+pub struct IntervalTimer {
+    interrupt_code: u8,
+    interrupt_queued: bool,
+
+    control: u8,
+    reload: u8,
+    prescaler: u8,
+
+    counter: u16,
+    prescale_count: u8,
+}
+
+impl IntervalTimer {
+    pub fn new(code: u8) -> Self {
+        Self {
+            interrupt_code: code,
+            interrupt_queued: false,
+
+            control: 0,
+            reload: 0,
+            prescaler: 0,
+
+            counter: 0,
+            prescale_count: 0,
+        }
+    }
+
+    fn load_counter(&mut self) {
+        self.counter = if self.reload == 0 { 256 } else { self.reload as u16 };
+        self.prescale_count = self.prescaler;
+    }
+
+    /// Counts one step or cycle.
+    fn count(&mut self) {
+        if self.control & CONTROL_ENABLED == 0 {
+            return;
+        }
+
+        if self.prescale_count != 0 {
+            self.prescale_count -= 1;
+            return;
+        }
+        self.prescale_count = self.prescaler;
+
+        self.counter -= 1;
+        if self.counter == 0 {
+            self.interrupt_queued = true;
+
+            if self.control & CONTROL_PERIODIC != 0 {
+                self.load_counter();
+            } else {
+                self.control &= !CONTROL_ENABLED;
+            }
+        }
+    }
+}
+
+impl Device for IntervalTimer {
+    fn init_device(&mut self) {
+        /* Pass */
+    }
+
+    fn startup(&mut self) {
+        /* Pass */
+    }
+
+    /// Counts one CPU step.
+    fn update_device(&mut self) {
+        if self.control & CONTROL_CYCLES == 0 {
+            self.count();
+        }
+    }
+
+    fn cycles_elapsed(&mut self, cycles: u8) {
+        if self.control & CONTROL_CYCLES != 0 {
+            for _ in 0..cycles {
+                self.count();
+            }
+        }
+    }
+
+    fn draw_ui(&mut self, _no_gui: bool, debug: bool) -> Option<String> {
+        if debug {
+            Some(format!("{:?}", self))
+        } else {
+            None
+        }
+    }
+
+    fn has_interrupt_request(&mut self) -> Option<(u8, String)> {
+        if self.interrupt_queued {
+            self.interrupt_queued = false;
+            Some((self.interrupt_code, "Timer expired".to_string()))
+        } else {
+            None
+        }
+    }
+
+    fn reset_device(&mut self) {
+        self.interrupt_queued = false;
+        self.control = 0;
+        self.reload = 0;
+        self.prescaler = 0;
+        self.counter = 0;
+        self.prescale_count = 0;
+    }
+
+    fn read(&mut self, address: u8) -> u8 {
+        match address {
+            0 => self.control,
+            1 => self.reload,
+            2 => self.prescaler,
+            3 => self.counter as u8,
+
+            _ => 0
+        }
+    }
+
+    fn write(&mut self, address: u8, value: u8) {
+        match address {
+            0 => {
+                let was_enabled = self.control & CONTROL_ENABLED != 0;
+                self.control = value & (CONTROL_ENABLED | CONTROL_PERIODIC | CONTROL_CYCLES);
+
+                if !was_enabled && self.control & CONTROL_ENABLED != 0 {
+                    self.load_counter();
+                }
+            }
+            1 => self.reload = value,
+            2 => self.prescaler = value,
+
+            _ => {}
+        }
+    }
+
+    fn get_address_space(&self) -> Option<u8> { Some(4) }
+
+    fn save_state(&self) -> Vec<u8> {
+        let [counter_hi, counter_lo] = self.counter.to_be_bytes();
+        vec![self.control, self.reload, self.prescaler, counter_hi, counter_lo, self.prescale_count, self.interrupt_queued as u8]
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), String> {
+        let [control, reload, prescaler, counter_hi, counter_lo, prescale_count, interrupt_queued] = state else {
+            return Err(format!("expected 7 bytes of state, got {}", state.len()));
+        };
+
+        let counter = u16::from_be_bytes([*counter_hi, *counter_lo]);
+        if counter > 256 {
+            return Err(format!("the counter {} is past 256", counter));
+        }
+        if counter == 0 && control & CONTROL_ENABLED != 0 {
+            return Err("the timer is enabled with a counter of 0".to_string());
+        }
+
+        self.control = *control;
+        self.reload = *reload;
+        self.prescaler = *prescaler;
+        self.counter = counter;
+        self.prescale_count = *prescale_count;
+        self.interrupt_queued = *interrupt_queued != 0;
+        Ok(())
+    }
+
+    fn as_any(&self) -> &dyn Any { self }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Counts the given steps, returns after which ones (starting at 1) the timer expired.
+    fn expiries(timer: &mut IntervalTimer, steps: u32) -> Vec<u32> {
+        (1..=steps).filter(|_| {
+            timer.update_device();
+            timer.has_interrupt_request().is_some()
+        }).collect()
+    }
+
+    fn started(reload: u8, prescaler: u8, control: u8) -> IntervalTimer {
+        let mut timer = IntervalTimer::new(3);
+        timer.write(1, reload);
+        timer.write(2, prescaler);
+        timer.write(0, control);
+        timer
+    }
+
+    #[test]
+    fn a_one_shot_timer_turns_off() {
+        let mut timer = started(3, 0, CONTROL_ENABLED);
+        assert_eq!(expiries(&mut timer, 10), [3]);
+        assert_eq!(timer.read(0) & CONTROL_ENABLED, 0);
+    }
+
+    #[test]
+    fn a_periodic_timer_reloads() {
+        let mut timer = started(3, 0, CONTROL_ENABLED | CONTROL_PERIODIC);
+        assert_eq!(expiries(&mut timer, 10), [3, 6, 9]);
+        assert_eq!(timer.read(3), 2);
+    }
+
+    #[test]
+    fn reload_0_is_256() {
+        let mut timer = started(0, 0, CONTROL_ENABLED);
+        assert_eq!(expiries(&mut timer, 300), [256]);
+    }
+
+    #[test]
+    fn the_prescaler_divides_the_steps() {
+        let mut timer = started(2, 2, CONTROL_ENABLED | CONTROL_PERIODIC);
+        assert_eq!(expiries(&mut timer, 12), [6, 12]);
+    }
+
+    #[test]
+    fn cycle_mode_ignores_steps() {
+        let mut timer = started(5, 0, CONTROL_ENABLED | CONTROL_CYCLES);
+        assert!(expiries(&mut timer, 10).is_empty());
+
+        timer.cycles_elapsed(4);
+        assert!(timer.has_interrupt_request().is_none());
+        timer.cycles_elapsed(1);
+        assert_eq!(timer.has_interrupt_request().map(|(code, _)| code), Some(3));
+    }
+
+    #[test]
+    fn invalid_counters_are_rejected() {
+        let mut timer = started(3, 1, CONTROL_ENABLED | CONTROL_PERIODIC);
+        timer.update_device();
+        let state = timer.save_state();
+
+        let mut restored = IntervalTimer::new(3);
+        restored.load_state(&state).unwrap();
+        assert_eq!(restored.save_state(), state);
+
+        assert!(restored.load_state(&[CONTROL_ENABLED, 3, 0, 0, 0, 0, 0]).is_err());
+        assert!(restored.load_state(&[0, 3, 0, 1, 1, 0, 0]).is_err());
+        restored.load_state(&[0, 3, 0, 0, 0, 0, 0]).unwrap();
+    }
+}
@@ -1,17 +1,92 @@
-use bitmatch::bitmatch;
 use owo_colors::{OwoColorize, Style};
+use serde::{Deserialize, Serialize};
+use crate::helium::faults::{Fault, FaultAction, FaultKind, FaultPolicy};
 use crate::helium::io_controller::IOController;
+use crate::helium::isa::{Condition, Extensions, Instruction};
 use crate::helium::memory::MemoryControl;
 use crate::utils::chars::*;
 
+/// The flags of the CPU.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Flag {
+    Zero,
+    Signed,
+    Carry,
+    Overflow,
+}
+
+/// Something the CPU did on the memory or the IO bus, or an interrupt it entered or left.
+/// These are only collected while bus logging is enabled, see [`CPU::set_bus_logging`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum BusEvent {
+    /// A write into the RAM, rejected ROM writes are not logged.
+    MemoryWrite { address: u8, old_value: u8, new_value: u8 },
+    IoRead { address: u8, value: u8 },
+    IoWrite { address: u8, value: u8 },
+    /// Software interrupts come from INT (and fault exceptions), the others from devices.
+    InterruptEntry { code: u8, software: bool, return_address: u8 },
+    InterruptExit { return_address: u8 },
+}
+
+/// A misuse of the stack, these are only collected while stack checks are enabled, see [`CPU::set_stack_checks`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum StackFault {
+    /// A push (or CALL) which ran into the ROM, the value is lost.
+    Overflow { address: u8 },
+    /// A pop (or RET) while the stack was empty, SP wrapped around.
+    Underflow,
+}
+
+/// What an interrupt saves with nested interrupts, RETI restores it.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InterruptFrame {
+    pub return_address: u8,
+    /// In the FSWAP layout.
+    pub flags: u8,
+    pub interrupt_enabled: bool,
+}
+
+/// Everything in the CPU besides the memory and the devices, as stored in snapshots.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CpuState {
+    pub registers: [u8; 4],
+    pub instruction_reg: u8,
+    pub instruction_addr: u8,
+    pub program_counter: u8,
+    pub secondary_counter: u8,
+
+    pub interrupt_addr: u8,
+    pub interrupt_code: u8,
+    pub interrupt_req: bool,
+    pub interrupt_enabled: bool,
+    pub interrupt_queued: bool,
+    pub in_interrupt: bool,
+
+    /// In the FSWAP layout (0b0000_SCOZ).
+    pub flags: u8,
+    pub is_on: bool,
+    /// Missing from older snapshots.
+    #[serde(default)]
+    pub cycles: u64,
+    #[serde(default)]
+    pub stack_pointer: u8,
+    #[serde(default)]
+    pub interrupt_stack: Vec<InterruptFrame>,
+}
+
 /// The main CPU, it follows the ISA given in the isa.txt file, each .next() call will complete an instruction.
 #[derive(Debug)]
 pub struct CPU {
     registers: [u8; 4], // A, B, C, D
 
     instruction_reg: u8,
+    /// The address the instruction in the instruction register was fetched from.
+    instruction_addr: u8,
     program_counter: u8,
     secondary_counter: u8,
+    /// Only used with the stack extension, 0 means the stack is empty.
+    stack_pointer: u8,
 
     interrupt_addr: u8,
     interrupt_code: u8,
@@ -21,6 +96,8 @@ pub struct CPU {
     /// When a software interrupt occurs, this will be toggled as true.
     interrupt_queued: bool,
     in_interrupt: bool,
+    /// Only used with nested interrupts, the innermost interrupt is on the top.
+    interrupt_stack: Vec<InterruptFrame>,
 
     carry: bool,
     overflow: bool,
@@ -31,16 +108,42 @@ pub struct CPU {
     pub io_ctl: IOController,
 
     pub is_on: bool,
+    /// The clock cycles since the start, see [`Instruction::cycles`].
+    cycles: u64,
+
+    extensions: Extensions,
+
+    bus_logging: bool,
+    bus_events: Vec<BusEvent>,
+
+    stack_checks: bool,
+    stack_faults: Vec<StackFault>,
+
+    fault_policy: FaultPolicy,
+    /// The logged faults which weren't taken yet.
+    faults: Vec<Fault>,
+    halt_fault: Option<Fault>,
+    /// Entered once the current instruction is done.
+    pending_exception: Option<Fault>,
 }
 
+/// How many logged faults are kept until they are taken, the newer ones get dropped.
+const MAX_LOGGED_FAULTS: usize = 256;
+
 impl CPU {
     pub fn new(devices: IOController, rom_data: Vec<u8>) -> Self {
-        let mem = MemoryControl::new(rom_data);
+        Self::with_memory(devices, MemoryControl::new(rom_data))
+    }
+
+    /// Like new, but with an already set up (for example banked) memory.
+    pub fn with_memory(devices: IOController, mem: MemoryControl) -> Self {
         Self {
             registers: [0; 4],
             instruction_reg: 0,
+            instruction_addr: 0,
             program_counter: 0,
             secondary_counter: 0,
+            stack_pointer: 0,
 
             interrupt_req: false,
             interrupt_enabled: false,
@@ -48,6 +151,7 @@ impl CPU {
             interrupt_addr: 0,
             interrupt_queued: false,
             in_interrupt: false,
+            interrupt_stack: Vec::new(),
 
             carry: false,
             overflow: false,
@@ -58,6 +162,20 @@ impl CPU {
             io_ctl: devices,
 
             is_on: false,
+            cycles: 0,
+
+            extensions: Extensions::default(),
+
+            bus_logging: false,
+            bus_events: Vec::new(),
+
+            stack_checks: false,
+            stack_faults: Vec::new(),
+
+            fault_policy: FaultPolicy::default(),
+            faults: Vec::new(),
+            halt_fault: None,
+            pending_exception: None,
         }
     }
 
@@ -66,7 +184,9 @@ impl CPU {
         self.registers = [0u8; 4];
         self.program_counter = 0;
         self.secondary_counter = 0;
+        self.stack_pointer = 0;
         self.instruction_reg = 0;
+        self.instruction_addr = 0;
         self.interrupt_addr = 0;
         self.interrupt_code = 0;
 
@@ -74,6 +194,9 @@ impl CPU {
         self.interrupt_enabled = false;
         self.interrupt_queued = false;
         self.in_interrupt = false;
+        self.interrupt_stack.clear();
+        self.halt_fault = None;
+        self.pending_exception = None;
 
         self.carry = false;
         self.overflow = false;
@@ -92,8 +215,143 @@ impl CPU {
     /// Causes a interrupt request.
     pub fn interrupt(&mut self) { self.interrupt_req = true; }
 
+    /// Returns the content of a general purpose register (0 - 3).
+    pub fn register(&self, index: usize) -> u8 { self.registers[index] }
+
+    /// Overwrites a general purpose register (0 - 3).
+    pub fn set_register(&mut self, index: usize, value: u8) { self.registers[index] = value; }
+
+    pub fn program_counter(&self) -> u8 { self.program_counter }
+
+    /// The clock cycles since the start.
+    pub fn cycles(&self) -> u64 { self.cycles }
+
+    pub fn set_program_counter(&mut self, address: u8) { self.program_counter = address; }
+
+    /// The counter holding the return address while in an interrupt.
+    pub fn secondary_counter(&self) -> u8 { self.secondary_counter }
+
+    pub fn set_secondary_counter(&mut self, address: u8) { self.secondary_counter = address; }
+
+    /// The stack pointer of the stack extension, it points at the last pushed byte.
+    pub fn stack_pointer(&self) -> u8 { self.stack_pointer }
+
+    pub fn set_stack_pointer(&mut self, address: u8) { self.stack_pointer = address; }
+
+    /// The ISA extensions the CPU decodes.
+    pub fn extensions(&self) -> Extensions { self.extensions }
+
+    pub fn set_extensions(&mut self, extensions: Extensions) { self.extensions = extensions; }
+
+    pub fn fault_policy(&self) -> FaultPolicy { self.fault_policy }
+
+    pub fn set_fault_policy(&mut self, policy: FaultPolicy) { self.fault_policy = policy; }
+
+    /// The fault which halted the CPU, if it was one.
+    pub fn halt_fault(&self) -> Option<Fault> { self.halt_fault }
+
+    pub fn interrupt_addr(&self) -> u8 { self.interrupt_addr }
+
+    pub fn set_interrupt_addr(&mut self, address: u8) { self.interrupt_addr = address; }
+
+    /// Whether hardware interrupts are accepted (EI/DI).
+    pub fn interrupt_enabled(&self) -> bool { self.interrupt_enabled }
+
+    pub fn set_interrupt_enabled(&mut self, enabled: bool) { self.interrupt_enabled = enabled; }
+
+    /// True while the CPU is running an interrupt handler (until RETI).
+    pub fn in_interrupt(&self) -> bool { self.in_interrupt }
+
+    /// How many interrupts are being handled, with nested interrupts this can be more than 1.
+    pub fn interrupt_level(&self) -> usize {
+        match self.extensions.interrupt_nesting {
+            Some(_) => self.interrupt_stack.len(),
+            None => self.in_interrupt as usize,
+        }
+    }
+
+    /// True if a device requested an interrupt which wasn't served yet.
+    pub fn interrupt_requested(&self) -> bool { self.interrupt_req }
+
+    /// True if a software interrupt (INT) is waiting to be served.
+    pub fn interrupt_queued(&self) -> bool { self.interrupt_queued }
+
+    /// The code of the last interrupt, this is what GIC reads.
+    pub fn interrupt_code(&self) -> u8 { self.interrupt_code }
+
+    /// The last instruction fetched.
+    pub fn instruction_reg(&self) -> u8 { self.instruction_reg }
+
+    /// The address of the last instruction fetched.
+    pub fn instruction_addr(&self) -> u8 { self.instruction_addr }
+
+    pub fn flag(&self, flag: Flag) -> bool {
+        match flag {
+            Flag::Zero => self.zero,
+            Flag::Signed => self.signed,
+            Flag::Carry => self.carry,
+            Flag::Overflow => self.overflow,
+        }
+    }
+
+    pub fn set_flag(&mut self, flag: Flag, state: bool) {
+        match flag {
+            Flag::Zero => self.zero = state,
+            Flag::Signed => self.signed = state,
+            Flag::Carry => self.carry = state,
+            Flag::Overflow => self.overflow = state,
+        }
+    }
+
+    pub fn save_state(&self) -> CpuState {
+        CpuState {
+            registers: self.registers,
+            instruction_reg: self.instruction_reg,
+            instruction_addr: self.instruction_addr,
+            program_counter: self.program_counter,
+            secondary_counter: self.secondary_counter,
+
+            interrupt_addr: self.interrupt_addr,
+            interrupt_code: self.interrupt_code,
+            interrupt_req: self.interrupt_req,
+            interrupt_enabled: self.interrupt_enabled,
+            interrupt_queued: self.interrupt_queued,
+            in_interrupt: self.in_interrupt,
+
+            flags: self.flags_into_u8(),
+            is_on: self.is_on,
+            cycles: self.cycles,
+            stack_pointer: self.stack_pointer,
+            interrupt_stack: self.interrupt_stack.clone(),
+        }
+    }
+
+    /// Restores the registers and flags, the memory and the devices are restored separately.
+    pub fn load_state(&mut self, state: &CpuState) {
+        self.registers = state.registers;
+        self.instruction_reg = state.instruction_reg;
+        self.instruction_addr = state.instruction_addr;
+        self.program_counter = state.program_counter;
+        self.secondary_counter = state.secondary_counter;
+
+        self.interrupt_addr = state.interrupt_addr;
+        self.interrupt_code = state.interrupt_code;
+        self.interrupt_req = state.interrupt_req;
+        self.interrupt_enabled = state.interrupt_enabled;
+        self.interrupt_queued = state.interrupt_queued;
+        self.in_interrupt = state.in_interrupt;
+
+        self.flags_from_u8(state.flags);
+        self.is_on = state.is_on;
+        self.cycles = state.cycles;
+        self.stack_pointer = state.stack_pointer;
+        self.interrupt_stack = state.interrupt_stack.clone();
+        if self.is_on {
+            self.halt_fault = None;
+        }
+    }
+
     /// Executes the next instruction if the CPU is on.
-    #[bitmatch]
     pub fn next(&mut self) {
         // If Off return.
         if !self.is_on {
@@ -101,40 +359,31 @@ impl CPU {
         }
 
         // CHECK FOR INTERRUPT
-        if self.interrupt_enabled && !self.in_interrupt && self.interrupt_req && !self.interrupt_queued {
-            self.in_interrupt = true;
+        if self.interrupt_enabled && self.can_enter_interrupt() && self.interrupt_req && !self.interrupt_queued {
             self.interrupt_req = false;
-
-            self.secondary_counter = self.program_counter;
-            self.program_counter = self.interrupt_addr;
+            self.enter_interrupt(false);
         }
 
-        if self.interrupt_queued && !self.in_interrupt {
-            self.in_interrupt = true;
+        if self.interrupt_queued && self.can_enter_interrupt() {
             self.interrupt_queued = false;
-
-            self.secondary_counter = self.program_counter;
-            self.program_counter = self.interrupt_addr;
+            self.enter_interrupt(true);
         }
 
 
         // load instruction
+        self.instruction_addr = self.program_counter;
         self.instruction_reg = self.memory.get(self.program_counter);
         // increment program counter.
         self.program_counter = self.program_counter.overflowing_add(1).0; // doesn't panic when 255 + 1 causes an overflow
 
         // Decode instruction
-        // These are for the liter
-
-        #[allow(unused_variables)]
-        let x: u8 = 0;
-        #[allow(unused_variables)]
-        let y: u8 = 0;
+        let instruction = Instruction::decode_with(self.instruction_reg, self.extensions);
+        let cycles = instruction.map_or(1, |instruction| instruction.cycles());
+        self.cycles += cycles as u64;
 
-        #[bitmatch]
-        match self.instruction_reg {
-            "0000_0000" => { self.is_on = false } // halt
-            "0000_01_xx" => {
+        match instruction {
+            Some(Instruction::Halt) => { self.is_on = false } // halt
+            Some(Instruction::LoadImm(x)) => {
                 // LOAD IMM
                 let reg = x as usize;
                 let value = self.memory.get(self.program_counter);
@@ -143,65 +392,65 @@ impl CPU {
                 self.registers[reg] = value;
             }
 
-            "00_0010_xx" => {
+            Some(Instruction::Load(x)) => {
                 // Load (imm)
                 let reg = x as usize;
                 let addr = self.memory.get(self.program_counter);
                 self.program_counter = self.program_counter.overflowing_add(1).0;
 
-                self.registers[reg] = self.memory.get(addr);
+                self.registers[reg] = self.mem_read(addr);
             }
 
-            "00_0011_xx" => {
+            Some(Instruction::Store(x)) => {
                 // Store (imm)
                 let reg = x as usize;
                 let addr = self.memory.get(self.program_counter);
                 self.program_counter = self.program_counter.overflowing_add(1).0;
 
-                self.memory.set(addr, self.registers[reg]);
+                self.mem_write(addr, self.registers[reg]);
             }
 
-            "00_0100_xx" => {
+            Some(Instruction::In(x)) => {
                 // IN (imm)
                 let reg = x as usize;
                 let io_addr = self.memory.get(self.program_counter);
                 self.program_counter = self.program_counter.overflowing_add(1).0;
 
-                self.registers[reg] = self.io_ctl.read(io_addr);
+                self.registers[reg] = self.io_read(io_addr);
             }
-            "00_0101_xx" => {
+            Some(Instruction::InReg(x)) => {
                 // IN (reg(imm))
                 let reg = x as usize;
-                let mut io_addr_reg = self.memory.get(self.program_counter);
+                let io_addr_reg = self.memory.get(self.program_counter);
                 self.program_counter = self.program_counter.overflowing_add(1).0;
 
-                if io_addr_reg > 3 { io_addr_reg = 0; } // Bounds check
-
-                let io_addr = self.registers[io_addr_reg as usize];
-                self.registers[reg] = self.io_ctl.read(io_addr);
+                if let Some(io_addr_reg) = self.register_index(io_addr_reg) {
+                    let io_addr = self.registers[io_addr_reg];
+                    self.registers[reg] = self.io_read(io_addr);
+                }
 
             }
 
-            "00_0110_xx" => {
+            Some(Instruction::Out(x)) => {
                 // OUT (imm)
                 let reg = x as usize;
                 let io_addr = self.memory.get(self.program_counter);
                 self.program_counter = self.program_counter.overflowing_add(1).0;
 
-                self.io_ctl.write(io_addr, self.registers[reg]);
+                self.io_write(io_addr, self.registers[reg]);
             }
-            "00_0111_xx" => {
+            Some(Instruction::OutReg(x)) => {
                 //OUT reg(imm)
                 let reg = x as usize;
-                let mut io_addr_reg = self.memory.get(self.program_counter);
+                let io_addr_reg = self.memory.get(self.program_counter);
                 self.program_counter = self.program_counter.overflowing_add(1).0;
 
-                if io_addr_reg > 3 { io_addr_reg = 0; } // Bounds check
-
-                let io_addr = self.registers[io_addr_reg as usize];
-                self.io_ctl.write(io_addr, self.registers[reg]);
+                if let Some(io_addr_reg) = self.register_index(io_addr_reg) {
+                    let io_addr = self.registers[io_addr_reg];
+                    self.io_write(io_addr, self.registers[reg]);
+                }
             }
-            "00_1000_xx" => {
+            Some(Instruction::FlagSwap(x)) => {
                 // FSWAP
                 let reg = x as usize;
                 let flag_state = self.flags_into_u8();
@@ -209,7 +458,7 @@ impl CPU {
                 self.registers[reg] = flag_state;
             }
 
-            "00_1001_xx" => {
+            Some(Instruction::ShiftRight(x)) => {
                 // Shift Right (by 1)
                 let reg = x as usize;
                 let value = self.registers[reg];
@@ -217,7 +466,7 @@ impl CPU {
                 self.carry = value & 1 == 1;
                 self.registers[reg] = value >> 1;
             }
-            "00_1010_xx" => {
+            Some(Instruction::ShiftLeft(x)) => {
                 // Shift Left (by 1)
                 let reg = x as usize;
                 let value = self.registers[reg];
@@ -228,50 +477,61 @@ impl CPU {
 
             // Interrupt instructions
 
-            "00_1011_xx" => {
+            Some(Instruction::SetInterruptAddr(x)) => {
                 // Set Interrupt addr
                 let reg = x as usize;
                 self.interrupt_addr = self.registers[reg];
             }
-            "00_1100_00" => {
+            Some(Instruction::EnableInterrupt) => {
                 // enable interrupt
                 self.interrupt_enabled = true;
             }
-            "00_1100_01" => {
+            Some(Instruction::ClearInterrupt) => {
                 // Clear interrupt req
                 self.interrupt_req = false;
             }
-            "00_1100_10" => {
+            Some(Instruction::DisableInterrupt) => {
                 // disable interrupt
                 self.interrupt_enabled = false;
             }
-            "00_1100_11" => {
+            Some(Instruction::ReturnFromInterrupt) if !self.in_interrupt => {
+                /* Not in an interrupt, nothing to return from */
+            }
+            Some(Instruction::ReturnFromInterrupt) => {
                 // Return from interrupt mode.
-                if !self.in_interrupt { return; }
+                if let Some(frame) = self.interrupt_stack.pop() {
+                    self.secondary_counter = frame.return_address;
+                    self.flags_from_u8(frame.flags);
+                    self.interrupt_enabled = frame.interrupt_enabled;
+                }
 
                 self.program_counter = self.secondary_counter;
-                self.in_interrupt = false;
+                self.in_interrupt = !self.interrupt_stack.is_empty();
+                if let Some(outer) = self.interrupt_stack.last() {
+                    self.secondary_counter = outer.return_address;
+                }
+                self.log(BusEvent::InterruptExit { return_address: self.program_counter });
             }
-            "00_1101_00" => {
+            Some(Instruction::CallInterrupt) => {
                 // Call Interrupt
                 self.interrupt_code = 0;
                 self.interrupt_queued = true;
             }
-            "00_1101_01" => {
+            Some(Instruction::GetInterruptCause) => {
                 // Get INT code
                 self.registers[0] = self.interrupt_code;
             }
             // Some system commands
-            "00_1101_10" => { self.reset(); }
-            "00_1101_11" => { /* No Operation */ }
+            Some(Instruction::Reset) => { self.reset(); }
+            Some(Instruction::NoOp) => { /* No Operation */ }
 
 
-            "00_1110_xx" => {
+            Some(Instruction::LoadPc(x)) => {
                 // LPC Load Program Counter
                 let reg = x as usize;
                 self.registers[reg] = self.program_counter;
             }
-            "00_1111_xx" => {
+            Some(Instruction::RotateRight(x)) => {
                 // Rotate right
                 let reg = x as usize;
                 let value = self.registers[reg];
@@ -280,27 +540,27 @@ impl CPU {
             }
 
             //ALU ops
-            "01_00_xx_yy" => {
+            Some(Instruction::Add(x, y)) => {
                 // add and save (uses carry, and updates flags)
                 self.add(x as usize, y as usize, true);
             }
-            "01_01_xx_yy" => {
+            Some(Instruction::Sub(x, y)) => {
                 // sub and save, uses carry, updates flags
                 self.sub(x as usize, y as usize, true);
             }
 
-            "01_10_xx_yy" => {
+            Some(Instruction::CompareAdd(x, y)) => {
                 //add without save
                 self.add(x as usize, y as usize, false);
             }
-            "01_11_xx_yy" => {
+            Some(Instruction::Compare(x, y)) => {
                 //sub without save
                 self.sub(x as usize, y as usize, false);
             }
 
             // Logic ops & move
 
-            "10_00_xx_yy" => {
+            Some(Instruction::And(x, y)) => {
                 // AND a b
                 let reg_a = x as usize;
                 let reg_b = y as usize;
@@ -311,7 +571,7 @@ impl CPU {
 
                 self.registers[reg_b] = result;
             }
-            "10_01_xx_yy" => {
+            Some(Instruction::Xor(x, y)) => {
                 // xor a b
                 let reg_a = x as usize;
                 let reg_b = y as usize;
@@ -322,7 +582,7 @@ impl CPU {
 
                 self.registers[reg_b] = result;
             }
-            "10_10_xx_yy" => {
+            Some(Instruction::Or(x, y)) => {
                 // or a b
                 let reg_a = x as usize;
                 let reg_b = y as usize;
@@ -334,7 +594,7 @@ impl CPU {
                 self.registers[reg_b] = result;
             }
 
-            "10_11_xx_yy" => {
+            Some(Instruction::Move(x, y)) => {
                 // Move A -> B
                 let reg_a = x as usize;
                 let reg_b = y as usize;
@@ -343,66 +603,285 @@ impl CPU {
             }
 
             // memory with regs
-            "11_00_xxyy" => {
+            Some(Instruction::LoadReg(x, y)) => {
                 // Load reg[xx] = mem[reg[yy]]
                 let reg_a = x as usize;
                 let reg_b = y as usize;
 
-                self.registers[reg_a] = self.memory.get(self.registers[reg_b]);
+                self.registers[reg_a] = self.mem_read(self.registers[reg_b]);
             }
-            "11_01_xxyy" => {
+            Some(Instruction::StoreReg(x, y)) => {
                 // Store mem[reg[yy]] = reg[xx]
 
                 let reg_a = x as usize;
                 let reg_b = y as usize;
 
-                self.memory.set(self.registers[reg_b], self.registers[reg_a]);
+                self.mem_write(self.registers[reg_b], self.registers[reg_a]);
             }
 
             // Jumps
-            "11_10_xxx_y" => {
+            Some(Instruction::Jump(condition, is_reg)) => {
                 // JMP IF cond(x) to (if y: reg(imm8)?: imm8)
-                let condition = x as u8;
-                let is_reg = y == 1;
-
-                let mut address = self.memory.get(self.program_counter);
+                let operand = self.memory.get(self.program_counter);
                 self.program_counter = self.program_counter.overflowing_add(1).0;
 
-                if is_reg { address = self.registers[address as usize] }
-                self.jmp_if(condition, address);
+                if let Some(address) = self.jump_target(operand, is_reg) {
+                    self.jmp_if(condition, address);
+                }
 
             }
-            "11_11_xxx_y" => {
+            Some(Instruction::JumpRel(condition, is_reg)) => {
                 // JMPR IF cond(x) to (if y: reg(imm8)?: imm8)
-                let condition = x as u8;
-                let is_reg = y == 1;
-
-                let mut address = self.memory.get(self.program_counter);
+                let operand = self.memory.get(self.program_counter);
                 self.program_counter = self.program_counter.overflowing_add(1).0;
 
-                if is_reg { address = self.registers[address as usize] }
+                if let Some(offset) = self.jump_target(operand, is_reg) {
+                    let address = self.program_counter.overflowing_add(offset).0;
+                    self.jmp_if(condition, address);
+                }
+            }
 
-                address = self.program_counter.overflowing_add(address).0;
-                self.jmp_if(condition, address);
+            // Stack extension
+            Some(Instruction::Push(x)) => {
+                let reg = x as usize;
+                self.push(self.registers[reg]);
+            }
+            Some(Instruction::Pop(x)) => {
+                let reg = x as usize;
+                self.registers[reg] = self.pop();
             }
+            Some(Instruction::Call) => {
+                let address = self.memory.get(self.program_counter);
+                self.program_counter = self.program_counter.overflowing_add(1).0;
 
-            "aaaa_aaaa" => {
-                println!("unknown instruction: {a:08b}");
+                self.push(self.program_counter);
+                self.program_counter = address;
+            }
+            Some(Instruction::Return) => {
+                self.program_counter = self.pop();
             }
+            Some(Instruction::StackSwap(x)) => {
+                let reg = x as usize;
+                std::mem::swap(&mut self.stack_pointer, &mut self.registers[reg]);
+            }
+
+            None => {
+                self.fault(FaultKind::IllegalOpcode, self.instruction_reg);
+            }
+        }
+        let exception = self.pending_exception.take();
+        if let Some(fault) = exception {
+            self.enter_exception(fault);
         }
         // After everything
-        self.io_ctl.update();
+        self.io_ctl.update(cycles);
         // Check for interrupts
         if let Some(code) = self.io_ctl.device_has_interrupt_request() {
-            if !self.interrupt_queued { // Software interrupts have priority.
+            if !self.interrupt_queued && exception.is_none() { // Software interrupts and exceptions have priority.
                 self.interrupt_req = true;
                 self.interrupt_code = code;
             }
         }
     }
 
-    /// Turns the active CPU flags into an u8.
-    fn flags_into_u8(&mut self) -> u8 {
+    /// Enables collecting the bus events of every instruction, until they are taken.
+    pub fn set_bus_logging(&mut self, enabled: bool) {
+        self.bus_logging = enabled;
+        if !enabled {
+            self.bus_events.clear();
+        }
+    }
+
+    /// Returns and forgets the bus events since the last call.
+    pub fn take_bus_events(&mut self) -> Vec<BusEvent> {
+        std::mem::take(&mut self.bus_events)
+    }
+
+    /// Without nested interrupts only one interrupt can be handled at a time,
+    /// with them as many as the interrupt return stack can hold.
+    fn can_enter_interrupt(&self) -> bool {
+        match self.extensions.interrupt_nesting {
+            Some(depth) => self.interrupt_stack.len() < depth as usize,
+            None => !self.in_interrupt,
+        }
+    }
+
+    /// Saves the return address (and with nested interrupts the flags and IE) and jumps to the interrupt address.
+    fn enter_interrupt(&mut self, software: bool) {
+        if !software {
+            self.io_ctl.acknowledge_interrupt();
+        }
+        self.enter_handler(self.interrupt_addr, software);
+    }
+
+    /// Enters the fault vector with the cause as the interrupt code,
+    /// if there's no room to save the return address the CPU halts with a double fault (without a vector with the fault).
+    fn enter_exception(&mut self, fault: Fault) {
+        let Some(vector) = self.fault_policy.vector else {
+            self.is_on = false;
+            self.halt_fault = Some(fault);
+            return;
+        };
+        if !self.can_enter_interrupt() {
+            self.is_on = false;
+            self.halt_fault = Some(Fault { kind: FaultKind::DoubleFault, address: fault.address, detail: fault.kind.cause() });
+            return;
+        }
+
+        self.interrupt_code = fault.kind.cause();
+        self.enter_handler(vector, true);
+    }
+
+    fn enter_handler(&mut self, target: u8, software: bool) {
+        if self.extensions.interrupt_nesting.is_some() {
+            self.interrupt_stack.push(InterruptFrame {
+                return_address: self.program_counter,
+                flags: self.flags_into_u8(),
+                interrupt_enabled: self.interrupt_enabled,
+            });
+            // The handler has to enable them again if it wants to be interrupted.
+            self.interrupt_enabled = false;
+        }
+
+        self.in_interrupt = true;
+        self.secondary_counter = self.program_counter;
+        self.program_counter = target;
+        self.log(BusEvent::InterruptEntry { code: self.interrupt_code, software, return_address: self.secondary_counter });
+    }
+
+    /// Returns and forgets the logged faults since the last call.
+    pub fn take_faults(&mut self) -> Vec<Fault> {
+        std::mem::take(&mut self.faults)
+    }
+
+    /// Handles a fault of the current instruction as the policy says,
+    /// returns false if the faulting access has to be skipped.
+    fn fault(&mut self, kind: FaultKind, detail: u8) -> bool {
+        let fault = Fault { kind, address: self.instruction_addr, detail };
+
+        match self.fault_policy.action(kind) {
+            FaultAction::Ignore => true,
+            FaultAction::Log => {
+                if self.faults.len() < MAX_LOGGED_FAULTS {
+                    self.faults.push(fault);
+                }
+                true
+            }
+            FaultAction::Halt => {
+                self.is_on = false;
+                self.halt_fault = Some(fault);
+                false
+            }
+            FaultAction::Exception => {
+                self.pending_exception.get_or_insert(fault);
+                false
+            }
+        }
+    }
+
+    /// The imm8 of a jump, or the register it names for the register forms. None if the register index was invalid.
+    fn jump_target(&mut self, operand: u8, is_reg: bool) -> Option<u8> {
+        match is_reg {
+            true => self.register_index(operand).map(|reg| self.registers[reg]),
+            false => Some(operand),
+        }
+    }
+
+    /// The register an indirect IN, OUT or jump uses, r0 if the index is invalid but the fault is ignored.
+    fn register_index(&mut self, index: u8) -> Option<usize> {
+        if index <= 3 {
+            Some(index as usize)
+        } else {
+            self.fault(FaultKind::InvalidRegister, index).then_some(0)
+        }
+    }
+
+    /// Enables collecting overflows and underflows of the stack, until they are taken.
+    pub fn set_stack_checks(&mut self, enabled: bool) {
+        self.stack_checks = enabled;
+        if !enabled {
+            self.stack_faults.clear();
+        }
+    }
+
+    /// Returns and forgets the stack faults since the last call.
+    pub fn take_stack_faults(&mut self) -> Vec<StackFault> {
+        std::mem::take(&mut self.stack_faults)
+    }
+
+    fn push(&mut self, value: u8) {
+        self.stack_pointer = self.stack_pointer.wrapping_sub(1);
+
+        if !self.mem_write(self.stack_pointer, value) && self.stack_checks {
+            self.stack_faults.push(StackFault::Overflow { address: self.stack_pointer });
+        }
+    }
+
+    fn pop(&mut self) -> u8 {
+        if self.stack_pointer == 0 && self.stack_checks {
+            self.stack_faults.push(StackFault::Underflow);
+        }
+
+        let value = self.mem_read(self.stack_pointer);
+        self.stack_pointer = self.stack_pointer.wrapping_add(1);
+        value
+    }
+
+    fn log(&mut self, event: BusEvent) {
+        if self.bus_logging {
+            self.bus_events.push(event);
+        }
+    }
+
+    /// A data read, device regions of the memory map go to the IO bus.
+    fn mem_read(&mut self, address: u8) -> u8 {
+        match self.memory.device_address(address) {
+            Some(io_address) => self.io_read(io_address),
+            None => self.memory.read(address),
+        }
+    }
+
+    /// Returns false if the write got rejected (ROM).
+    fn mem_write(&mut self, address: u8, value: u8) -> bool {
+        if let Some(io_address) = self.memory.device_address(address) {
+            self.io_write(io_address, value);
+            return true;
+        }
+
+        let old_value = self.memory.written_value(address);
+        let written = self.memory.set(address, value);
+        if written {
+            self.log(BusEvent::MemoryWrite { address, old_value, new_value: value });
+        } else {
+            self.fault(FaultKind::RomWrite, address);
+        }
+        written
+    }
+
+    /// The bank select and ROM control registers are decoded here, before the devices get the address.
+    fn io_read(&mut self, address: u8) -> u8 {
+        let value = match self.memory.bank_select_register(address) {
+            Some(window) => self.memory.selected_banks()[window],
+            None if self.memory.is_rom_control(address) => self.memory.rom_mapped() as u8,
+            None if !self.io_ctl.is_mapped(address) && !self.fault(FaultKind::UnmappedIo, address) => 0,
+            None => self.io_ctl.read(address),
+        };
+        self.log(BusEvent::IoRead { address, value });
+        value
+    }
+
+    fn io_write(&mut self, address: u8, value: u8) {
+        match self.memory.bank_select_register(address) {
+            Some(window) => self.memory.select_bank(window, value),
+            None if self.memory.is_rom_control(address) => self.memory.map_rom(value & 1 == 1),
+            None if !self.io_ctl.is_mapped(address) && !self.fault(FaultKind::UnmappedIo, address) => {}
+            None => self.io_ctl.write(address, value),
+        }
+        self.log(BusEvent::IoWrite { address, value });
+    }
+
+    /// Turns the active CPU flags into an u8 (0b0000_SCOZ), this is the layout FSWAP uses.
+    pub fn flags_into_u8(&self) -> u8 {
         (self.signed as u8) << 3
             | (self.carry as u8) << 2
             | (self.overflow as u8) << 1
@@ -410,7 +889,7 @@ impl CPU {
     }
 
     /// Sets the active CPU flags from an u8.
-    fn flags_from_u8(&mut self, flags: u8) {
+    pub fn flags_from_u8(&mut self, flags: u8) {
         self.signed =   (flags & 8) == 8;
         self.carry =    (flags & 4) == 4;
         self.overflow = (flags & 2) == 2;
@@ -457,33 +936,20 @@ impl CPU {
     }
 
     /// Handles conditional jumps.
-    /// # Panics:
-    /// Panics if an invalid condition is given.
-    /// # Conditions:
-    /// 0: Always
-    /// 1: if carry
-    /// 2: if !carry
-    /// 3: if overflow
-    /// 4: if !overflow
-    /// 5: if zero
-    /// 6: if !zero
-    /// 7: if signed
-    fn jmp_if(&mut self, condition_code: u8, address: u8) {
-        match condition_code {
-            0 => self.program_counter = address,
-
-            1 => if self.carry { self.program_counter = address},
-            2 => if !self.carry { self.program_counter = address},
-
-            3 => if self.overflow { self.program_counter = address},
-            4 => if !self.overflow { self.program_counter = address},
-
-            5 => if self.zero { self.program_counter = address},
-            6 => if !self.zero { self.program_counter = address},
-
-            7 => if self.signed { self.program_counter = address},
-
-            _ => panic!("INVALID CONDITION CODE GIVEN: {:08b} {}", condition_code, condition_code)
+    fn jmp_if(&mut self, condition: Condition, address: u8) {
+        let taken = match condition {
+            Condition::Always => true,
+            Condition::Carry => self.carry,
+            Condition::NotCarry => !self.carry,
+            Condition::Overflow => self.overflow,
+            Condition::NotOverflow => !self.overflow,
+            Condition::Zero => self.zero,
+            Condition::NotZero => !self.zero,
+            Condition::Signed => self.signed,
+        };
+
+        if taken {
+            self.program_counter = address;
         }
     }
 
@@ -607,8 +1073,202 @@ impl CPU {
             V_LINE
         ));
 
-        // Footer
-        out.push_str("└─────────────────────┴─────────────────────┴────────┴────────┴────────────────┘");
+        // Footer, the SP only gets shown with the stack extension.
+        let stack = match self.extensions.stack {
+            true => format!("{}| {} {} |{}", H_LINE, "SP:".bold().green(), Self::hex_repr(self.stack_pointer), H_LINE.to_string().repeat(4)),
+            false => H_LINE.to_string().repeat(15),
+        };
+        // The interrupt level only with nested interrupts, right aligned in the flags column.
+        let level = match self.extensions.interrupt_nesting {
+            Some(depth) => {
+                let value = format!("{}/{}", self.interrupt_stack.len(), depth);
+                let width = "| IL:  |".len() + value.len();
+                format!("{}| {} {} |", H_LINE.to_string().repeat(16 - width), "IL:".bold().green(), value)
+            }
+            None => H_LINE.to_string().repeat(16),
+        };
+        out.push_str(&format!("└──| {} {:<14} |{}┴────────┴────────┴{}┘",
+                              "Cycles:".bold().green(), self.cycles, stack, level));
         out
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::stdout_ascii_buffer::CharIOBuffer;
+    use crate::helium::memory::{MemoryRegion, RegionKind};
+    use crate::machine::Machine;
+    use crate::tools::assembler::Assembler;
+
+    fn machine(source: &str, extensions: Extensions) -> Machine {
+        let rom = Assembler::assemble(source).unwrap();
+        Machine::builder(rom).extensions(extensions).build().unwrap()
+    }
+
+    fn stack() -> Extensions {
+        Extensions { stack: true, ..Extensions::default() }
+    }
+
+    #[test]
+    fn call_and_ret_use_the_stack() {
+        let source = "
+        LDI r0, 7
+        PUSH r0
+        CALL sub
+        POP r1
+        HLT
+sub:    XSP r2
+        MOV r2, r3
+        XSP r2
+        RET";
+        let mut machine = machine(source, stack());
+        assert!(machine.run(100));
+
+        assert_eq!(machine.cpu.register(1), 7);
+        assert_eq!(machine.cpu.register(3), 0xFE);
+        assert_eq!(machine.cpu.stack_pointer(), 0);
+        assert_eq!(machine.cpu.memory.get(0xFF), 7);
+    }
+
+    #[test]
+    fn the_stack_encodings_are_the_old_instructions_without_the_extension() {
+        let mut machine = machine("LDI r0, 7\nPUSH r0\nHLT", Extensions::default());
+        assert!(machine.run(100));
+
+        assert_eq!(machine.cpu.register(0), 7);
+        assert_eq!(machine.cpu.stack_pointer(), 0);
+        assert_eq!(machine.cpu.memory.get(0xFF), 0);
+    }
+
+    #[test]
+    fn nested_interrupts_stop_at_the_depth() {
+        let source = "
+        LDI r0, handler
+        SIA r0
+        EI
+loop:   JMP loop
+handler: EI
+        FSWAP r3
+        RETI";
+        let mut machine = machine(source, Extensions { interrupt_nesting: Some(2), ..Extensions::default() });
+        machine.run(3);
+        machine.cpu.set_flag(Flag::Carry, true);
+
+        machine.cpu.interrupt();
+        machine.step();
+        assert_eq!((machine.cpu.interrupt_level(), machine.cpu.program_counter()), (1, 7));
+
+        // The handler enabled interrupts again, so it gets interrupted.
+        machine.cpu.interrupt();
+        machine.step();
+        assert_eq!((machine.cpu.interrupt_level(), machine.cpu.program_counter()), (2, 7));
+
+        // The return stack is full, this one has to wait.
+        machine.cpu.interrupt();
+        machine.step();
+        assert_eq!(machine.cpu.interrupt_level(), 2);
+        assert!(machine.cpu.interrupt_requested());
+        assert!(!machine.cpu.flag(Flag::Carry));
+
+        // RETI goes back into the first handler, with its flags and IE.
+        machine.step();
+        assert_eq!((machine.cpu.interrupt_level(), machine.cpu.program_counter()), (1, 7));
+        assert!(machine.cpu.flag(Flag::Carry));
+        assert!(machine.cpu.interrupt_enabled());
+    }
+
+    #[test]
+    fn ld_and_st_reach_the_devices_of_device_regions() {
+        let rom = Assembler::assemble("LDI r0, 'X'\nST r0, 0xF1\nLD r1, 0xF1\nHLT").unwrap();
+        let device_region = MemoryRegion { kind: RegionKind::Device, start: 0xF0, end: 0xF3, open_bus: None, io: Some(0x10) };
+        let mut machine = Machine::builder(rom)
+            .device(0x10..0x14, CharIOBuffer::with_size(4))
+            .regions(vec![device_region])
+            .build()
+            .unwrap();
+        assert!(machine.run(100));
+
+        assert_eq!(machine.cpu.register(1), b'X');
+        assert_eq!(machine.find_device::<CharIOBuffer>().unwrap().buffer, [0, b'X', 0, 0]);
+    }
+
+    fn with_policy(source: &str, policy: FaultPolicy) -> Result<Machine, String> {
+        let rom = Assembler::assemble(source).unwrap();
+        Machine::builder(rom).fault_policy(policy).build()
+    }
+
+    #[test]
+    fn a_rom_write_exception_enters_the_fault_vector() {
+        let source = "
+        LDI r0, 9
+        ST r0, 0x00
+        HLT
+        .org 0x80
+        GIC
+        HLT";
+        let policy = FaultPolicy { rom_write: FaultAction::Exception, vector: Some(0x80), ..FaultPolicy::default() };
+        let mut machine = with_policy(source, policy).unwrap();
+        assert!(machine.run(100));
+
+        assert_eq!(machine.cpu.register(0), FaultKind::RomWrite.cause());
+        assert_eq!(machine.cpu.secondary_counter(), 4);
+        assert!(machine.cpu.in_interrupt());
+        assert_eq!(machine.cpu.memory.get(0x00), 0x04);
+        assert_eq!(machine.cpu.halt_fault(), None);
+    }
+
+    #[test]
+    fn a_fault_in_the_handler_is_a_double_fault() {
+        let source = "
+        ST r0, 0x00
+        HLT
+        .org 0x80
+        ST r0, 0x00
+        HLT";
+        let policy = FaultPolicy { rom_write: FaultAction::Exception, vector: Some(0x80), ..FaultPolicy::default() };
+        let mut machine = with_policy(source, policy).unwrap();
+        assert!(machine.run(100));
+
+        let fault = Fault { kind: FaultKind::DoubleFault, address: 0x80, detail: FaultKind::RomWrite.cause() };
+        assert_eq!(machine.cpu.halt_fault(), Some(fault));
+    }
+
+    #[test]
+    fn faults_are_ignored_logged_or_halt_as_configured() {
+        let source = "OUT r0, 0x10\n.byte 0x1C, 0x07\nHLT";
+
+        let mut machine = with_policy(source, FaultPolicy::default()).unwrap();
+        assert!(machine.run(100));
+        assert_eq!(machine.cpu.take_faults(), []);
+        assert_eq!(machine.cpu.program_counter(), 5);
+
+        let policy = FaultPolicy { unmapped_io: FaultAction::Log, invalid_register: FaultAction::Halt, ..FaultPolicy::default() };
+        let mut machine = with_policy(source, policy).unwrap();
+        assert!(machine.run(100));
+        assert_eq!(machine.cpu.take_faults(), [Fault { kind: FaultKind::UnmappedIo, address: 0, detail: 0x10 }]);
+        assert_eq!(machine.cpu.halt_fault(), Some(Fault { kind: FaultKind::InvalidRegister, address: 2, detail: 7 }));
+    }
+
+    #[test]
+    fn exceptions_need_a_vector() {
+        let policy = FaultPolicy { illegal_opcode: FaultAction::Exception, ..FaultPolicy::default() };
+        assert!(with_policy("HLT", policy).is_err());
+    }
+
+    #[test]
+    fn rom_writes_log_the_old_value_of_the_ram_under_it() {
+        let rom = Assembler::assemble("LDI r0, 9\nST r0, 0x00\nST r0, 0x00\nHLT").unwrap();
+        let mut machine = Machine::builder(rom).rom_control(0x5F).build().unwrap();
+        machine.cpu.set_bus_logging(true);
+        assert!(machine.run(100));
+
+        let writes = machine.cpu.take_bus_events().into_iter()
+            .filter_map(|event| match event {
+                BusEvent::MemoryWrite { old_value, new_value, .. } => Some((old_value, new_value)),
+                _ => None,
+            })
+            .collect::<Vec<(u8, u8)>>();
+        assert_eq!(writes, [(0, 9), (9, 9)]);
+    }
+}
@@ -0,0 +1,138 @@
+use std::fmt;
+use clap::ValueEnum;
+use serde::Deserialize;
+use crate::helium::isa;
+
+/// The things a program can do wrong, each kind has its own [`FaultAction`].
+#[derive(ValueEnum, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum FaultKind {
+    /// An opcode which doesn't decode (with the enabled extensions).
+    IllegalOpcode,
+    /// A write into ROM or an already written write-once byte.
+    RomWrite,
+    /// An IN or OUT on an address without a device.
+    UnmappedIo,
+    /// The register index of an indirect IN, OUT or jump is past r3, it's r0 when ignored.
+    InvalidRegister,
+    /// An exception which couldn't be entered, because the interrupt return stack (or SC) was in use.
+    /// This one always halts.
+    #[value(skip)]
+    #[serde(skip)]
+    DoubleFault,
+}
+
+impl FaultKind {
+    /// The code GIC reads in the exception handler.
+    pub fn cause(self) -> u8 {
+        match self {
+            FaultKind::DoubleFault => 0,
+            FaultKind::IllegalOpcode => 1,
+            FaultKind::RomWrite => 2,
+            FaultKind::UnmappedIo => 3,
+            FaultKind::InvalidRegister => 4,
+        }
+    }
+}
+
+/// What the CPU does about a fault.
+#[derive(ValueEnum, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum FaultAction {
+    /// Carries on as if nothing happened.
+    Ignore,
+    /// Carries on, the fault can be taken with [`CPU::take_faults`](crate::helium::cpu::CPU::take_faults).
+    Log,
+    /// Stops the CPU, the fault is kept as [`CPU::halt_fault`](crate::helium::cpu::CPU::halt_fault).
+    Halt,
+    /// Finishes the instruction and enters the fault vector like an interrupt, GIC gives the cause code.
+    /// The faulting access itself is skipped (an invalid register index doesn't become r0).
+    Exception,
+}
+
+/// The action for every fault kind, read from the `[faults]` table of a machine config:
+/// ```toml
+/// [faults]
+/// illegal_opcode = "halt"     # log by default, the others are ignored by default
+/// rom_write = "exception"
+/// unmapped_io = "log"
+/// invalid_register = "halt"
+/// vector = 0xF0               # where exceptions go, needed if one is used
+/// ```
+#[derive(Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct FaultPolicy {
+    pub illegal_opcode: FaultAction,
+    pub rom_write: FaultAction,
+    pub unmapped_io: FaultAction,
+    pub invalid_register: FaultAction,
+    pub vector: Option<u8>,
+}
+
+impl Default for FaultPolicy {
+    /// Behaves like the CPU always did, only unknown opcodes get reported.
+    fn default() -> Self {
+        Self {
+            illegal_opcode: FaultAction::Log,
+            rom_write: FaultAction::Ignore,
+            unmapped_io: FaultAction::Ignore,
+            invalid_register: FaultAction::Ignore,
+            vector: None,
+        }
+    }
+}
+
+impl FaultPolicy {
+    pub fn action(&self, kind: FaultKind) -> FaultAction {
+        match kind {
+            FaultKind::IllegalOpcode => self.illegal_opcode,
+            FaultKind::RomWrite => self.rom_write,
+            FaultKind::UnmappedIo => self.unmapped_io,
+            FaultKind::InvalidRegister => self.invalid_register,
+            FaultKind::DoubleFault => FaultAction::Halt,
+        }
+    }
+
+    /// Changes the action of a kind, the double fault always halts.
+    pub fn set_action(&mut self, kind: FaultKind, action: FaultAction) {
+        match kind {
+            FaultKind::IllegalOpcode => self.illegal_opcode = action,
+            FaultKind::RomWrite => self.rom_write = action,
+            FaultKind::UnmappedIo => self.unmapped_io = action,
+            FaultKind::InvalidRegister => self.invalid_register = action,
+            FaultKind::DoubleFault => {}
+        }
+    }
+
+    /// Exceptions need the vector.
+    pub fn check(&self) -> Result<(), String> {
+        let exceptions = [self.illegal_opcode, self.rom_write, self.unmapped_io, self.invalid_register]
+            .contains(&FaultAction::Exception);
+
+        if exceptions && self.vector.is_none() {
+            return Err("Faults can only raise exceptions with a fault vector".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// A fault, with the address of the instruction which caused it.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Fault {
+    pub kind: FaultKind,
+    pub address: u8,
+    /// The opcode, the memory or IO address, or the register index, depending on the kind.
+    pub detail: u8,
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            FaultKind::IllegalOpcode => write!(f, "{} at {:02X}", isa::unknown_instruction(self.detail), self.address),
+            FaultKind::RomWrite => write!(f, "write into the ROM at {:02X} by {:02X}", self.detail, self.address),
+            FaultKind::UnmappedIo => write!(f, "no device at IO address {:02X}, accessed by {:02X}", self.detail, self.address),
+            FaultKind::InvalidRegister => write!(f, "invalid register index {} at {:02X}", self.detail, self.address),
+            FaultKind::DoubleFault => write!(f, "double fault at {:02X}, cause {} while a handler was running", self.address, self.detail),
+        }
+    }
+}
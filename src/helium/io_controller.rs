@@ -4,20 +4,47 @@ use std::io::BufWriter;
 use std::io::Write;
 use std::ops::{Range};
 use owo_colors::OwoColorize;
+use serde::{Deserialize, Serialize};
 use crate::devices::device::Device;
+use crate::devices::interrupt_controller::{self, InterruptController};
+use crate::replay::{InputRecorder, InputReplay};
 
 #[derive(Debug)]
 struct RangedDevice {
     pub range: Range<u8>,
-    pub device: Box<dyn Device>
+    pub device: Box<dyn Device>,
+    /// The line on the interrupt controller, the first 8 devices get one.
+    pub line: Option<u8>,
+}
+
+/// The state of a mounted device, as stored in snapshots.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceState {
+    /// The name of the device, used to check that the snapshot fits the machine.
+    pub name: String,
+    pub address: u8,
+    pub state: Vec<u8>,
+}
+
+/// Where the input of the devices which talk to the outside world goes or comes from.
+#[derive(Debug)]
+enum InputSource {
+    Live,
+    Record(InputRecorder),
+    Replay(InputReplay),
 }
 
 /// Handles all "Hardware components" / Devices connected to the CPUs IO Bus
-/// Has generalized functions for everything and allows the mounting of Devices in a colliding way (the address ranges can match/intersect)
+/// Has generalized functions for everything, the address ranges of the mounted Devices can't intersect.
 #[derive(Debug)]
 pub struct IOController {
     devices: Vec<RangedDevice>,
     interrupt_log: Option<File>,
+    input: InputSource,
+    /// How many times the devices were updated, which is the number of executed instructions.
+    steps: u64,
+    /// The index of the interrupt controller in devices, if one is mounted.
+    interrupt_controller: Option<usize>,
 }
 // should probably work with callbacks, like IO.mount(addr_range, callback: Fn(addr, data))
 // also, it should give a warning if 2 "Devices" "Collide" in the address range, but it shouldn't crash.
@@ -33,28 +60,88 @@ impl IOController {
             file = Some(File::create("interrupts.log").expect("Failed to open interrupt log file."));
         }
 
-        Self { devices: Vec::new(), interrupt_log: file }
+        Self { devices: Vec::new(), interrupt_log: file, input: InputSource::Live, steps: 0, interrupt_controller: None }
     }
 
 
     /// Takes a device and a given address range for it to use.
-    pub fn mount_device<D>(&mut self, address: Range<u8>, mut device: D)
+    /// Fails if the range doesn't match the address space of the device or intersects another device,
+    /// only one interrupt controller can be mounted.
+    pub fn mount_device<D>(&mut self, address: Range<u8>, mut device: D) -> Result<(), String>
     where D: Device + 'static {
 
-        let addr_space = device.get_address_space();
+        let name = device.get_name().to_string();
 
-        if addr_space.is_some() && addr_space.unwrap() != address.len() as u8 {
-            println!("Device address space does not match given range");
+        if let Some(addr_space) = device.get_address_space() {
+            if addr_space as usize != address.len() {
+                return Err(format!("{} needs {} IO addresses but got {} ({:02X}..{:02X})",
+                                   name, addr_space, address.len(), address.start, address.end));
+            }
+        }
+
+        self.check_free(&address, &name)?;
+
+        let mut line = None;
+        if device.as_any().is::<InterruptController>() {
+            if self.interrupt_controller.is_some() {
+                return Err(String::from("Only one interrupt controller can be mounted"));
+            }
+            self.interrupt_controller = Some(self.devices.len());
+        } else {
+            let used = self.devices.iter().filter(|device| device.line.is_some()).count();
+            line = (used < interrupt_controller::LINES).then_some(used as u8);
         }
 
         device.init_device();
-        self.devices.push(RangedDevice { range: address, device: Box::new(device) });
+        self.devices.push(RangedDevice { range: address, device: Box::new(device), line });
+        Ok(())
+    }
+
+    /// Fails if a mounted device uses an address of the range, the name is what wants to use it.
+    pub fn check_free(&self, address: &Range<u8>, name: &str) -> Result<(), String> {
+        let collision = self.devices.iter()
+            .find(|other| address.start < other.range.end && other.range.start < address.end);
+
+        match collision {
+            Some(other) => Err(format!("{} at {:02X}..{:02X} overlaps {} at {:02X}..{:02X}",
+                                       name, address.start, address.end,
+                                       other.device.get_name(), other.range.start, other.range.end)),
+            None => Ok(()),
+        }
+    }
+
+    /// Writes the input of the devices into a replay file from now on.
+    pub fn record_input(&mut self, recorder: InputRecorder) {
+        self.input = InputSource::Record(recorder);
+    }
+
+    /// Feeds the devices the input of a replay file from now on, fails if there is no device for some of it.
+    /// The devices must not get input from anywhere else, see [`crate::devices::telnet_terminal::TelnetTerminal::replayed`].
+    pub fn replay_input(&mut self, replay: InputReplay) -> Result<(), String> {
+        for address in replay.addresses() {
+            if !self.devices.iter().any(|device| device.range.start == address) {
+                return Err(format!("The replay has input for a device at {:02X}, but nothing is mounted there", address));
+            }
+        }
+
+        self.input = InputSource::Replay(replay);
+        Ok(())
+    }
+
+    /// Stops recording or replaying, reports write errors and replays which went differently than the recording.
+    pub fn finish_input(&mut self) -> Result<(), String> {
+        match std::mem::replace(&mut self.input, InputSource::Live) {
+            InputSource::Live => Ok(()),
+            InputSource::Record(recorder) => recorder.finish(),
+            InputSource::Replay(replay) => replay.finish(),
+        }
     }
 
     /// Ran when the CPU starts up.
     pub fn startup(&mut self) {
         for device in &mut self.devices {
             device.device.startup();
+            Self::exchange_input(&mut self.input, self.steps, device);
         }
     }
 
@@ -73,19 +160,59 @@ impl IOController {
         }).next() // Return the first matching device found
     }
 
-    /// Updates the CPU
-    pub fn update(&mut self) {
+    /// How many times the devices were updated, recordings and replays are keyed to it.
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    /// Moves the step counter, used when stepping back.
+    pub fn set_steps(&mut self, steps: u64) {
+        self.steps = steps;
+    }
+
+    /// Updates the devices after an instruction which took the given clock cycles.
+    pub fn update(&mut self, cycles: u8) {
+        self.steps += 1;
+
         for device_data in &mut self.devices {
             device_data.device.update_device();
+            device_data.device.cycles_elapsed(cycles);
+            Self::exchange_input(&mut self.input, self.steps, device_data);
+        }
+    }
+
+    /// Records the input the device got, in a replay it gets the input of the step instead.
+    fn exchange_input(input: &mut InputSource, step: u64, device: &mut RangedDevice) {
+        match input {
+            InputSource::Live => {
+                device.device.take_input();
+            }
+            InputSource::Record(recorder) => {
+                for device_input in device.device.take_input() {
+                    recorder.input(step, device.range.start, device_input);
+                }
+            }
+            InputSource::Replay(replay) => {
+                device.device.take_input();
+                while let Some(device_input) = replay.take_input(step, device.range.start) {
+                    device.device.replay_input(device_input);
+                }
+            }
         }
     }
 
     /// Checks all devices if they would like to cause an interrupt.
+    /// With an interrupt controller their lines get raised on it and it decides which line interrupts the CPU.
     pub fn device_has_interrupt_request(&mut self) -> Option<u8> {
         let mut has_interrupt = false;
         let mut int_code: u8 = 0;
+        let mut lines: Vec<u8> = Vec::new();
+
+        for (index, device) in self.devices.iter_mut().enumerate() {
+            if self.interrupt_controller == Some(index) {
+                continue;
+            }
 
-        for device in &mut self.devices {
             if let Some((code, int_log)) = device.device.has_interrupt_request() {
                 // try logging this
                 if self.interrupt_log.is_some() {
@@ -98,6 +225,7 @@ impl IOController {
 
                 has_interrupt = true;
                 int_code = int_code | code;
+                lines.extend(device.line);
             }
         }
 
@@ -110,10 +238,34 @@ impl IOController {
         }
 
 
-        if !has_interrupt {
-            None
-        } else {
-            Some(int_code)
+        let request = match self.interrupt_controller {
+            Some(index) => {
+                let controller = &mut self.devices[index].device;
+                for line in lines {
+                    controller.raise_line(line);
+                }
+                controller.has_interrupt_request().map(|(line, _)| line)
+            }
+            None if has_interrupt => Some(int_code),
+            None => None,
+        };
+
+        match &mut self.input {
+            InputSource::Live => {}
+            InputSource::Record(recorder) => {
+                if let Some(code) = request {
+                    recorder.interrupt(self.steps, code);
+                }
+            }
+            InputSource::Replay(replay) => replay.check_interrupt(self.steps, request),
+        }
+        request
+    }
+
+    /// Tells the interrupt controller (if there is one) that the CPU entered the interrupt it requested.
+    pub fn acknowledge_interrupt(&mut self) {
+        if let Some(index) = self.interrupt_controller {
+            self.devices[index].device.acknowledge_interrupt();
         }
     }
 
@@ -132,9 +284,55 @@ impl IOController {
         out_buffer
     }
 
+    /// Like draw_ui, but every device on its own with a title made of its name, IO range
+    /// and interrupt line (if there is an interrupt controller).
+    pub fn draw_device_uis(&mut self, no_gui: bool, debug: bool) -> Vec<(String, Option<String>)> {
+        let has_controller = self.interrupt_controller.is_some();
+
+        self.devices.iter_mut().map(|dev| {
+            let mut title = format!("{} {:02X}..{:02X}", dev.device.get_name(), dev.range.start, dev.range.end);
+            if let (true, Some(line)) = (has_controller, dev.line) {
+                title.push_str(&format!(" line {}", line));
+            }
+            (title, dev.device.draw_ui(no_gui, debug))
+        }).collect()
+    }
+
+    /// Collects the state of every device, in mounting order.
+    pub fn save_state(&self) -> Vec<DeviceState> {
+        self.devices.iter().map(|device| DeviceState {
+            name: device.device.get_name().to_string(),
+            address: device.range.start,
+            state: device.device.save_state(),
+        }).collect()
+    }
+
+    /// Restores the state of every device, the same devices have to be mounted at the same addresses.
+    pub fn load_state(&mut self, states: &[DeviceState]) -> Result<(), String> {
+        if states.len() != self.devices.len() {
+            return Err(format!("The snapshot has {} devices but the machine has {}", states.len(), self.devices.len()));
+        }
+
+        for (device, state) in self.devices.iter().zip(states) {
+            if device.device.get_name() != state.name || device.range.start != state.address {
+                return Err(format!("The snapshot has {} at {:02X} where the machine has {} at {:02X}",
+                                   state.name, state.address, device.device.get_name(), device.range.start));
+            }
+        }
+
+        for (device, state) in self.devices.iter_mut().zip(states) {
+            device.device.load_state(&state.state)
+                .map_err(|e| format!("{}: {}", state.name, e))?;
+        }
+        Ok(())
+    }
+
+    /// True if a device is mounted on the address.
+    pub fn is_mapped(&self, address: u8) -> bool {
+        self.devices.iter().any(|device| device.range.contains(&address))
+    }
+
     /// Read Data from a device on a given address, if no device is present, result is 0.
-    /// ## Caution:
-    /// If multiple devices are listening on this address: all results get Binary OR-d
     pub fn read(&mut self, address: u8) -> u8 {
         let mut out = 0;
 
@@ -147,8 +345,7 @@ impl IOController {
         return out
     }
 
-    /// Write data to the give IO address,
-    /// all devices listening on the given address will get the data.
+    /// Write data to the give IO address, if no device is present, it's ignored.
     pub fn write(&mut self, address: u8, data: u8) {
         for device_data in &mut self.devices {
             if !device_data.range.contains(&address) { continue }
@@ -0,0 +1,383 @@
+// The opcodes are grouped like in isa.txt (00_0001_xx), not by nibbles.
+#![allow(clippy::unusual_byte_groupings)]
+
+use bitmatch::bitmatch;
+
+/// A register operand, an index into the 4 general purpose registers (r0 - r3).
+pub type Reg = u8;
+
+/// The conditions used by the JMP and JMPR instructions, the discriminant is the `ccc` field of the opcode.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Condition {
+    Always = 0,
+    Carry = 1,
+    NotCarry = 2,
+    Overflow = 3,
+    NotOverflow = 4,
+    Zero = 5,
+    NotZero = 6,
+    Signed = 7,
+}
+
+impl Condition {
+    /// All conditions ordered by their code.
+    pub const ALL: [Condition; 8] = [
+        Condition::Always,
+        Condition::Carry,
+        Condition::NotCarry,
+        Condition::Overflow,
+        Condition::NotOverflow,
+        Condition::Zero,
+        Condition::NotZero,
+        Condition::Signed,
+    ];
+
+    /// Creates a condition from the lower 3 bits of the given value.
+    pub fn from_bits(bits: u8) -> Self {
+        Self::ALL[(bits & 0b111) as usize]
+    }
+
+    /// The 3 bit condition code.
+    pub fn bits(self) -> u8 {
+        self as u8
+    }
+
+    /// The short name used by the assembler, like `NZ` for not zero.
+    pub fn mnemonic(self) -> &'static str {
+        match self {
+            Condition::Always => "AL",
+            Condition::Carry => "C",
+            Condition::NotCarry => "NC",
+            Condition::Overflow => "O",
+            Condition::NotOverflow => "NO",
+            Condition::Zero => "Z",
+            Condition::NotZero => "NZ",
+            Condition::Signed => "S",
+        }
+    }
+
+    /// Looks up a condition by its mnemonic (case-insensitive).
+    pub fn from_mnemonic(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|cond| cond.mnemonic().eq_ignore_ascii_case(name))
+    }
+}
+
+/// Optional additions to the ISA, they take over encodings which are useless in the base ISA,
+/// so a machine has to enable them, see [`Instruction::decode_with`].
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct Extensions {
+    /// An SP register with PUSH, POP, CALL, RET and XSP.
+    pub stack: bool,
+    /// Nested interrupts: entering an interrupt pushes the return address, the flags and IE
+    /// onto an interrupt return stack this deep and disables interrupts, RETI pops them again.
+    pub interrupt_nesting: Option<u8>,
+}
+
+/// Every instruction of the ISA described in isa.txt, by the meaning of its opcode byte.
+/// The imm8 operand (if there is one) is not part of this, it's the byte following the opcode,
+/// see [`Instruction::has_imm8`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Instruction {
+    /// 00_0000_00
+    Halt,
+    /// 00_0001_xx && imm8: reg[xx] = imm8
+    LoadImm(Reg),
+    /// 00_0010_xx && imm8: reg[xx] = mem[imm8]
+    Load(Reg),
+    /// 00_0011_xx && imm8: mem[imm8] = reg[xx]
+    Store(Reg),
+    /// 00_0100_xx && imm8: reg[xx] = IO[imm8]
+    In(Reg),
+    /// 00_0101_xx && imm8: reg[xx] = IO[reg[imm8]]
+    InReg(Reg),
+    /// 00_0110_xx && imm8: IO[imm8] = reg[xx]
+    Out(Reg),
+    /// 00_0111_xx && imm8: IO[reg[imm8]] = reg[xx]
+    OutReg(Reg),
+    /// 00_1000_xx: swaps the flags and reg[xx]
+    FlagSwap(Reg),
+    /// 00_1001_xx
+    ShiftRight(Reg),
+    /// 00_1010_xx
+    ShiftLeft(Reg),
+    /// 00_1011_xx: interrupt address = reg[xx]
+    SetInterruptAddr(Reg),
+    /// 00_1100_00
+    EnableInterrupt,
+    /// 00_1100_01
+    ClearInterrupt,
+    /// 00_1100_10
+    DisableInterrupt,
+    /// 00_1100_11
+    ReturnFromInterrupt,
+    /// 00_1101_00
+    CallInterrupt,
+    /// 00_1101_01: r0 = interrupt code
+    GetInterruptCause,
+    /// 00_1101_10
+    Reset,
+    /// 00_1101_11
+    NoOp,
+    /// 00_1110_xx: reg[xx] = PC
+    LoadPc(Reg),
+    /// 00_1111_xx: rotate right through the carry flag
+    RotateRight(Reg),
+    /// 01_00_xx_yy: reg[yy] = reg[xx] + reg[yy]
+    Add(Reg, Reg),
+    /// 01_01_xx_yy: reg[yy] = reg[yy] - reg[xx]
+    Sub(Reg, Reg),
+    /// 01_10_xx_yy: like ADD but only the flags get updated
+    CompareAdd(Reg, Reg),
+    /// 01_11_xx_yy: like SUB but only the flags get updated
+    Compare(Reg, Reg),
+    /// 10_00_xx_yy: reg[yy] = reg[xx] & reg[yy]
+    And(Reg, Reg),
+    /// 10_01_xx_yy: reg[yy] = reg[xx] ^ reg[yy]
+    Xor(Reg, Reg),
+    /// 10_10_xx_yy: reg[yy] = reg[xx] | reg[yy]
+    Or(Reg, Reg),
+    /// 10_11_xx_yy: reg[yy] = reg[xx]
+    Move(Reg, Reg),
+    /// 11_00_xx_yy: reg[xx] = mem[reg[yy]]
+    LoadReg(Reg, Reg),
+    /// 11_01_xx_yy: mem[reg[yy]] = reg[xx]
+    StoreReg(Reg, Reg),
+    /// 11_10_ccc_r && imm8: jump to imm8 (or reg[imm8] if r is set)
+    Jump(Condition, bool),
+    /// 11_11_ccc_r && imm8: jump to PC + imm8 (or PC + reg[imm8] if r is set)
+    JumpRel(Condition, bool),
+
+    /// Stack extension, takes MOV rX, rX (10_11_xx_xx): SP -= 1, mem[SP] = reg[xx]
+    Push(Reg),
+    /// Stack extension, takes STR rX, rX (11_01_xx_xx): reg[xx] = mem[SP], SP += 1
+    Pop(Reg),
+    /// Stack extension, takes CMN r0, r0 (01_10_00_00) && imm8: pushes the return address and jumps to imm8
+    Call,
+    /// Stack extension, takes CMN r1, r1 (01_10_01_01): pops the PC
+    Return,
+    /// Stack extension, takes OR rX, rX (10_10_xx_xx): swaps SP and reg[xx]
+    StackSwap(Reg),
+}
+
+impl Instruction {
+    /// Decodes an opcode byte, this is the decode table used by the CPU, the first matching pattern wins.
+    /// Returns None if the opcode doesn't mean anything.
+    #[bitmatch]
+    pub fn decode(opcode: u8) -> Option<Self> {
+        #[bitmatch]
+        match opcode {
+            "0000_0000" => Some(Instruction::Halt),
+            "0000_01_xx" => Some(Instruction::LoadImm(x)),
+
+            "00_0010_xx" => Some(Instruction::Load(x)),
+            "00_0011_xx" => Some(Instruction::Store(x)),
+
+            "00_0100_xx" => Some(Instruction::In(x)),
+            "00_0101_xx" => Some(Instruction::InReg(x)),
+            "00_0110_xx" => Some(Instruction::Out(x)),
+            "00_0111_xx" => Some(Instruction::OutReg(x)),
+
+            "00_1000_xx" => Some(Instruction::FlagSwap(x)),
+            "00_1001_xx" => Some(Instruction::ShiftRight(x)),
+            "00_1010_xx" => Some(Instruction::ShiftLeft(x)),
+
+            // Interrupt instructions
+            "00_1011_xx" => Some(Instruction::SetInterruptAddr(x)),
+            "00_1100_00" => Some(Instruction::EnableInterrupt),
+            "00_1100_01" => Some(Instruction::ClearInterrupt),
+            "00_1100_10" => Some(Instruction::DisableInterrupt),
+            "00_1100_11" => Some(Instruction::ReturnFromInterrupt),
+            "00_1101_00" => Some(Instruction::CallInterrupt),
+            "00_1101_01" => Some(Instruction::GetInterruptCause),
+
+            // Some system commands
+            "00_1101_10" => Some(Instruction::Reset),
+            "00_1101_11" => Some(Instruction::NoOp),
+            "00_1110_xx" => Some(Instruction::LoadPc(x)),
+            "00_1111_xx" => Some(Instruction::RotateRight(x)),
+
+            // ALU ops
+            "01_00_xx_yy" => Some(Instruction::Add(x, y)),
+            "01_01_xx_yy" => Some(Instruction::Sub(x, y)),
+            "01_10_xx_yy" => Some(Instruction::CompareAdd(x, y)),
+            "01_11_xx_yy" => Some(Instruction::Compare(x, y)),
+
+            // Logic ops & move
+            "10_00_xx_yy" => Some(Instruction::And(x, y)),
+            "10_01_xx_yy" => Some(Instruction::Xor(x, y)),
+            "10_10_xx_yy" => Some(Instruction::Or(x, y)),
+            "10_11_xx_yy" => Some(Instruction::Move(x, y)),
+
+            // memory with regs
+            "11_00_xx_yy" => Some(Instruction::LoadReg(x, y)),
+            "11_01_xx_yy" => Some(Instruction::StoreReg(x, y)),
+
+            // Jumps
+            "11_10_xxx_y" => Some(Instruction::Jump(Condition::from_bits(x), y == 1)),
+            "11_11_xxx_y" => Some(Instruction::JumpRel(Condition::from_bits(x), y == 1)),
+
+            "????_????" => None,
+        }
+    }
+
+    /// Like decode, but the enabled extensions take over the encodings they use.
+    pub fn decode_with(opcode: u8, extensions: Extensions) -> Option<Self> {
+        let instruction = Self::decode(opcode);
+        if !extensions.stack {
+            return instruction;
+        }
+
+        match instruction {
+            Some(Instruction::Move(x, y)) if x == y => Some(Instruction::Push(x)),
+            Some(Instruction::StoreReg(x, y)) if x == y => Some(Instruction::Pop(x)),
+            Some(Instruction::Or(x, y)) if x == y => Some(Instruction::StackSwap(x)),
+            Some(Instruction::CompareAdd(0, 0)) => Some(Instruction::Call),
+            Some(Instruction::CompareAdd(1, 1)) => Some(Instruction::Return),
+            other => other,
+        }
+    }
+
+    /// Encodes the instruction into its opcode byte.
+    pub fn opcode(&self) -> u8 {
+        match *self {
+            Instruction::Halt => 0b00_0000_00,
+            Instruction::LoadImm(reg) => 0b00_0001_00 | reg & 3,
+            Instruction::Load(reg) => 0b00_0010_00 | reg & 3,
+            Instruction::Store(reg) => 0b00_0011_00 | reg & 3,
+            Instruction::In(reg) => 0b00_0100_00 | reg & 3,
+            Instruction::InReg(reg) => 0b00_0101_00 | reg & 3,
+            Instruction::Out(reg) => 0b00_0110_00 | reg & 3,
+            Instruction::OutReg(reg) => 0b00_0111_00 | reg & 3,
+            Instruction::FlagSwap(reg) => 0b00_1000_00 | reg & 3,
+            Instruction::ShiftRight(reg) => 0b00_1001_00 | reg & 3,
+            Instruction::ShiftLeft(reg) => 0b00_1010_00 | reg & 3,
+            Instruction::SetInterruptAddr(reg) => 0b00_1011_00 | reg & 3,
+            Instruction::EnableInterrupt => 0b00_1100_00,
+            Instruction::ClearInterrupt => 0b00_1100_01,
+            Instruction::DisableInterrupt => 0b00_1100_10,
+            Instruction::ReturnFromInterrupt => 0b00_1100_11,
+            Instruction::CallInterrupt => 0b00_1101_00,
+            Instruction::GetInterruptCause => 0b00_1101_01,
+            Instruction::Reset => 0b00_1101_10,
+            Instruction::NoOp => 0b00_1101_11,
+            Instruction::LoadPc(reg) => 0b00_1110_00 | reg & 3,
+            Instruction::RotateRight(reg) => 0b00_1111_00 | reg & 3,
+            Instruction::Add(a, b) => 0b01_00_00_00 | Self::reg_pair(a, b),
+            Instruction::Sub(a, b) => 0b01_01_00_00 | Self::reg_pair(a, b),
+            Instruction::CompareAdd(a, b) => 0b01_10_00_00 | Self::reg_pair(a, b),
+            Instruction::Compare(a, b) => 0b01_11_00_00 | Self::reg_pair(a, b),
+            Instruction::And(a, b) => 0b10_00_00_00 | Self::reg_pair(a, b),
+            Instruction::Xor(a, b) => 0b10_01_00_00 | Self::reg_pair(a, b),
+            Instruction::Or(a, b) => 0b10_10_00_00 | Self::reg_pair(a, b),
+            Instruction::Move(a, b) => 0b10_11_00_00 | Self::reg_pair(a, b),
+            Instruction::LoadReg(a, b) => 0b11_00_00_00 | Self::reg_pair(a, b),
+            Instruction::StoreReg(a, b) => 0b11_01_00_00 | Self::reg_pair(a, b),
+            Instruction::Jump(cond, is_reg) => 0b11_10_000_0 | cond.bits() << 1 | is_reg as u8,
+            Instruction::JumpRel(cond, is_reg) => 0b11_11_000_0 | cond.bits() << 1 | is_reg as u8,
+            Instruction::Push(reg) => Instruction::Move(reg, reg).opcode(),
+            Instruction::Pop(reg) => Instruction::StoreReg(reg, reg).opcode(),
+            Instruction::Call => Instruction::CompareAdd(0, 0).opcode(),
+            Instruction::Return => Instruction::CompareAdd(1, 1).opcode(),
+            Instruction::StackSwap(reg) => Instruction::Or(reg, reg).opcode(),
+        }
+    }
+
+    /// Returns true if the opcode is followed by an imm8 operand.
+    pub fn has_imm8(&self) -> bool {
+        matches!(self,
+            Instruction::LoadImm(_)
+            | Instruction::Load(_)
+            | Instruction::Store(_)
+            | Instruction::In(_)
+            | Instruction::InReg(_)
+            | Instruction::Out(_)
+            | Instruction::OutReg(_)
+            | Instruction::Jump(..)
+            | Instruction::JumpRel(..)
+            | Instruction::Call
+        )
+    }
+
+    /// How many clock cycles the instruction takes:
+    /// one for fetching the opcode, one for the imm8 and one for the data access of loads, stores, IO and the stack.
+    /// ```text
+    /// 1: everything else
+    /// 2: LDI, JMP, JMPR, LDR, STR, PUSH, POP, RET
+    /// 3: LD, ST, IN, OUT, CALL
+    /// ```
+    pub fn cycles(&self) -> u8 {
+        let data_access = matches!(self,
+            Instruction::Load(_)
+            | Instruction::Store(_)
+            | Instruction::In(_)
+            | Instruction::InReg(_)
+            | Instruction::Out(_)
+            | Instruction::OutReg(_)
+            | Instruction::LoadReg(..)
+            | Instruction::StoreReg(..)
+            | Instruction::Push(_)
+            | Instruction::Pop(_)
+            | Instruction::Call
+            | Instruction::Return
+        );
+        1 + self.has_imm8() as u8 + data_access as u8
+    }
+
+    /// The name used by the assembler and the disassembler.
+    pub fn mnemonic(&self) -> &'static str {
+        match self {
+            Instruction::Halt => "HLT",
+            Instruction::LoadImm(_) => "LDI",
+            Instruction::Load(_) => "LD",
+            Instruction::Store(_) => "ST",
+            Instruction::In(_) | Instruction::InReg(_) => "IN",
+            Instruction::Out(_) | Instruction::OutReg(_) => "OUT",
+            Instruction::FlagSwap(_) => "FSWAP",
+            Instruction::ShiftRight(_) => "SHR",
+            Instruction::ShiftLeft(_) => "SHL",
+            Instruction::SetInterruptAddr(_) => "SIA",
+            Instruction::EnableInterrupt => "EI",
+            Instruction::ClearInterrupt => "CI",
+            Instruction::DisableInterrupt => "DI",
+            Instruction::ReturnFromInterrupt => "RETI",
+            Instruction::CallInterrupt => "INT",
+            Instruction::GetInterruptCause => "GIC",
+            Instruction::Reset => "RST",
+            Instruction::NoOp => "NOP",
+            Instruction::LoadPc(_) => "LPC",
+            Instruction::RotateRight(_) => "ROR",
+            Instruction::Add(..) => "ADD",
+            Instruction::Sub(..) => "SUB",
+            Instruction::CompareAdd(..) => "CMN",
+            Instruction::Compare(..) => "CMP",
+            Instruction::And(..) => "AND",
+            Instruction::Xor(..) => "XOR",
+            Instruction::Or(..) => "OR",
+            Instruction::Move(..) => "MOV",
+            Instruction::LoadReg(..) => "LDR",
+            Instruction::StoreReg(..) => "STR",
+            Instruction::Jump(..) => "JMP",
+            Instruction::JumpRel(..) => "JMPR",
+            Instruction::Push(_) => "PUSH",
+            Instruction::Pop(_) => "POP",
+            Instruction::Call => "CALL",
+            Instruction::Return => "RET",
+            Instruction::StackSwap(_) => "XSP",
+        }
+    }
+
+    /// The length of the instruction in bytes, including the imm8.
+    pub fn size(&self) -> u8 {
+        if self.has_imm8() { 2 } else { 1 }
+    }
+
+    /// Packs 2 register indexes into the lower 4 bits (xx_yy).
+    fn reg_pair(a: Reg, b: Reg) -> u8 {
+        (a & 3) << 2 | b & 3
+    }
+}
+
+/// How undecodable opcodes get reported.
+pub fn unknown_instruction(opcode: u8) -> String {
+    format!("unknown instruction: {:08b}", opcode)
+}
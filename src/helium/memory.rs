@@ -1,56 +1,552 @@
 use owo_colors::{OwoColorize, Style};
+use std::collections::BTreeSet;
+use std::ops::Range;
+use serde::{Deserialize, Serialize};
 use crate::utils::chars::*;
+
+/// The size of the address space, every u8 address is valid.
+const MEMORY_SIZE: usize = u8::MAX as usize + 1;
+
+/// The kinds of accesses a watchpoint can trigger on.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum WatchKind {
+    /// A data read (LD, LDR), instruction fetches don't count.
+    Read,
+    /// Any write, even if the value stays the same.
+    Write,
+    /// A write which changes the value.
+    Change,
+    /// A write into the ROM which got rejected,
+    /// these get reported on every ROM address while enabled with [`MemoryControl::set_rom_write_watch`].
+    RomWrite,
+}
+
+/// A triggered watchpoint, for reads old_value and new_value are the same.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct WatchHit {
+    pub kind: WatchKind,
+    pub address: u8,
+    pub old_value: u8,
+    pub new_value: u8,
+}
+
+/// How a banked memory is split up, read from the `[banks]` table of a machine config:
+/// ```toml
+/// [banks]
+/// size = 0x40      # the size of a bank and of a window
+/// windows = 1      # the switchable windows at the top of the address space
+/// count = 8        # how many banks there are
+/// select = 0x50    # the IO address of the bank select register of the first window
+/// ```
+/// Everything below the windows is the fixed low window. In the physical memory the banks follow the fixed window,
+/// at the start window i shows bank i, so the first 256 bytes of a ROM image are what the CPU sees after a reset.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BankLayout {
+    pub size: u8,
+    #[serde(default = "default_windows")]
+    pub windows: u8,
+    pub count: u8,
+    /// Window i is selected by writing the bank number to `select + i`, reading it gives the selected bank.
+    pub select: u8,
+}
+
+fn default_windows() -> u8 { 1 }
+
+impl BankLayout {
+    /// Checks that the windows leave room for the fixed window and that every window has its bank.
+    pub fn check(&self) -> Result<(), String> {
+        if self.size == 0 || self.windows == 0 {
+            return Err("The bank size and the window count have to be at least 1".to_string());
+        }
+        if self.windows as usize * self.size as usize >= MEMORY_SIZE {
+            return Err(format!("{} windows of {} bytes leave no room for the fixed window", self.windows, self.size));
+        }
+        if self.count < self.windows {
+            return Err(format!("{} banks are not enough for {} windows", self.count, self.windows));
+        }
+        if self.select as usize + self.windows as usize >= MEMORY_SIZE {
+            return Err(format!("The bank select registers don't fit after {:02X}", self.select));
+        }
+        Ok(())
+    }
+
+    /// The first address of the windows, which is also the size of the fixed window.
+    pub fn window_start(&self) -> u8 {
+        (MEMORY_SIZE - self.windows as usize * self.size as usize) as u8
+    }
+
+    /// Where the bank starts in the physical memory (and in a ROM image).
+    pub fn bank_offset(&self, bank: u8) -> usize {
+        self.window_start() as usize + bank as usize * self.size as usize
+    }
+
+    /// The size of the physical memory, the fixed window and every bank.
+    pub fn memory_size(&self) -> usize {
+        self.bank_offset(self.count)
+    }
+
+    /// The IO addresses of the bank select registers.
+    pub fn select_registers(&self) -> Range<u8> {
+        self.select..self.select.saturating_add(self.windows)
+    }
+}
+
+/// What the CPU finds in a region of the memory map.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RegionKind {
+    /// Writes get rejected.
+    Rom,
+    /// Writable, even where the ROM image is.
+    Ram,
+    /// Every byte can be written once, after that it behaves like ROM.
+    WriteOnce,
+    /// Nothing answers, reads give the open bus value and writes are lost.
+    Unmapped,
+    /// LD and ST go to the devices, as if IN and OUT were used on the IO address.
+    Device,
+}
+
+/// The read value of unmapped memory if the region doesn't say otherwise.
+const OPEN_BUS: u8 = 0xFF;
+
+/// A region of the memory map, read from the `[[region]]` tables of a machine config:
+/// ```toml
+/// [[region]]
+/// type = "unmapped"
+/// start = 0xE0
+/// end = 0xEF          # inclusive
+/// open_bus = 0x00     # 0xFF by default
+///
+/// [[region]]
+/// type = "device"
+/// start = 0xF0
+/// end = 0xF3
+/// io = 0x36           # F0 is IO address 36, F1 is 37 ...
+/// ```
+/// Addresses outside of every region keep the default map: the ROM image and RAM after it.
+/// The regions are in the address space of the CPU, so with banks they apply to whatever bank is selected.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MemoryRegion {
+    #[serde(rename = "type")]
+    pub kind: RegionKind,
+    pub start: u8,
+    pub end: u8,
+    /// What an unmapped region reads as.
+    pub open_bus: Option<u8>,
+    /// The IO address the start of a device region is mapped to.
+    pub io: Option<u8>,
+}
+
+impl MemoryRegion {
+    pub fn contains(&self, address: u8) -> bool {
+        (self.start..=self.end).contains(&address)
+    }
+
+    /// Checks that the region isn't empty and only has the options of its kind.
+    pub fn check(&self) -> Result<(), String> {
+        if self.start > self.end {
+            return Err(format!("The region {:02X}..={:02X} ends before it starts", self.start, self.end));
+        }
+        if self.open_bus.is_some() && self.kind != RegionKind::Unmapped {
+            return Err(format!("open_bus is only an option of unmapped regions, not of {:?}", self.kind));
+        }
+        match (self.kind, self.io) {
+            (RegionKind::Device, None) => Err("A device region needs the io address".to_string()),
+            (RegionKind::Device, Some(io)) if io as usize + (self.end - self.start) as usize >= MEMORY_SIZE => {
+                Err(format!("The device region {:02X}..={:02X} doesn't fit into the IO space from {:02X}", self.start, self.end, io))
+            }
+            (RegionKind::Device, Some(_)) => Ok(()),
+            (kind, Some(_)) => Err(format!("io is only an option of device regions, not of {:?}", kind)),
+            (_, None) => Ok(()),
+        }
+    }
+
+    /// What the hexdump legend says about the region.
+    fn describe(&self) -> String {
+        match self.kind {
+            RegionKind::Rom => "rom".to_string(),
+            RegionKind::Ram => "ram".to_string(),
+            RegionKind::WriteOnce => "write-once".to_string(),
+            RegionKind::Unmapped => format!("unmapped, reads {:02X}", self.open_bus.unwrap_or(OPEN_BUS)),
+            RegionKind::Device => format!("devices at IO {:02X}", self.io.unwrap_or(0)),
+        }
+    }
+}
+
+/// The contents of the memory, as stored in snapshots.
+/// With banks the contents are the whole physical memory.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MemoryState {
+    pub contents: Vec<u8>,
+    pub rom_limit: Option<usize>,
+    /// The bank selected by each window, empty without banks.
+    #[serde(default)]
+    pub selected_banks: Vec<u8>,
+    /// The physical addresses in write-once regions which were written already.
+    #[serde(default)]
+    pub written_once: Vec<usize>,
+    /// False once the program unmapped the ROM with the ROM control register.
+    #[serde(default = "default_rom_mapped")]
+    pub rom_mapped: bool,
+}
+
+fn default_rom_mapped() -> bool { true }
+
 /// Responsible for making sure there is a "ROM" block in the memory.
 /// Allows the reading and writing of memory, 
 /// also has draw_ui which basically generates a styled hexdump of the memory.
-#[derive(Debug, Copy, Clone)]
+///
+/// Watchpoints can be put on addresses, the hits get collected until [`MemoryControl::take_watch_hits`] is called.
+///
+/// A banked memory (see [`BankLayout`]) is bigger than the address space, the addresses the CPU uses
+/// go through the selected banks. The ROM is the start of the physical memory.
+///
+/// The memory map (see [`MemoryRegion`]) can override what an address is, device regions are handled by the CPU,
+/// here they read as 0 and ignore writes.
+///
+/// With the ROM control register (see [`MemoryControl::enable_rom_control`]) there is RAM under the ROM,
+/// stored after the physical memory. Writes into the ROM go to that RAM, and once the ROM is unmapped it's read too.
+/// That's how a boot ROM can load a program to address 0 and then get out of the way.
+#[derive(Debug, Clone)]
 pub struct MemoryControl {
-    container: [u8; u8::MAX as usize],
-    rom_limit: Option<u8>,
+    container: Vec<u8>,
+    /// The last physical address of the ROM.
+    rom_limit: Option<usize>,
+
+    banks: Option<BankLayout>,
+    selected_banks: Vec<u8>,
+
+    regions: Vec<MemoryRegion>,
+    written_once: BTreeSet<usize>,
+
+    /// The IO address of the ROM control register, None if the ROM can't be unmapped.
+    rom_control: Option<u8>,
+    rom_mapped: bool,
+    /// Where the RAM under the ROM starts in the container, the size of the physical memory.
+    shadow_start: usize,
+
+    watchpoints: Vec<(u8, WatchKind)>,
+    watch_rom_writes: bool,
+    watch_hits: Vec<WatchHit>,
 }
 impl MemoryControl {
-    pub fn new(mut rom: Vec<u8>) -> Self {
-        assert!(rom.len() <= u8::MAX as usize);
-
-        let mut container: [u8; u8::MAX as usize] = [0; u8::MAX as usize];
-        let len = rom.len() as u8;
+    pub fn new(rom: Vec<u8>) -> Self {
+        assert!(rom.len() <= MEMORY_SIZE);
+        Self::with_size(rom, MEMORY_SIZE)
+    }
 
-        let drain = rom.drain(..);
-        for (i, val) in drain.enumerate() {
-            container[i] = val;
+    /// Creates a banked memory, the ROM image can span the fixed window and every bank.
+    pub fn banked(rom: Vec<u8>, layout: BankLayout) -> Result<Self, String> {
+        layout.check()?;
+        if rom.len() > layout.memory_size() {
+            return Err(format!("The rom image has {} bytes, the banked memory only {}", rom.len(), layout.memory_size()));
         }
 
-        let mut rom_limit: Option<u8> = None;
+        let mut memory = Self::with_size(rom, layout.memory_size());
+        memory.banks = Some(layout);
+        memory.selected_banks = (0..layout.windows).collect();
+        Ok(memory)
+    }
 
-        if len != 0 {
-            rom_limit = Some(len - 1);
-        }
+    fn with_size(mut rom: Vec<u8>, size: usize) -> Self {
+        let rom_limit = rom.len().checked_sub(1);
+        rom.resize(size, 0);
 
         Self {
-            container,
+            container: rom,
             rom_limit,
+
+            banks: None,
+            selected_banks: Vec::new(),
+
+            regions: Vec::new(),
+            written_once: BTreeSet::new(),
+
+            rom_control: None,
+            rom_mapped: true,
+            shadow_start: size,
+
+            watchpoints: Vec::new(),
+            watch_rom_writes: false,
+            watch_hits: Vec::new(),
+        }
+    }
+
+    /// Sets the memory map, fails if a region is invalid or two regions overlap.
+    pub fn set_regions(&mut self, regions: Vec<MemoryRegion>) -> Result<(), String> {
+        for (i, region) in regions.iter().enumerate() {
+            region.check()?;
+
+            let overlap = regions[..i].iter()
+                .find(|other| region.start <= other.end && other.start <= region.end);
+            if let Some(other) = overlap {
+                return Err(format!("The region {:02X}..={:02X} overlaps {:02X}..={:02X}", region.start, region.end, other.start, other.end));
+            }
+        }
+
+        self.regions = regions;
+        Ok(())
+    }
+
+    pub fn regions(&self) -> &[MemoryRegion] {
+        &self.regions
+    }
+
+    fn region(&self, index: u8) -> Option<&MemoryRegion> {
+        self.regions.iter().find(|region| region.contains(index))
+    }
+
+    /// The IO address a device region maps the address to, None if it's not in one.
+    pub fn device_address(&self, index: u8) -> Option<u8> {
+        let region = self.region(index)?;
+        match region.kind {
+            RegionKind::Device => region.io.map(|io| io + (index - region.start)),
+            _ => None,
+        }
+    }
+
+    /// The bank layout, None for a flat memory.
+    pub fn banks(&self) -> Option<BankLayout> {
+        self.banks
+    }
+
+    /// The bank shown by each window.
+    pub fn selected_banks(&self) -> &[u8] {
+        &self.selected_banks
+    }
+
+    /// The window whose bank select register is at the IO address.
+    pub fn bank_select_register(&self, io_address: u8) -> Option<usize> {
+        let layout = self.banks?;
+        layout.select_registers().contains(&io_address).then(|| (io_address - layout.select) as usize)
+    }
+
+    /// Shows the bank in the window, bank numbers past the last bank wrap around.
+    pub fn select_bank(&mut self, window: usize, bank: u8) {
+        if let Some(layout) = self.banks {
+            self.selected_banks[window] = bank % layout.count;
+        }
+    }
+
+    /// Adds the ROM control register at the IO address and the RAM under the ROM, which starts out as zeros.
+    /// Bit 0 of the register enables the ROM, so writing 0 unmaps it and writing 1 maps it again, it starts out as 1.
+    pub fn enable_rom_control(&mut self, io_address: u8) {
+        if self.rom_control.is_none() {
+            let rom_size = self.rom_limit.map_or(0, |limit| limit + 1);
+            self.container.resize(self.shadow_start + rom_size, 0);
+        }
+        self.rom_control = Some(io_address);
+    }
+
+    /// The IO address of the ROM control register.
+    pub fn rom_control(&self) -> Option<u8> {
+        self.rom_control
+    }
+
+    pub fn is_rom_control(&self, io_address: u8) -> bool {
+        self.rom_control == Some(io_address)
+    }
+
+    pub fn rom_mapped(&self) -> bool {
+        self.rom_mapped
+    }
+
+    /// Maps the ROM in or out, does nothing without the ROM control register.
+    pub fn map_rom(&mut self, mapped: bool) {
+        if self.rom_control.is_some() {
+            self.rom_mapped = mapped;
+        }
+    }
+
+    /// Where the byte under the physical ROM address is, None if it's not in the ROM or there is no RAM under it.
+    fn shadow_address(&self, physical: usize) -> Option<usize> {
+        let limit = self.rom_limit?;
+        (self.rom_control.is_some() && physical <= limit).then_some(self.shadow_start + physical)
+    }
+
+    /// Where the address the CPU uses is in the physical memory, the same for a flat memory.
+    /// While the ROM is unmapped, ROM addresses are in the RAM under it.
+    pub fn physical_address(&self, index: u8) -> usize {
+        let physical = self.bank_address(index);
+        match self.shadow_address(physical) {
+            Some(shadow) if !self.rom_mapped && self.region(index).is_none() => shadow,
+            _ => physical,
+        }
+    }
+
+    /// Where the address is in the banks, ignoring the RAM under the ROM.
+    fn bank_address(&self, index: u8) -> usize {
+        match self.banks {
+            Some(layout) if index >= layout.window_start() => {
+                let offset = (index - layout.window_start()) as usize;
+                let window = offset / layout.size as usize;
+                layout.bank_offset(self.selected_banks[window]) + offset % layout.size as usize
+            }
+            _ => index as usize,
         }
     }
 
     pub fn get(&self, index: u8) -> u8 {
-        return self.container[index as usize];
+        match self.region(index) {
+            Some(MemoryRegion { kind: RegionKind::Unmapped, open_bus, .. }) => open_bus.unwrap_or(OPEN_BUS),
+            Some(MemoryRegion { kind: RegionKind::Device, .. }) => 0,
+            _ => self.container[self.physical_address(index)],
+        }
     }
 
-    /// Returns true if: the mem write was successful (not in ROM).
-    pub fn set(&mut self, index: u8, value: u8) -> bool {
-        if self.rom_limit.is_none() {
-            self.container[index as usize] = value;
-            return true;
+    /// Like get, but it's a data read by the program, so it can trigger read watchpoints.
+    pub fn read(&mut self, index: u8) -> u8 {
+        let value = self.get(index);
+        if self.is_watched(index, WatchKind::Read) {
+            self.watch_hits.push(WatchHit { kind: WatchKind::Read, address: index, old_value: value, new_value: value });
         }
+        value
+    }
+
+    /// Returns true if: the mem write was successful (not in ROM or a used write-once byte).
+    /// Writes into unmapped and device regions are not rejected, they just get lost.
+    /// Writes into the ROM aren't rejected either if there is RAM under it, they go there.
+    pub fn set(&mut self, index: u8, value: u8) -> bool {
+        let Some(physical) = self.write_address(index) else { return true };
 
-        let limit = self.rom_limit.unwrap();
-        if index <= limit {
+        // The RAM under the ROM is past the ROM limit, so writes going there pass the check.
+        let writable = match self.region(index).map(|region| region.kind) {
+            Some(RegionKind::Rom) => false,
+            Some(RegionKind::WriteOnce) => self.written_once.insert(physical),
+            Some(_) => true,
+            None => !self.rom_limit.is_some_and(|limit| physical <= limit),
+        };
+        let old_value = self.container[physical];
+
+        if !writable {
+            if self.watch_rom_writes {
+                self.watch_hits.push(WatchHit { kind: WatchKind::RomWrite, address: index, old_value, new_value: value });
+            }
             return false;
         }
-        // index > rom limit
-        self.container[index as usize] = value;
+        // not ROM
+        self.container[physical] = value;
+
+        if self.is_watched(index, WatchKind::Write) {
+            self.watch_hits.push(WatchHit { kind: WatchKind::Write, address: index, old_value, new_value: value });
+        } else if old_value != value && self.is_watched(index, WatchKind::Change) {
+            self.watch_hits.push(WatchHit { kind: WatchKind::Change, address: index, old_value, new_value: value });
+        }
         return true;
     }
+
+    /// Where a write to the address goes in the physical memory, None if it gets lost (unmapped and device regions).
+    /// With RAM under the ROM, writes to ROM addresses go there, even while the ROM is mapped.
+    pub fn write_address(&self, index: u8) -> Option<usize> {
+        let physical = self.bank_address(index);
+        match self.region(index).map(|region| region.kind) {
+            Some(RegionKind::Unmapped | RegionKind::Device) => None,
+            Some(_) => Some(physical),
+            None => Some(self.shadow_address(physical).unwrap_or(physical)),
+        }
+    }
+
+    /// The byte a write to the address replaces, which isn't the one get reads if it goes to the RAM under the mapped ROM.
+    pub fn written_value(&self, index: u8) -> u8 {
+        self.write_address(index).map_or_else(|| self.get(index), |physical| self.container[physical])
+    }
+
+    /// Writes even into the ROM and without triggering watchpoints, used to undo writes.
+    /// Takes a physical address, see [`MemoryControl::physical_address`].
+    pub fn overwrite(&mut self, physical: usize, value: u8) {
+        self.container[physical] = value;
+    }
+
+    /// The contents (with the RAM under the ROM), the ROM limit, the selected banks, the used write-once bytes
+    /// and if the ROM is mapped, watchpoints and the memory map are not part of the state.
+    pub fn save_state(&self) -> MemoryState {
+        MemoryState {
+            contents: self.container.clone(),
+            rom_limit: self.rom_limit,
+            selected_banks: self.selected_banks.clone(),
+            written_once: self.written_once.iter().copied().collect(),
+            rom_mapped: self.rom_mapped,
+        }
+    }
+
+    /// Replaces the set of used write-once bytes, used to undo writes.
+    pub fn set_written_once(&mut self, written: &[usize]) {
+        self.written_once = written.iter().copied().collect();
+    }
+
+    pub fn load_state(&mut self, state: &MemoryState) -> Result<(), String> {
+        if state.contents.len() != self.container.len() {
+            return Err(format!("The memory state has {} bytes instead of {}", state.contents.len(), self.container.len()));
+        }
+        if state.selected_banks.len() != self.selected_banks.len() {
+            return Err(format!("The memory state has {} bank windows instead of {}", state.selected_banks.len(), self.selected_banks.len()));
+        }
+        if let Some(layout) = self.banks {
+            if let Some(bank) = state.selected_banks.iter().find(|bank| **bank >= layout.count) {
+                return Err(format!("The memory state selects bank {} but there are only {}", bank, layout.count));
+            }
+        }
+        if state.rom_limit != self.rom_limit {
+            let size = |limit: Option<usize>| limit.map_or(0, |limit| limit + 1);
+            return Err(format!("The memory state has a ROM of {} bytes instead of {}", size(state.rom_limit), size(self.rom_limit)));
+        }
+        if !state.rom_mapped && self.rom_control.is_none() {
+            return Err("The memory state has the ROM unmapped, but there is no ROM control register".to_string());
+        }
+
+        self.container.copy_from_slice(&state.contents);
+        self.selected_banks.clone_from(&state.selected_banks);
+        self.set_written_once(&state.written_once);
+        self.rom_mapped = state.rom_mapped;
+        Ok(())
+    }
+
+    /// A FNV-1a hash of the whole (physical) memory and the RAM under the ROM, for quickly comparing the state of two runs.
+    pub fn digest(&self) -> u64 {
+        self.container.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+            (hash ^ *byte as u64).wrapping_mul(0x0100_0000_01b3)
+        })
+    }
+
+    /// Puts a watchpoint on the address, adding the same one twice does nothing.
+    pub fn add_watchpoint(&mut self, address: u8, kind: WatchKind) {
+        if !self.is_watched(address, kind) {
+            self.watchpoints.push((address, kind));
+        }
+    }
+
+    /// Removes a watchpoint, returns false if there was none.
+    pub fn remove_watchpoint(&mut self, address: u8, kind: WatchKind) -> bool {
+        let len = self.watchpoints.len();
+        self.watchpoints.retain(|watchpoint| *watchpoint != (address, kind));
+        len != self.watchpoints.len()
+    }
+
+    /// Removes every watchpoint on the given address.
+    pub fn clear_watchpoints(&mut self, address: u8) {
+        self.watchpoints.retain(|(watched, _)| *watched != address);
+    }
+
+    pub fn watchpoints(&self) -> &[(u8, WatchKind)] {
+        &self.watchpoints
+    }
+
+    /// Enables the reporting of rejected writes into the ROM.
+    pub fn set_rom_write_watch(&mut self, enabled: bool) {
+        self.watch_rom_writes = enabled;
+    }
+
+    /// Returns and forgets the watchpoint hits since the last call.
+    pub fn take_watch_hits(&mut self) -> Vec<WatchHit> {
+        std::mem::take(&mut self.watch_hits)
+    }
+
+    fn is_watched(&self, address: u8, kind: WatchKind) -> bool {
+        self.watchpoints.contains(&(address, kind))
+    }
     
     /// Creates a Hexdump lookalike UI
     pub fn draw_hexdump(&self) -> String {
@@ -84,10 +580,7 @@ impl MemoryControl {
                     break;
                 }
 
-                let value = match self.container.get(index){
-                    Some(v) => *v,
-                    None => break,
-                };
+                let value = self.get(index as u8);
                 index += 1;
 
                 let hex = format!("{:02X}", value);
@@ -152,6 +645,135 @@ impl MemoryControl {
             CORNEL_DR
         ));
 
+        // The memory map and which bank every window shows, the dump is what the CPU sees.
+        for region in &self.regions {
+            out.push_str(&format!("\n  {:02X}..{:02X}: {}", region.start, region.end, region.describe()));
+        }
+        if let Some(layout) = self.banks {
+            for (window, bank) in self.selected_banks.iter().enumerate() {
+                let start = layout.window_start() as usize + window * layout.size as usize;
+                out.push_str(&format!("\n  {:02X}..{:02X}: bank {} of {}",
+                                      start, start + layout.size as usize - 1, bank, layout.count));
+            }
+        }
+        if let Some(io_address) = self.rom_control {
+            let state = if self.rom_mapped { "mapped" } else { "unmapped, the RAM under it shows" };
+            out.push_str(&format!("\n  ROM: {} (control register at IO {:02X})", state, io_address));
+        }
+
         out
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LAYOUT: BankLayout = BankLayout { size: 0x40, windows: 1, count: 4, select: 0x50 };
+
+    #[test]
+    fn the_window_shows_the_selected_bank() {
+        // The ROM covers the fixed window, bank 0 and the first byte of bank 1.
+        let mut rom = vec![0; LAYOUT.bank_offset(1) + 1];
+        rom[0xC0] = 0xB0;
+        rom[LAYOUT.bank_offset(1)] = 0xB1;
+        let mut memory = MemoryControl::banked(rom, LAYOUT).unwrap();
+        assert_eq!(memory.get(0xC0), 0xB0);
+
+        memory.select_bank(0, 1);
+        assert_eq!(memory.get(0xC0), 0xB1);
+        assert!(!memory.set(0xC0, 1));
+        assert!(memory.set(0xC1, 2));
+
+        // Past the last bank it wraps around.
+        memory.select_bank(0, 6);
+        assert_eq!(memory.selected_banks(), [2]);
+        assert_eq!(memory.get(0xC1), 0);
+        memory.select_bank(0, 1);
+        assert_eq!((memory.get(0xC1), memory.physical_address(0xC1)), (2, 0x101));
+    }
+
+    #[test]
+    fn the_bank_layout_is_checked() {
+        assert_eq!(LAYOUT.memory_size(), 0x1C0);
+        assert_eq!(LAYOUT.select_registers(), 0x50..0x51);
+        assert!(BankLayout { windows: 4, ..LAYOUT }.check().is_err());
+        assert!(BankLayout { count: 0, ..LAYOUT }.check().is_err());
+        assert!(MemoryControl::banked(vec![0; 0x1C1], LAYOUT).is_err());
+    }
+
+    fn region(kind: RegionKind, start: u8, end: u8) -> MemoryRegion {
+        MemoryRegion { kind, start, end, open_bus: None, io: None }
+    }
+
+    #[test]
+    fn regions_override_the_default_map() {
+        let mut memory = MemoryControl::new(vec![1, 2, 3, 4]);
+        memory.set_regions(vec![
+            region(RegionKind::Ram, 0x02, 0x03),
+            region(RegionKind::Rom, 0x10, 0x10),
+            region(RegionKind::WriteOnce, 0x20, 0x21),
+            MemoryRegion { open_bus: Some(0), ..region(RegionKind::Unmapped, 0x30, 0x3F) },
+            region(RegionKind::Unmapped, 0x40, 0x40),
+            MemoryRegion { io: Some(0x36), ..region(RegionKind::Device, 0xF0, 0xF3) },
+        ]).unwrap();
+
+        assert!(!memory.set(0x01, 9));
+        assert!(memory.set(0x02, 9));
+        assert_eq!(memory.get(0x02), 9);
+        assert!(!memory.set(0x10, 9));
+
+        assert!(memory.set(0x20, 5));
+        assert!(!memory.set(0x20, 6));
+        assert!(memory.set(0x21, 7));
+        assert_eq!((memory.get(0x20), memory.get(0x21)), (5, 7));
+
+        assert!(memory.set(0x30, 9));
+        assert_eq!((memory.get(0x30), memory.get(0x40)), (0x00, 0xFF));
+
+        assert_eq!(memory.device_address(0xF2), Some(0x38));
+        assert_eq!(memory.device_address(0x02), None);
+    }
+
+    #[test]
+    fn invalid_regions_are_rejected() {
+        let mut memory = MemoryControl::new(Vec::new());
+        assert!(memory.set_regions(vec![region(RegionKind::Ram, 0x10, 0x20), region(RegionKind::Rom, 0x20, 0x30)]).is_err());
+        assert!(memory.set_regions(vec![region(RegionKind::Ram, 0x20, 0x10)]).is_err());
+        assert!(memory.set_regions(vec![region(RegionKind::Device, 0x10, 0x20)]).is_err());
+        assert!(memory.set_regions(vec![MemoryRegion { open_bus: Some(0), ..region(RegionKind::Ram, 0x10, 0x20) }]).is_err());
+        assert!(memory.regions().is_empty());
+    }
+
+    #[test]
+    fn rom_writes_go_to_the_ram_under_it() {
+        let mut memory = MemoryControl::new(vec![0xAA, 0xBB]);
+        assert!(!memory.set(0x00, 1));
+
+        memory.enable_rom_control(0x5F);
+        assert_eq!(memory.written_value(0x00), 0);
+        assert!(memory.set(0x00, 1));
+        assert_eq!((memory.get(0x00), memory.written_value(0x00)), (0xAA, 1));
+
+        memory.map_rom(false);
+        assert_eq!((memory.get(0x00), memory.get(0x01)), (1, 0));
+        memory.map_rom(true);
+        assert_eq!(memory.get(0x00), 0xAA);
+    }
+
+    #[test]
+    fn memory_states_need_the_same_rom() {
+        let mut memory = MemoryControl::new(vec![0xAA, 0xBB]);
+        let mut state = memory.save_state();
+        state.rom_limit = Some(0);
+        assert!(memory.load_state(&state).unwrap_err().contains("ROM of 1 bytes instead of 2"));
+
+        // Unmapping needs the ROM control register.
+        let state = MemoryState { rom_mapped: false, ..memory.save_state() };
+        assert!(memory.load_state(&state).is_err());
+        memory.enable_rom_control(0x5F);
+        let state = MemoryState { rom_mapped: false, ..memory.save_state() };
+        memory.load_state(&state).unwrap();
+        assert!(!memory.rom_mapped());
+    }
+}
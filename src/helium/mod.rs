@@ -3,4 +3,8 @@ pub mod memory;
 pub mod io_controller;
 
 /// Gives Imports the CPU and the IOContoler.
-pub mod prelude;
\ No newline at end of file
+pub mod prelude;
+/// The instruction set, shared by the CPU and the tooling (assembler, disassembler).
+pub mod isa;
+/// What the CPU does when a program misbehaves.
+pub mod faults;
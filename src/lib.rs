@@ -0,0 +1,50 @@
+#![feature(bigint_helper_methods)]
+#![feature(ascii_char)]
+
+//! The Helium emulator as a library, for embedding the VM into test harnesses and tools.
+//!
+//! The quickest way in is the [`Machine`] builder:
+//! ```no_run
+//! use helium_vm::Machine;
+//! use helium_vm::devices::stdout_ascii_buffer::CharIOBuffer;
+//!
+//! let rom = helium_vm::load_rom("hello.bin".as_ref()).unwrap();
+//! let mut machine = Machine::builder(rom)
+//!     .device(0..50, CharIOBuffer::new())
+//!     .build()
+//!     .unwrap();
+//!
+//! machine.run(10_000);
+//! println!("r0 = {}", machine.cpu.register(0));
+//! ```
+
+/// Contains the "Core" of Helium, the CPU, IO-CTL and the memory.
+pub mod helium;
+/// Holds all devices and the Device Trait.
+pub mod devices;
+/// Some utility stuff
+pub mod utils;
+/// Tooling for writing programs, like the assembler.
+pub mod tools;
+/// Wires a ROM and devices together into a running CPU.
+pub mod machine;
+/// The machine config file and the devices it can mount.
+pub mod config;
+/// Saving and restoring the whole machine state.
+pub mod snapshot;
+/// Recording and replaying the input devices get from outside the machine.
+pub mod replay;
+/// Paces the CPU in real time.
+pub mod scheduler;
+/// The boot ROM which loads a program from the disk.
+pub mod boot;
+
+pub use crate::helium::cpu::{Flag, CPU};
+pub use crate::helium::memory::{BankLayout, MemoryControl, MemoryRegion, RegionKind};
+pub use crate::helium::faults::{Fault, FaultAction, FaultKind, FaultPolicy};
+pub use crate::helium::io_controller::IOController;
+pub use crate::devices::device::Device;
+pub use crate::machine::{load_banked_rom, load_rom, Machine, MachineBuilder};
+pub use crate::config::{DeviceConfig, DeviceType, MachineConfig};
+pub use crate::snapshot::Snapshot;
+pub use crate::replay::{InputRecorder, InputReplay};
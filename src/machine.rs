@@ -0,0 +1,209 @@
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::ops::Range;
+use std::path::Path;
+use crate::devices::device::Device;
+use crate::helium::faults::FaultPolicy;
+use crate::helium::isa::Extensions;
+use crate::helium::memory::{BankLayout, MemoryControl, MemoryRegion};
+use crate::helium::prelude::*;
+use crate::tools::tracer::Tracer;
+
+/// Collects the ROM and the devices of a machine, see [`Machine::builder`].
+#[derive(Debug)]
+pub struct MachineBuilder {
+    rom: Vec<u8>,
+    io_ctl: IOController,
+    /// The first device which couldn't be mounted, reported by build.
+    error: Option<String>,
+    tracer: Option<Tracer>,
+    extensions: Extensions,
+    banks: Option<BankLayout>,
+    regions: Vec<MemoryRegion>,
+    rom_control: Option<u8>,
+    fault_policy: FaultPolicy,
+}
+
+impl MachineBuilder {
+    /// Mounts a device on the given IO address range, if it doesn't fit build will fail.
+    pub fn device<D>(mut self, address: Range<u8>, device: D) -> Self
+    where D: Device + 'static {
+        if self.error.is_none() {
+            self.error = self.io_ctl.mount_device(address, device).err();
+        }
+        self
+    }
+
+    /// Uses an already set up IO controller instead of the empty one.
+    /// Devices mounted before this are dropped.
+    pub fn io_controller(mut self, io_ctl: IOController) -> Self {
+        self.io_ctl = io_ctl;
+        self
+    }
+
+    /// Records every executed instruction.
+    pub fn tracer(mut self, tracer: Tracer) -> Self {
+        self.tracer = Some(tracer);
+        self
+    }
+
+    /// Enables ISA extensions, the base ISA is used by default.
+    pub fn extensions(mut self, extensions: Extensions) -> Self {
+        self.extensions = extensions;
+        self
+    }
+
+    /// Uses a banked memory, the ROM can then be a multi-bank image.
+    pub fn banks(mut self, layout: BankLayout) -> Self {
+        self.banks = Some(layout);
+        self
+    }
+
+    /// Sets the memory map, see [`MemoryRegion`].
+    pub fn regions(mut self, regions: Vec<MemoryRegion>) -> Self {
+        self.regions = regions;
+        self
+    }
+
+    /// Puts the ROM control register on the IO address, so the program can unmap the ROM,
+    /// see [`MemoryControl::enable_rom_control`].
+    pub fn rom_control(mut self, address: u8) -> Self {
+        self.rom_control = Some(address);
+        self
+    }
+
+    /// Decides what happens on faults, see [`FaultPolicy`].
+    pub fn fault_policy(mut self, policy: FaultPolicy) -> Self {
+        self.fault_policy = policy;
+        self
+    }
+
+    /// Creates the CPU and powers it on, fails if a device couldn't be mounted
+    /// or the ROM doesn't fit into the memory, or the memory map or the fault policy is invalid,
+    /// or the ROM control register is on a used IO address.
+    pub fn build(self) -> Result<Machine, String> {
+        if let Some(error) = self.error {
+            return Err(error);
+        }
+
+        let mut memory = match self.banks {
+            Some(layout) => {
+                self.io_ctl.check_free(&layout.select_registers(), "Bank select")?;
+                MemoryControl::banked(self.rom, layout)?
+            }
+            None if self.rom.len() > 256 => {
+                return Err(format!("The rom image has {} bytes, more than 256 need banks", self.rom.len()));
+            }
+            None => MemoryControl::new(self.rom),
+        };
+        memory.set_regions(self.regions)?;
+        if let Some(address) = self.rom_control {
+            let bank_select = self.banks.is_some_and(|layout| layout.select_registers().contains(&address));
+            if bank_select || self.io_ctl.is_mapped(address) {
+                return Err(format!("The ROM control register at {:02X} overlaps a device or a bank select register", address));
+            }
+            memory.enable_rom_control(address);
+        }
+        self.fault_policy.check()?;
+
+        let mut cpu = CPU::with_memory(self.io_ctl, memory);
+        cpu.set_extensions(self.extensions);
+        cpu.set_fault_policy(self.fault_policy);
+        cpu.start();
+
+        Ok(Machine { cpu, steps: 0, tracer: self.tracer })
+    }
+}
+
+/// A powered on CPU with its memory and devices, stepping it doesn't draw any UI.
+#[derive(Debug)]
+pub struct Machine {
+    pub cpu: CPU,
+    /// How many instructions were executed since the build.
+    pub steps: u64,
+    /// Take it out and finish it once done, to see if the trace was written.
+    pub tracer: Option<Tracer>,
+}
+
+impl Machine {
+    /// Starts building a machine around the given ROM image, without any devices or interrupt logging.
+    pub fn builder(rom: Vec<u8>) -> MachineBuilder {
+        MachineBuilder {
+            rom,
+            io_ctl: IOController::new(false),
+            error: None,
+            tracer: None,
+            extensions: Extensions::default(),
+            banks: None,
+            regions: Vec::new(),
+            rom_control: None,
+            fault_policy: FaultPolicy::default(),
+        }
+    }
+
+    /// Executes a single instruction, does nothing once the CPU halted.
+    pub fn step(&mut self) {
+        if self.cpu.is_on {
+            if let Some(tracer) = self.tracer.as_mut() {
+                tracer.before_step(&mut self.cpu);
+            }
+
+            self.cpu.next();
+            self.steps += 1;
+
+            if let Some(tracer) = self.tracer.as_mut() {
+                tracer.after_step(&mut self.cpu);
+            }
+        }
+    }
+
+    /// Runs until the CPU halts or `max_steps` instructions were executed, returns true if it halted.
+    pub fn run(&mut self, max_steps: u64) -> bool {
+        for _ in 0..max_steps {
+            if !self.cpu.is_on {
+                break;
+            }
+            self.step();
+        }
+        !self.cpu.is_on
+    }
+
+    /// Finds a mounted device by its type, see [`IOController::find_device`].
+    pub fn find_device<T: Device + 'static>(&self) -> Option<&T> {
+        self.cpu.io_ctl.find_device::<T>()
+    }
+}
+
+/// Takes a Path to a file which will be loaded into a 256 long vec, returns error messages if something goes wrong. 
+pub fn load_rom(path: &Path) -> Result<Vec<u8>, String> {
+    read_rom(path, 256)
+}
+
+/// Like load_rom, but the file can be as big as the physical memory of the bank layout.
+pub fn load_banked_rom(path: &Path, layout: &BankLayout) -> Result<Vec<u8>, String> {
+    layout.check()?;
+    read_rom(path, layout.memory_size())
+}
+
+fn read_rom(path: &Path, limit: usize) -> Result<Vec<u8>, String> {
+    let rom_file = File::open(path)
+        .map_err(|e| format!("Could not open rom-file: {}", e))?;
+
+    let rom_meta = rom_file.metadata()
+        .map_err(|e| format!("Failed to read the metadata of the rom-file: {}", e))?;
+
+    let size = rom_meta.len();
+    if size > limit as u64 {
+        return Err(format!("Rom file exceeds the {} byte limit ({})", limit, size));
+    }
+
+    let reader = BufReader::new(rom_file);
+    let mut rom: Vec<u8> = Vec::with_capacity(size as usize);
+
+    for byte in reader.bytes() {
+        rom.push(byte
+            .map_err(|e| format!("Failed to read byte: {}", e))?
+        );
+    }
+    Ok(rom)
+}
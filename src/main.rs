@@ -1,39 +1,87 @@
-#![feature(bigint_helper_methods)]
-#![feature(ascii_char)]
-
-use clap::{Parser, ValueEnum};
+use clap::{Args, Parser, Subcommand, ValueEnum};
 use std::path::{Path, PathBuf};
-use std::fs::File;
-use std::io::{BufReader, Read};
+use std::fs;
+use std::io::{stdin, IsTerminal};
 use std::process::exit;
+use std::thread;
 use std::time::{Duration, Instant};
 use ansi_escapes::{ClearScreen, CursorHide, CursorShow};
-use crate::devices::stdout_ascii_buffer::CharIOBuffer;
-use crate::devices::telnet_terminal::TelnetTerminal;
-
-use crate::helium::prelude::*;
+use crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use crossterm::terminal;
 
-/// Contains the "Core" of Helium, the CPU, IO-CTL and the memory.
-pub mod helium;
-/// Holds all devices and the Device Trait.
-pub mod devices;
-/// Some utility stuff
-pub mod utils;
+use helium_vm::helium::prelude::*;
+use helium_vm::helium::faults::{FaultAction, FaultKind, FaultPolicy};
+use helium_vm::helium::isa::Extensions;
+use helium_vm::{load_banked_rom, load_rom, DeviceType, Flag, InputRecorder, InputReplay, Machine, MachineBuilder, MachineConfig, Snapshot};
+use helium_vm::scheduler::{check_rate, Scheduler, Speed};
+use helium_vm::boot;
+use helium_vm::tools::assembler::Assembler;
+use helium_vm::tools::disassembler;
+use helium_vm::tools::debugger::{self, Debugger, Supervisor};
+use helium_vm::tools::gdb_stub::GdbStub;
+use helium_vm::tools::test_runner::TestSpec;
+use helium_vm::tools::tracer::{TraceFormat, Tracer};
+use helium_vm::tools::tui::Tui;
+use owo_colors::OwoColorize;
+use serde::Serialize;
 
 /// Holds all command line arguments.
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    run: RunArgs,
+}
+
+/// The arguments for running a rom, these work without the run subcommand too.
+#[derive(Args)]
+struct RunArgs {
     /// Path to the file containing the rom image, the file must be less than 255 bytes
-    #[arg(value_name = "ROM file")]
-    rom_file: PathBuf,
+    #[arg(value_name = "ROM file", required_unless_present_any = ["machine", "boot"])]
+    rom_file: Option<PathBuf>,
+
+    /// Boots from the disk with the built-in boot ROM instead of a rom file, needs the disk device
+    #[arg(long, conflicts_with = "rom_file")]
+    boot: bool,
 
-    /// Defines how many instructions the CPU should complete every second.
-    #[arg(short, long, value_name = "Step rate(f)", default_value = "100")]
-    step_rate: f32,
+    /// A TOML file describing the machine: the rom, the step rate and the devices with their IO ranges
+    #[arg(short, long, value_name = "Machine config")]
+    machine: Option<PathBuf>,
+
+    /// Defines how many instructions the CPU should complete every second. [default: 100]
+    #[arg(short, long, value_name = "Step rate(f)", value_parser = parse_rate)]
+    step_rate: Option<f32>,
+
+    /// Paces the CPU by clock cycles instead of instructions, instructions with an imm8 or a data access take longer
+    #[arg(long, value_name = "Hz", conflicts_with = "step_rate", value_parser = parse_rate)]
+    clock_hz: Option<f32>,
+
+    /// How many times per second the UI gets redrawn, independent of the step rate
+    #[arg(long, value_name = "FPS", default_value = "30", value_parser = parse_rate)]
+    ui_rate: f32,
+
+    /// Enables the stack extension of the ISA: an SP register with PUSH, POP, CALL, RET and XSP
+    #[arg(long)]
+    stack: bool,
+
+    /// Lets interrupts interrupt each other up to the given depth, the return address, the flags and IE get saved on entry
+    #[arg(long, value_name = "Depth", value_parser = clap::value_parser!(u8).range(1..))]
+    nested_interrupts: Option<u8>,
+
+    /// What the CPU does on a kind of fault, like `rom-write=halt` (ignore, log, halt or exception), can be repeated
+    #[arg(long = "fault", value_name = "Kind=Action", value_parser = parse_fault)]
+    faults: Vec<(FaultKind, FaultAction)>,
+
+    /// Where fault exceptions jump to, the cause code can be read with GIC
+    #[arg(long, value_name = "Address", value_parser = parse_address)]
+    fault_vector: Option<u8>,
 
     /// What Devices to Link
-    #[clap(short, long, value_enum)]
+    #[clap(short, long, value_enum, conflicts_with = "machine")]
     #[arg(default_value = "term-link")]
     devices: Vec<DeviceType>,
 
@@ -45,127 +93,691 @@ struct Cli {
     #[arg(long, default_value = "false")]
     no_gui: bool,
 
-    /// Enables debug controls
+    /// Enables debug controls, the CPU starts paused in the interactive debugger
     #[arg(long)]
     debug: bool,
 
+    /// Uses a full-screen terminal UI with panes for the registers, disassembly, memory, breakpoints and devices
+    #[arg(long, conflicts_with_all = ["debug", "gdb", "no_gui"])]
+    tui: bool,
+
+    /// Sets a debugger breakpoint on the given address (hex with 0x), implies --debug (unless --tui is used)
+    #[arg(short, long = "breakpoint", value_name = "Address", value_parser = parse_address)]
+    breakpoints: Vec<u8>,
+
+    /// How many instructions the debugger remembers for stepping back
+    #[arg(long, value_name = "Steps", default_value = "10000")]
+    history: usize,
+
+    /// Waits for a GDB connection on 127.0.0.1:<port> and lets it control the CPU (remote serial protocol)
+    #[arg(long, value_name = "GDB Port", conflicts_with_all = ["debug", "breakpoints"])]
+    gdb: Option<u16>,
+
     /// The Port for the TermLink server hosted on 127.0.0.1:??? (only when TermLink is enabled tho)
-    #[arg(short, long, value_name = "Terminal Port", default_value = "5555")]
-    port: u16
+    #[arg(short, long, value_name = "Terminal Port", default_value = "5555", conflicts_with = "machine")]
+    port: u16,
+
+    /// The disk image file of the disk device (only when the disk is enabled), it gets created if it's missing
+    #[arg(long, value_name = "Image file", conflicts_with = "machine")]
+    disk_image: Option<PathBuf>,
+
+    /// Starts from a snapshot instead of the reset state, the machine needs the same devices as the snapshot
+    #[arg(long, value_name = "Snapshot file")]
+    restore: Option<PathBuf>,
+
+    /// Writes a record of every executed instruction into the file
+    #[arg(long, value_name = "Trace file")]
+    trace: Option<PathBuf>,
+
+    /// The format of the --trace file
+    #[arg(long, value_enum, default_value = "jsonl", requires = "trace")]
+    trace_format: TraceFormat,
+
+    /// Records everything the devices get from the outside world (like TermLink input) into a replay file
+    #[arg(long, value_name = "Replay file")]
+    record: Option<PathBuf>,
+
+    /// Feeds the devices the input of a recording at the same steps, the TermLink doesn't open a port then
+    #[arg(long, value_name = "Replay file", conflicts_with = "record")]
+    replay: Option<PathBuf>,
 }
 
-/// This enum holds all available devices for use
-#[derive(ValueEnum, Copy, Clone, Debug, PartialOrd, PartialEq)]
-enum DeviceType {
-    TermLink,
-    CharBuffer,
+/// Tools which run instead of the emulator.
+#[derive(Subcommand)]
+enum Command {
+    /// Runs a rom image, same as running without a subcommand but it can also run headless.
+    Run {
+        #[command(flatten)]
+        args: Box<RunArgs>,
+
+        /// Runs as fast as possible without any UI and prints the final state as JSON.
+        /// Exits with 0 on HALT, 3 when the step limit is hit, 4 when a fault halted the CPU and -1 on errors (2 is taken by clap).
+        #[arg(long, conflicts_with_all = ["debug", "breakpoints", "gdb"])]
+        headless: bool,
+
+        /// Stops a headless run after this many instructions.
+        #[arg(long, value_name = "Steps", requires = "headless")]
+        max_steps: Option<u64>,
+
+        /// Saves a snapshot of the machine at the end of a headless run.
+        #[arg(long, value_name = "Snapshot file", requires = "headless")]
+        save_snapshot: Option<PathBuf>,
+    },
+
+    /// Assembles a source file into a rom image.
+    Asm {
+        /// Path to the assembly source.
+        #[arg(value_name = "Source file")]
+        source: PathBuf,
+
+        /// Where to write the rom image, defaults to the source path with a .bin extension.
+        #[arg(short, long, value_name = "Output file")]
+        output: Option<PathBuf>,
+
+        /// Assembles for the stack extension, the instructions it takes the encodings of (like OR rX, rX) are rejected.
+        #[arg(long)]
+        stack: bool,
+    },
+
+    /// Prints the address, the raw bytes and the mnemonic of every instruction in a rom image.
+    Disasm {
+        /// Path to the rom image.
+        #[arg(value_name = "ROM file")]
+        rom_file: PathBuf,
+
+        /// Decodes the instructions of the stack extension.
+        #[arg(long)]
+        stack: bool,
+
+        /// Takes the bank layout and the extensions from a machine config, so multi-bank images can be disassembled
+        #[arg(short, long, value_name = "Machine config")]
+        machine: Option<PathBuf>,
+    },
+
+    /// Runs the rom tests described by the given spec files.
+    Test {
+        /// Paths to the TOML test specs.
+        #[arg(value_name = "Spec file", required = true)]
+        specs: Vec<PathBuf>,
+    },
 }
 
-impl DeviceType {
-    /// Used for mounting the device automatically, each device has their own impl of this and this.
-    /// makes mounting stuff easy by just iterating over a list of these and calling mount.
-    pub fn mount(&self, cli: &Cli, mounter: &mut IOController) {
-        match self {
-            DeviceType::TermLink => {
-                let tt = TelnetTerminal::new(1, cli.port);
-                mounter.mount_device(51..54, tt);
+fn main() {
+    let config = Cli::parse();
+
+    if let Some(command) = &config.command {
+        let result = match command {
+            Command::Run { args, headless: true, max_steps, save_snapshot } => {
+                run_headless(args, *max_steps, save_snapshot.as_deref())
             }
-            
-            DeviceType::CharBuffer => {
-                let ch_io_buffer = CharIOBuffer::new();
-                mounter.mount_device(0..51, ch_io_buffer);
+            Command::Run { args, .. } => {
+                run(args);
+                Ok(())
             }
+            Command::Asm { source, output, stack } => assemble(source, output.as_deref(), *stack),
+            Command::Disasm { rom_file, stack, machine } => disassemble(rom_file, *stack, machine.as_deref()),
+            Command::Test { specs } => run_tests(specs),
+        };
+
+        if let Err(msg) = result {
+            eprintln!("{}", msg);
+            exit(-1)
         }
+        return;
     }
+
+    run(&config.run);
 }
 
-fn main() {
-    let config = Cli::parse();
+/// Loads the rom and mounts the devices, either from the machine config or the command line.
+/// Returns the machine, ready to be built, and the speed.
+fn setup(config: &RunArgs) -> Result<(MachineBuilder, Speed), String> {
+    let machine = config.machine.as_deref()
+        .map(MachineConfig::load)
+        .transpose()?;
+
+    let banks = machine.as_ref().and_then(|machine| machine.banks);
+    let boot = config.boot || machine.as_ref().is_some_and(|machine| machine.boot);
+    let rom = if boot {
+        boot::rom()
+    } else {
+        let rom_file = config.rom_file.clone()
+            .or_else(|| machine.as_ref().and_then(|machine| machine.rom.clone()))
+            .ok_or("No rom file given, neither on the command line nor in the machine config")?;
+        match &banks {
+            Some(layout) => load_banked_rom(&rom_file, layout)?,
+            None => load_rom(&rom_file)?,
+        }
+    };
+
+    // The boot ROM always uses the same register, the config can't move it (see MachineConfig::load).
+    let rom_control = if boot {
+        Some(boot::ROM_CONTROL)
+    } else {
+        machine.as_ref().and_then(|machine| machine.rom_control)
+    };
+
+    // The command line wins over the machine config.
+    let speed = match (config.step_rate, config.clock_hz, &machine) {
+        (Some(rate), _, _) => Speed::StepRate(rate),
+        (_, Some(hz), _) => Speed::ClockHz(hz),
+        (_, _, Some(MachineConfig { clock_hz: Some(hz), .. })) => Speed::ClockHz(*hz),
+        (_, _, Some(MachineConfig { step_rate: Some(rate), .. })) => Speed::StepRate(*rate),
+        _ => Speed::StepRate(100.0),
+    };
+
+    let extensions = Extensions {
+        stack: config.stack || machine.as_ref().is_some_and(|machine| machine.stack),
+        interrupt_nesting: config.nested_interrupts.or(machine.as_ref().and_then(|machine| machine.nested_interrupts)),
+    };
+
+    let mut fault_policy = machine.as_ref().map_or(FaultPolicy::default(), |machine| machine.faults);
+    for (kind, action) in &config.faults {
+        fault_policy.set_action(*kind, *action);
+    }
+    if config.fault_vector.is_some() {
+        fault_policy.vector = config.fault_vector;
+    }
+
+    let mut device_mounter = IOController::new(config.interrupt_logging);
+
+    let devices = match &machine {
+        Some(machine) => machine.devices.clone(),
+        None => {
+            // Load devices dynamically.
+            config.devices.iter().map(|device_type| {
+                let mut device = device_type.default_config();
+                if *device_type == DeviceType::TermLink {
+                    device.port = Some(config.port);
+                }
+                if *device_type == DeviceType::Disk {
+                    device.image = config.disk_image.clone();
+                }
+                device
+            }).collect()
+        }
+    };
+
+    for device in &devices {
+        if config.replay.is_some() {
+            device.mount_replayed(&mut device_mounter)?;
+        } else {
+            device.mount(&mut device_mounter)?;
+        }
+    }
+
+    if let Some(path) = &config.record {
+        device_mounter.record_input(InputRecorder::create(path)?);
+    }
+    if let Some(path) = &config.replay {
+        device_mounter.replay_input(InputReplay::load(path)?)?;
+    }
+
+    let mut builder = Machine::builder(rom)
+        .io_controller(device_mounter)
+        .extensions(extensions)
+        .fault_policy(fault_policy);
+    if let Some(layout) = banks {
+        builder = builder.banks(layout);
+    }
+    if let Some(address) = rom_control {
+        builder = builder.rom_control(address);
+    }
+    if let Some(machine) = machine {
+        builder = builder.regions(machine.regions);
+    }
+    Ok((builder, speed))
+}
 
-    let rom = load_rom(&config.rom_file)
+/// Runs the emulator with the UI and the optional debugger.
+fn run(config: &RunArgs) {
+    let (builder, speed) = setup(config)
         .map_err(|msg|{
         eprintln!("{}", msg);
         exit(-1)
     }).unwrap();
 
-    let micros_per_iter = 1_000_000f64 / config.step_rate as f64;
-    let per_iter_duration = Duration::from_micros(micros_per_iter.round() as u64);
+    let mut cpu = builder.build()
+        .map_err(|msg|{
+            eprintln!("{}", msg);
+            exit(-1)
+        }).unwrap().cpu;
+    if let Some(path) = &config.restore {
+        Snapshot::load(path)
+            .and_then(|snapshot| snapshot.restore(&mut cpu))
+            .map_err(|msg| {
+                eprintln!("{}", msg);
+                exit(-1)
+            }).unwrap();
+    }
+
+    // With the TUI the breakpoints are its own.
+    let debug = !config.tui && (config.debug || !config.breakpoints.is_empty());
+    let mut supervisor: Option<Box<dyn Supervisor>> = None;
 
-    #[allow(unused_mut)]
-    let mut device_mounter = IOController::new(config.interrupt_logging);
-    
-    // Load devices dynamically.
-    for device_type in config.devices.clone() {
-        device_type.mount(&config, &mut device_mounter)
+    if debug {
+        // The CPU ignores writes into the ROM, while debugging they are worth stopping for.
+        cpu.memory.set_rom_write_watch(true);
+        cpu.set_stack_checks(true);
+        supervisor = Some(Box::new(Debugger::new(&config.breakpoints, config.history)));
     }
+    if let Some(port) = config.gdb {
+        let stub = GdbStub::listen(port)
+            .map_err(|msg| {
+                eprintln!("{}", msg);
+                exit(-1)
+            }).unwrap();
+        supervisor = Some(Box::new(stub));
+    }
+
+    let mut tracer = config.trace.as_deref()
+        .map(|path| Tracer::create(path, config.trace_format))
+        .transpose()
+        .map_err(|msg| {
+            eprintln!("{}", msg);
+            exit(-1)
+        }).unwrap();
+
+    let mut tui = config.tui.then(|| Tui::enter(&config.breakpoints))
+        .transpose()
+        .map_err(|msg| {
+            eprintln!("{}", msg);
+            exit(-1)
+        }).unwrap();
 
-    let mut cpu = CPU::new(device_mounter, rom);
+    // The debugger and GDB read stdin themselves and want to see every step.
+    let draw_every_step = supervisor.is_some();
+    let controls = match (&supervisor, &tui) {
+        (None, None) if stdin().is_terminal() => KeyboardControls::enable(),
+        _ => None,
+    };
+    let raw = controls.is_some();
 
-    cpu.start();
-    if !config.no_gui {
-        update_state_ui(&cpu);
+    let mut scheduler = Scheduler::new(speed);
+    let redraw_interval = Duration::from_secs_f64(1.0 / config.ui_rate as f64);
+    let mut next_redraw = Instant::now();
+    let mut quit = false;
+
+    if tui.is_none() {
+        draw_ui(&mut cpu, config.no_gui, debug, None, raw);
     }
 
-    let mut start = Instant::now();
-    let mut elapsed = per_iter_duration;
+    while cpu.is_on && !quit {
+        // Run the instructions which are due, but don't let the UI starve in turbo.
+        let batch_end = Instant::now() + redraw_interval;
+        loop {
+            let now = Instant::now();
+            if !cpu.is_on || !scheduler.is_due(now) || now >= batch_end {
+                break;
+            }
 
-    while cpu.is_on {
-        // If enough time has passed, run it again.
-        if elapsed >= per_iter_duration {
+            if let Some(tui) = tui.as_mut() {
+                if tui.breakpoint_hit(&cpu) {
+                    scheduler.pause();
+                    break;
+                }
+            }
+
+            if let Some(supervisor) = supervisor.as_mut() {
+                supervisor.before_step(&mut cpu);
+                if !cpu.is_on {
+                    break;
+                }
+            }
+
+            if let Some(tracer) = tracer.as_mut() {
+                tracer.before_step(&mut cpu);
+            }
+
+            let cycles = cpu.cycles();
             cpu.next();
+            scheduler.stepped(cpu.cycles() - cycles);
 
-            print!("{}", CursorHide);
-            
-            if !config.no_gui {
-                update_state_ui(&cpu);
-                println!(); // Separation from the UI
+            if let Some(supervisor) = supervisor.as_mut() {
+                supervisor.after_step(&mut cpu);
+            }
+            if let Some(tracer) = tracer.as_mut() {
+                tracer.after_step(&mut cpu);
             }
-            
-            print!("{}", cpu.io_ctl.draw_ui(config.no_gui, config.debug));
 
-            start = Instant::now();
+            // The debugger reports the faults itself.
+            match tui.as_mut() {
+                Some(tui) => tui.report_faults(&mut cpu),
+                None if !debug => {
+                    for fault in cpu.take_faults() {
+                        eprintln!("{} {}", "Fault:".bright_red(), fault);
+                    }
+                }
+                None => {}
+            }
+
+            if draw_every_step {
+                draw_ui(&mut cpu, config.no_gui, debug, None, raw);
+            }
+        }
+
+        if !draw_every_step && Instant::now() >= next_redraw {
+            match tui.as_mut() {
+                Some(tui) => tui.draw(&mut cpu, &scheduler.status()),
+                None => {
+                    let status = controls.as_ref().map(|_| scheduler.status());
+                    draw_ui(&mut cpu, config.no_gui, debug, status.as_deref(), raw);
+                }
+            }
+            next_redraw = Instant::now() + redraw_interval;
+        }
+
+        if !cpu.is_on {
+            if let Some(supervisor) = supervisor.as_mut() {
+                supervisor.after_halt(&mut cpu);
+            }
+            continue;
+        }
+
+        // Sleep until the next instruction or redraw, key presses wake us up.
+        let wake_up = match scheduler.next_due() {
+            Some(due) if draw_every_step => due,
+            Some(due) => due.min(next_redraw),
+            None => next_redraw,
+        };
+        let timeout = wake_up.saturating_duration_since(Instant::now());
+
+        match (tui.as_mut(), &controls) {
+            (Some(tui), _) => quit = tui.handle_keys(&cpu, &mut scheduler, timeout),
+            (None, Some(controls)) => quit = controls.handle_keys(&mut scheduler, timeout),
+            (None, None) => thread::sleep(timeout),
         }
-        // Update elapsed
-        elapsed = start.elapsed();
     }
-    
+
+    if let Some(mut tui) = tui {
+        // Stays open after a halt, so the final state can be looked at.
+        while !quit {
+            tui.draw(&mut cpu, &scheduler.status());
+            quit = tui.handle_keys(&cpu, &mut scheduler, redraw_interval);
+        }
+        tui.leave()
+            .map_err(|msg| {
+                eprintln!("{}", msg);
+                exit(-1)
+            }).unwrap();
+    } else if !draw_every_step {
+        let status = controls.as_ref().map(|_| scheduler.status());
+        draw_ui(&mut cpu, config.no_gui, debug, status.as_deref(), raw);
+    }
+    drop(controls);
+
     // end of execution
     
     print!("{}", CursorShow);
+    if let Some(fault) = cpu.halt_fault() {
+        eprintln!("{} {}", "Halted by a fault:".bright_red(), fault);
+    }
+
+    if let Err(msg) = tracer.map_or(Ok(()), Tracer::finish).and(cpu.io_ctl.finish_input()) {
+        eprintln!("{}", msg);
+        exit(-1)
+    }
+}
+
+/// Draws the UI for the CPU and the memory (which clears the screen), the scheduler status and the devices.
+/// In raw mode the lines need a carriage return too.
+fn draw_ui(cpu: &mut CPU, no_gui: bool, debug: bool, status: Option<&str>, raw: bool) {
+    let mut out = String::new();
+
+    if !no_gui {
+        out.push_str(&format!("{}{}\n{}\n", ClearScreen, cpu.generate_state_ui(), cpu.memory.draw_hexdump()));
+
+        if let Some(status) = status {
+            out.push_str(&format!("{} | {}\n", status, KeyboardControls::HELP));
+        }
+        out.push('\n'); // Separation from the UI
+    }
+    out.push_str(&cpu.io_ctl.draw_ui(no_gui, debug));
+
+    if raw {
+        out = out.replace('\n', "\r\n");
+    }
+    print!("{}{}", CursorHide, out);
 }
 
-/// Draws the UI for the CPU and the memory, also clears the screen.
-fn update_state_ui(cpu: &CPU) {
-    let cpu_state_ui = cpu.generate_state_ui();
-    let memory_state_ui = cpu.memory.draw_hexdump();
+/// Keyboard shortcuts for the scheduler, they need the terminal in raw mode, which is restored on drop.
+struct KeyboardControls;
 
-    let out = format!("{}\n{}", cpu_state_ui, memory_state_ui);
-    // let line_count = out.lines().count();
+impl KeyboardControls {
+    const HELP: &'static str = "[space] pause/resume  [t] turbo  [+/-] speed  [q] quit";
+
+    fn enable() -> Option<Self> {
+        terminal::enable_raw_mode().ok().map(|_| Self)
+    }
+
+    /// Waits up to the timeout for key presses and applies them, returns true if the user wants to quit.
+    fn handle_keys(&self, scheduler: &mut Scheduler, timeout: Duration) -> bool {
+        let mut timeout = timeout;
+
+        while event::poll(timeout).unwrap_or(false) {
+            timeout = Duration::ZERO;
+            let Ok(Event::Key(key)) = event::read() else { continue };
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
 
-    println!("{}{}",ClearScreen, out);
+            match key.code {
+                KeyCode::Char(' ') => scheduler.toggle_pause(),
+                KeyCode::Char('t') => scheduler.toggle_turbo(),
+                KeyCode::Char('+') | KeyCode::Char('=') => scheduler.faster(),
+                KeyCode::Char('-') => scheduler.slower(),
+                KeyCode::Char('q') | KeyCode::Esc => return true,
+                // Raw mode swallows the signal.
+                KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => return true,
+                _ => {}
+            }
+        }
+        false
+    }
+}
+
+impl Drop for KeyboardControls {
+    fn drop(&mut self) {
+        let _ = terminal::disable_raw_mode();
+    }
 }
 
 
-/// Takes a Path to a file which will be loaded into a 256 long vec, returns error messages if something goes wrong. 
-fn load_rom(path: &Path) -> Result<Vec<u8>, String> {
-    let rom_file = File::open(&path)
-        .map_err(|e| format!("Could not open rom-file: {}", e))?;
+/// The JSON line a headless run prints at the end.
+#[derive(Serialize)]
+struct HeadlessResult {
+    /// "halted", "fault" or "step_limit".
+    status: &'static str,
+    steps: u64,
+    cycles: u64,
+    registers: [u8; 4],
+    pc: u8,
+    sc: u8,
+    sp: u8,
+    flags: HeadlessFlags,
+    memory_digest: String,
+    /// The fault which halted the CPU.
+    fault: Option<String>,
+}
 
-    let rom_meta = rom_file.metadata()
-        .map_err(|e| format!("Failed to read the metadata of the rom-file: {}", e))?;
+#[derive(Serialize)]
+struct HeadlessFlags {
+    zero: bool,
+    signed: bool,
+    carry: bool,
+    overflow: bool,
+}
 
-    let size = rom_meta.len();
-    if size > 256 {
-        return Err(format!("Rom file exceeds the 256 byte limit ({})", size));
+/// Runs the rom without UI until it halts or the step limit is hit, then prints the final state as JSON.
+/// Exits with the status of the run, errors are returned.
+fn run_headless(config: &RunArgs, max_steps: Option<u64>, save_snapshot: Option<&Path>) -> Result<(), String> {
+    let (mut builder, _) = setup(config)?;
+
+    if let Some(path) = &config.trace {
+        builder = builder.tracer(Tracer::create(path, config.trace_format)?);
     }
+    let mut machine = builder.build()?;
 
-    let reader = BufReader::new(rom_file);
-    let mut rom: Vec<u8> = Vec::with_capacity(size as usize);
+    if let Some(path) = &config.restore {
+        Snapshot::load(path)?.restore(&mut machine.cpu)?;
+    }
 
-    for byte in reader.bytes() {
-        rom.push(byte
-            .map_err(|e| format!("Failed to read byte: {}", e))?
-        );
+    let halted = machine.run(max_steps.unwrap_or(u64::MAX));
+    if let Some(tracer) = machine.tracer.take() {
+        tracer.finish()?;
     }
-    Ok(rom)
-}
\ No newline at end of file
+    machine.cpu.io_ctl.finish_input()?;
+    for fault in machine.cpu.take_faults() {
+        eprintln!("Fault: {}", fault);
+    }
+    let cpu = &machine.cpu;
+
+    if let Some(path) = save_snapshot {
+        Snapshot::capture(cpu).save(path)?;
+    }
+
+    let fault = cpu.halt_fault();
+    let status = match (halted, fault) {
+        (true, Some(_)) => "fault",
+        (true, None) => "halted",
+        (false, _) => "step_limit",
+    };
+    let result = HeadlessResult {
+        status,
+        steps: machine.steps,
+        cycles: cpu.cycles(),
+        registers: [0, 1, 2, 3].map(|i| cpu.register(i)),
+        pc: cpu.program_counter(),
+        sc: cpu.secondary_counter(),
+        sp: cpu.stack_pointer(),
+        flags: HeadlessFlags {
+            zero: cpu.flag(Flag::Zero),
+            signed: cpu.flag(Flag::Signed),
+            carry: cpu.flag(Flag::Carry),
+            overflow: cpu.flag(Flag::Overflow),
+        },
+        memory_digest: format!("{:016x}", cpu.memory.digest()),
+        fault: fault.map(|fault| fault.to_string()),
+    };
+    let json = serde_json::to_string(&result)
+        .map_err(|e| format!("Could not serialize the result: {}", e))?;
+    println!("{}", json);
+
+    exit(match status {
+        "halted" => 0,
+        "step_limit" => 3,
+        _ => 4,
+    })
+}
+
+/// Assembles the source file and writes the image next to it (or to the given output path).
+fn assemble(source: &Path, output: Option<&Path>, stack: bool) -> Result<(), String> {
+    let text = fs::read_to_string(source)
+        .map_err(|e| format!("Could not read source file: {}", e))?;
+
+    let extensions = Extensions { stack, ..Extensions::default() };
+    let image = Assembler::assemble_for(&text, extensions)
+        .map_err(|e| format!("{}: {}", source.display(), e))?;
+
+    let output = output.map(Path::to_path_buf)
+        .unwrap_or_else(|| source.with_extension("bin"));
+
+    fs::write(&output, &image)
+        .map_err(|e| format!("Could not write rom image: {}", e))?;
+
+    println!("Assembled {} bytes into {}", image.len(), output.display());
+    Ok(())
+}
+
+/// Prints the disassembly of the rom image.
+/// With banks the fixed window comes first, then every bank as it's seen through the first window.
+fn disassemble(rom_file: &Path, stack: bool, machine: Option<&Path>) -> Result<(), String> {
+    let machine = machine.map(MachineConfig::load).transpose()?;
+    let extensions = Extensions {
+        stack: stack || machine.as_ref().is_some_and(|machine| machine.stack),
+        ..Default::default()
+    };
+
+    let Some(layout) = machine.and_then(|machine| machine.banks) else {
+        print_disassembly(&load_rom(rom_file)?, 0, extensions);
+        return Ok(());
+    };
+
+    let rom = load_banked_rom(rom_file, &layout)?;
+    let window_start = layout.window_start();
+
+    println!("        .banks {:#04X}, {}", layout.size, layout.windows);
+    print_disassembly(&rom[..rom.len().min(window_start as usize)], 0, extensions);
+    for bank in 0..layout.count {
+        let start = layout.bank_offset(bank);
+        if start >= rom.len() {
+            break;
+        }
+        println!("        .bank {}", bank);
+        print_disassembly(&rom[start..rom.len().min(start + layout.size as usize)], window_start, extensions);
+    }
+    Ok(())
+}
+
+fn print_disassembly(image: &[u8], start: u8, extensions: Extensions) {
+    for line in disassembler::disassemble_from(image, start, extensions) {
+        let bytes = line.bytes.iter()
+            .map(|byte| format!("{:02X}", byte))
+            .collect::<Vec<String>>()
+            .join(" ");
+
+        println!("{:02X}: {:<6} {}", line.address, bytes, line.text);
+    }
+}
+
+/// Runs every test spec and prints the failed expectations, errors if any test failed.
+fn run_tests(specs: &[PathBuf]) -> Result<(), String> {
+    let mut failed = 0;
+
+    for path in specs {
+        let base_dir = path.parent().unwrap_or(Path::new("."));
+        let outcome = TestSpec::load(path).and_then(|spec| spec.run(base_dir));
+
+        match outcome {
+            Ok(outcome) if outcome.failures.is_empty() => {
+                println!("{} {} ({} steps)", "PASS".bright_green(), path.display(), outcome.steps);
+            }
+            Ok(outcome) => {
+                failed += 1;
+                println!("{} {} ({} steps)", "FAIL".bright_red(), path.display(), outcome.steps);
+                for failure in outcome.failures {
+                    println!("    {}", failure);
+                }
+            }
+            Err(msg) => {
+                failed += 1;
+                println!("{} {}: {}", "ERROR".bright_red(), path.display(), msg);
+            }
+        }
+    }
+
+    println!("{} passed, {} failed", specs.len() - failed, failed);
+    if failed != 0 {
+        return Err(format!("{} of {} tests failed", failed, specs.len()));
+    }
+    Ok(())
+}
+
+/// Parses a command line address, used by clap.
+fn parse_address(text: &str) -> Result<u8, String> {
+    debugger::parse_byte(Some(text))
+}
+
+/// Parses a step rate, a clock or the UI rate, they have to be positive.
+fn parse_rate(text: &str) -> Result<f32, String> {
+    let rate = text.parse::<f32>().map_err(|e| e.to_string())?;
+    check_rate(rate)
+}
+
+/// Parses a `kind=action` pair of --fault.
+fn parse_fault(text: &str) -> Result<(FaultKind, FaultAction), String> {
+    let (kind, action) = text.split_once('=')
+        .ok_or_else(|| format!("expected kind=action, got '{}'", text))?;
+
+    Ok((FaultKind::from_str(kind, true)?, FaultAction::from_str(action, true)?))
+}
@@ -0,0 +1,183 @@
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+use crate::devices::device::DeviceInput;
+
+/// A line of a replay file, the step is the number of executed instructions (0 is before the first one).
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum ReplayEvent {
+    /// A client connected to the device mounted at the address.
+    Connected { step: u64, address: u8 },
+    /// The client of the device went away.
+    Disconnected { step: u64, address: u8 },
+    /// The device received a byte.
+    Byte { step: u64, address: u8, value: u8 },
+    /// The devices requested an interrupt, the codes are OR-ed together.
+    Interrupt { step: u64, code: u8 },
+}
+
+impl ReplayEvent {
+    fn input(step: u64, address: u8, input: DeviceInput) -> Self {
+        match input {
+            DeviceInput::Connected => ReplayEvent::Connected { step, address },
+            DeviceInput::Disconnected => ReplayEvent::Disconnected { step, address },
+            DeviceInput::Byte(value) => ReplayEvent::Byte { step, address, value },
+        }
+    }
+
+    fn step(&self) -> u64 {
+        match *self {
+            ReplayEvent::Connected { step, .. }
+            | ReplayEvent::Disconnected { step, .. }
+            | ReplayEvent::Byte { step, .. }
+            | ReplayEvent::Interrupt { step, .. } => step,
+        }
+    }
+
+    /// The device and its input, None for interrupts.
+    fn device_input(&self) -> Option<(u8, DeviceInput)> {
+        match *self {
+            ReplayEvent::Connected { address, .. } => Some((address, DeviceInput::Connected)),
+            ReplayEvent::Disconnected { address, .. } => Some((address, DeviceInput::Disconnected)),
+            ReplayEvent::Byte { address, value, .. } => Some((address, DeviceInput::Byte(value))),
+            ReplayEvent::Interrupt { .. } => None,
+        }
+    }
+}
+
+/// Writes everything the devices got from the outside world into a replay file, one JSON object per line:
+/// ```text
+/// {"kind":"connected","step":0,"address":51}
+/// {"kind":"interrupt","step":1,"code":1}
+/// {"kind":"byte","step":40,"address":51,"value":104}
+/// ```
+/// Every line is flushed right away, so the recording survives the emulator getting killed.
+#[derive(Debug)]
+pub struct InputRecorder {
+    writer: BufWriter<File>,
+    /// The first write error, the recording stops there and finish reports it.
+    error: Option<String>,
+}
+
+impl InputRecorder {
+    pub fn create(path: &Path) -> Result<Self, String> {
+        let file = File::create(path)
+            .map_err(|e| format!("Could not create replay file: {}", e))?;
+
+        Ok(Self { writer: BufWriter::new(file), error: None })
+    }
+
+    /// Records input of the device mounted at the address.
+    pub fn input(&mut self, step: u64, address: u8, input: DeviceInput) {
+        self.write(&ReplayEvent::input(step, address, input));
+    }
+
+    pub fn interrupt(&mut self, step: u64, code: u8) {
+        self.write(&ReplayEvent::Interrupt { step, code });
+    }
+
+    /// Reports the first write error.
+    pub fn finish(self) -> Result<(), String> {
+        match self.error {
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
+    }
+
+    fn write(&mut self, event: &ReplayEvent) {
+        if self.error.is_some() {
+            return;
+        }
+
+        let result = serde_json::to_writer(&mut self.writer, event)
+            .map_err(|e| e.to_string())
+            .and_then(|_| writeln!(self.writer).and_then(|_| self.writer.flush()).map_err(|e| e.to_string()));
+
+        if let Err(e) = result {
+            self.error = Some(format!("Could not write replay file: {}", e));
+        }
+    }
+}
+
+/// Feeds the input of a replay file to the devices at the steps it was recorded at.
+/// The recorded interrupts are compared with the requested ones, so a replay which went differently gets noticed.
+#[derive(Debug)]
+pub struct InputReplay {
+    events: VecDeque<ReplayEvent>,
+    /// The first difference between the recording and the replay.
+    divergence: Option<String>,
+}
+
+impl InputReplay {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Could not read replay file: {}", e))?;
+
+        let mut events = VecDeque::new();
+        for (index, line) in text.lines().enumerate().filter(|(_, line)| !line.trim().is_empty()) {
+            let event: ReplayEvent = serde_json::from_str(line)
+                .map_err(|e| format!("Invalid replay file, line {}: {}", index + 1, e))?;
+
+            if events.back().is_some_and(|last: &ReplayEvent| last.step() > event.step()) {
+                return Err(format!("Invalid replay file, line {}: the steps go backwards", index + 1));
+            }
+            events.push_back(event);
+        }
+
+        Ok(Self { events, divergence: None })
+    }
+
+    /// The addresses of the devices which get input.
+    pub fn addresses(&self) -> Vec<u8> {
+        let mut addresses = self.events.iter()
+            .filter_map(|event| event.device_input().map(|(address, _)| address))
+            .collect::<Vec<u8>>();
+        addresses.sort_unstable();
+        addresses.dedup();
+        addresses
+    }
+
+    /// Returns the next input of the device at the address, if it arrived at the given step.
+    pub fn take_input(&mut self, step: u64, address: u8) -> Option<DeviceInput> {
+        let event = self.events.front()?;
+        if event.step() != step {
+            return None;
+        }
+
+        let (event_address, input) = event.device_input()?;
+        if event_address != address {
+            return None;
+        }
+
+        self.events.pop_front();
+        Some(input)
+    }
+
+    /// Compares the interrupt the devices requested at the step with the recorded one.
+    pub fn check_interrupt(&mut self, step: u64, code: Option<u8>) {
+        let expected = match self.events.front() {
+            Some(&ReplayEvent::Interrupt { step: recorded, code }) if recorded == step => {
+                self.events.pop_front();
+                Some(code)
+            }
+            _ => None,
+        };
+
+        if expected != code && self.divergence.is_none() {
+            let describe = |code: Option<u8>| code.map_or("none".to_string(), |code| format!("{:08b}", code));
+            self.divergence = Some(format!("The replay diverged at step {}: expected interrupt {}, got {}",
+                                           step, describe(expected), describe(code)));
+        }
+    }
+
+    /// Reports the first difference to the recording.
+    pub fn finish(self) -> Result<(), String> {
+        match self.divergence {
+            Some(divergence) => Err(divergence),
+            None => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,188 @@
+use std::fmt::{Display, Formatter};
+use std::time::{Duration, Instant};
+
+/// How far the scheduler may fall behind, anything more is skipped instead of catching up in a burst.
+const MAX_LAG: Duration = Duration::from_millis(100);
+
+/// The limits of the speed factor.
+const MIN_FACTOR: f64 = 1.0 / 1024.0;
+const MAX_FACTOR: f64 = 1024.0;
+
+/// How fast the CPU runs.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Speed {
+    /// Instructions per second.
+    StepRate(f32),
+    /// Clock cycles per second, every instruction takes as long as its cycles.
+    ClockHz(f32),
+}
+
+impl Speed {
+    /// How long an instruction which took the given cycles lasts.
+    pub fn delay(self, cycles: u64) -> Duration {
+        match self {
+            Speed::StepRate(rate) => Duration::from_secs_f64(1.0 / rate as f64),
+            Speed::ClockHz(hz) => Duration::from_secs_f64(cycles as f64 / hz as f64),
+        }
+    }
+}
+
+/// Rejects rates nothing can be paced by: zero, negative, infinite or NaN.
+pub fn check_rate(rate: f32) -> Result<f32, String> {
+    if rate.is_finite() && rate > 0.0 {
+        Ok(rate)
+    } else {
+        Err(format!("{} is not a positive rate", rate))
+    }
+}
+
+impl Display for Speed {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Speed::StepRate(rate) => write!(f, "{} steps/s", rate),
+            Speed::ClockHz(hz) => write!(f, "{} Hz", hz),
+        }
+    }
+}
+
+/// Paces the CPU in real time: the instructions which are due get run in a batch,
+/// then the caller sleeps until [`Scheduler::next_due`] instead of spinning.
+///
+/// It can be paused, run unthrottled (turbo) or sped up and slowed down,
+/// and it measures the instructions per second it actually achieves.
+#[derive(Debug)]
+pub struct Scheduler {
+    speed: Speed,
+    /// The speed gets multiplied by this.
+    factor: f64,
+    paused: bool,
+    turbo: bool,
+    /// Lets one instruction run while paused.
+    single_step: bool,
+    /// When the next instruction should run.
+    due: Instant,
+
+    /// The achieved instructions per second, measured over about a second.
+    ips: f64,
+    measure_start: Instant,
+    measure_steps: u64,
+}
+
+impl Scheduler {
+    pub fn new(speed: Speed) -> Self {
+        let now = Instant::now();
+        Self {
+            speed,
+            factor: 1.0,
+            paused: false,
+            turbo: false,
+            single_step: false,
+            due: now,
+
+            ips: 0.0,
+            measure_start: now,
+            measure_steps: 0,
+        }
+    }
+
+    /// True if an instruction should run now.
+    pub fn is_due(&self, now: Instant) -> bool {
+        self.single_step || (!self.paused && (self.turbo || now >= self.due))
+    }
+
+    /// When the next instruction should run, None while paused.
+    pub fn next_due(&self) -> Option<Instant> {
+        match (self.paused, self.turbo) {
+            _ if self.single_step => Some(Instant::now()),
+            (true, _) => None,
+            (false, true) => Some(Instant::now()),
+            (false, false) => Some(self.due),
+        }
+    }
+
+    /// Has to be called after every instruction with the cycles it took.
+    pub fn stepped(&mut self, cycles: u64) {
+        let now = Instant::now();
+        if let Some(limit) = now.checked_sub(MAX_LAG) {
+            self.due = self.due.max(limit);
+        }
+
+        self.due += self.speed.delay(cycles).div_f64(self.factor);
+        self.single_step = false;
+        self.measure_steps += 1;
+        self.measure(now);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn pause(&mut self) {
+        if !self.paused {
+            self.toggle_pause();
+        }
+    }
+
+    /// Lets a single instruction run while paused.
+    pub fn step_once(&mut self) {
+        if self.paused {
+            self.single_step = true;
+        }
+    }
+
+    pub fn toggle_pause(&mut self) {
+        self.paused = !self.paused;
+        self.restart();
+    }
+
+    /// Switches between running unthrottled and at the set speed.
+    pub fn toggle_turbo(&mut self) {
+        self.turbo = !self.turbo;
+        self.restart();
+    }
+
+    /// Doubles the speed.
+    pub fn faster(&mut self) {
+        self.factor = (self.factor * 2.0).min(MAX_FACTOR);
+    }
+
+    /// Halves the speed.
+    pub fn slower(&mut self) {
+        self.factor = (self.factor / 2.0).max(MIN_FACTOR);
+    }
+
+    /// The achieved instructions per second.
+    pub fn ips(&mut self) -> f64 {
+        self.measure(Instant::now());
+        self.ips
+    }
+
+    /// A line like "100 steps/s x2, 199.8 IPS" for the UI.
+    pub fn status(&mut self) -> String {
+        let ips = self.ips();
+        let mode = match (self.paused, self.turbo) {
+            (true, _) => "paused".to_string(),
+            (false, true) => "turbo".to_string(),
+            (false, false) if self.factor == 1.0 => self.speed.to_string(),
+            (false, false) => format!("{} x{}", self.speed, self.factor),
+        };
+        format!("{}, {:.1} IPS", mode, ips)
+    }
+
+    /// Starts pacing and measuring from now.
+    fn restart(&mut self) {
+        let now = Instant::now();
+        self.due = now;
+        self.measure_start = now;
+        self.measure_steps = 0;
+    }
+
+    fn measure(&mut self, now: Instant) {
+        let elapsed = now - self.measure_start;
+        if elapsed >= Duration::from_secs(1) {
+            self.ips = self.measure_steps as f64 / elapsed.as_secs_f64();
+            self.measure_start = now;
+            self.measure_steps = 0;
+        }
+    }
+}
@@ -0,0 +1,128 @@
+use std::fs;
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+use crate::helium::cpu::{CpuState, CPU};
+use crate::helium::io_controller::DeviceState;
+use crate::helium::memory::MemoryState;
+
+/// Bumped whenever the layout of the snapshot changes so older snapshots can't be read anymore.
+/// Fields which were added later (the cycles, SP, the interrupt stack, the banks, the write-once bytes
+/// and the ROM control state) have serde defaults, so version 1 snapshots from before them still load.
+const SNAPSHOT_VERSION: u32 = 1;
+
+/// The complete state of a machine: the CPU, the memory and every device, saved as TOML.
+/// A snapshot can only be restored into a machine with the same devices at the same addresses.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub version: u32,
+    pub cpu: CpuState,
+    pub memory: MemoryState,
+    #[serde(default, rename = "device")]
+    pub devices: Vec<DeviceState>,
+}
+
+impl Snapshot {
+    pub fn capture(cpu: &CPU) -> Self {
+        Self {
+            version: SNAPSHOT_VERSION,
+            cpu: cpu.save_state(),
+            memory: cpu.memory.save_state(),
+            devices: cpu.io_ctl.save_state(),
+        }
+    }
+
+    /// Puts the CPU into the saved state, fails if the devices of the machine don't match the snapshot.
+    /// Nothing changes if it fails, the devices and the memory get their old state back.
+    pub fn restore(&self, cpu: &mut CPU) -> Result<(), String> {
+        let devices = cpu.io_ctl.save_state();
+        let memory = cpu.memory.save_state();
+
+        let loaded = cpu.io_ctl.load_state(&self.devices)
+            .and_then(|_| cpu.memory.load_state(&self.memory));
+        if let Err(e) = loaded {
+            cpu.io_ctl.load_state(&devices)
+                .and_then(|_| cpu.memory.load_state(&memory))
+                .map_err(|rollback| format!("{} (and the old state couldn't be put back: {})", e, rollback))?;
+            return Err(e);
+        }
+
+        cpu.load_state(&self.cpu);
+        Ok(())
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let text = toml::to_string(self)
+            .map_err(|e| format!("Could not serialize the snapshot: {}", e))?;
+
+        fs::write(path, text).map_err(|e| format!("Could not write snapshot: {}", e))
+    }
+
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Could not read snapshot: {}", e))?;
+
+        let snapshot: Self = toml::from_str(&text)
+            .map_err(|e| format!("Invalid snapshot: {}", e))?;
+
+        if snapshot.version != SNAPSHOT_VERSION {
+            return Err(format!("Snapshot version {} is not supported (expected {})", snapshot.version, SNAPSHOT_VERSION));
+        }
+        Ok(snapshot)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::stdout_ascii_buffer::CharIOBuffer;
+    use crate::machine::Machine;
+    use crate::tools::assembler::Assembler;
+
+    /// Fills the char buffer and memory with the alphabet, forever.
+    const SOURCE: &str = "
+        LDI r0, 'A'
+        LDI r2, 1
+loop:   OUT r0, [r1]
+        ST r0, 0xF0
+        ADD r2, r0
+        ADD r2, r1
+        JMP loop";
+
+    fn machine(padding: usize) -> Machine {
+        let mut rom = Assembler::assemble(SOURCE).unwrap();
+        rom.resize(rom.len() + padding, 0);
+        Machine::builder(rom).device(0..50, CharIOBuffer::new()).build().unwrap()
+    }
+
+    #[test]
+    fn a_restored_machine_runs_on_the_same() {
+        let mut original = machine(0);
+        original.run(37);
+        let snapshot = Snapshot::capture(&original.cpu);
+        assert_eq!(toml::from_str::<Snapshot>(&toml::to_string(&snapshot).unwrap()).unwrap(), snapshot);
+
+        let mut restored = machine(0);
+        restored.run(3);
+        snapshot.restore(&mut restored.cpu).unwrap();
+
+        original.run(50);
+        restored.run(50);
+        assert_eq!(Snapshot::capture(&restored.cpu), Snapshot::capture(&original.cpu));
+        assert_eq!(restored.find_device::<CharIOBuffer>().unwrap().as_ascii_str()[..5], *"ABCDE");
+    }
+
+    #[test]
+    fn a_failed_restore_changes_nothing() {
+        let mut original = machine(0);
+        original.run(37);
+        let snapshot = Snapshot::capture(&original.cpu);
+
+        // The devices match, but the memory doesn't because of the bigger ROM.
+        let mut other = machine(1);
+        other.run(5);
+        let before = Snapshot::capture(&other.cpu);
+
+        assert!(snapshot.restore(&mut other.cpu).unwrap_err().contains("ROM"));
+        assert_eq!(Snapshot::capture(&other.cpu), before);
+    }
+}
@@ -0,0 +1,721 @@
+use std::collections::HashMap;
+use crate::helium::isa::{Condition, Extensions, Instruction, Reg};
+
+/// The size of the address space, the assembled image can't be bigger than this without banks.
+const IMAGE_SIZE: usize = 256;
+
+/// A two-pass assembler for the Helium ISA.
+///
+/// # Syntax:
+/// One statement per line, `;` starts a comment. Mnemonics, registers and conditions are case-insensitive.
+/// ```text
+/// start:  LDI r0, 'H'         ; labels end with a colon
+///         OUT r0, [r1]        ; register indirect forms use brackets
+///         JMP NZ, start       ; conditions: AL, C, NC, O, NO, Z, NZ, S
+///         .org 0x80           ; moves the output address
+/// text:   .byte "HI", 0, text+1
+/// ```
+/// Operand order follows the encoding: `OP rX, imm8` for the imm8 forms and `OP rX, rY` for the register pairs,
+/// so `ADD r0, r1` is `r1 = r0 + r1` and `STR r0, r1` is `mem[r1] = r0`.
+///
+/// Numbers can be decimal, `0x` hex, `0b` binary or a `'c'` char, labels can be offset with `+`/`-`.
+/// JMPR takes the target address and encodes the distance from the next instruction.
+///
+/// The instructions of the stack extension (`PUSH rX`, `POP rX`, `XSP rX`, `CALL target` and `RET`) are always accepted,
+/// they share their encodings with `MOV rX, rX`, `STR rX, rX`, `OR rX, rX` and `CMN`, so the machine has to enable the extension.
+/// [`Assembler::assemble_for`] the stack extension rejects those old instructions, as the CPU would run them as the new ones.
+///
+/// # Banks:
+/// `.banks size, windows` declares the bank layout of the machine (see `BankLayout`), after that `.bank n` moves the output
+/// into bank n, placed where it is in a multi-bank rom image. The addresses (and labels) are the ones the CPU sees
+/// while the bank is selected in the first window, `.bank n, w` assembles for window w instead.
+/// `.org` stays inside the window of the bank.
+/// ```text
+///         .banks 0x40, 1
+///         .bank 3
+/// far:    LDI r0, 1           ; far is 0xC0, the code lands at 0x180 in the image
+/// ```
+pub struct Assembler {
+    statements: Vec<Statement>,
+    labels: HashMap<String, u8>,
+    extensions: Extensions,
+}
+
+/// A line which produces output, with the address it starts at.
+struct Statement {
+    line: usize,
+    address: u8,
+    /// Where the statement goes in the image, the address unless it's in a bank.
+    offset: usize,
+    kind: StatementKind,
+}
+
+/// The bank the output currently goes into.
+struct BankCursor {
+    number: u8,
+    /// The first address of the window and where it goes in the image.
+    window_start: usize,
+    image_start: usize,
+    size: usize,
+}
+
+impl BankCursor {
+    fn offset(&self, address: usize) -> usize {
+        self.image_start + address - self.window_start
+    }
+}
+
+enum StatementKind {
+    Instruction { instruction: Instruction, imm: Option<Imm> },
+    Bytes(Vec<Expr>),
+}
+
+/// The imm8 operand of an instruction.
+enum Imm {
+    /// Used as is.
+    Value(Expr),
+    /// The target of a JMPR, gets turned into an offset from the next instruction.
+    Relative(Expr),
+}
+
+/// A sum of numbers and labels.
+#[derive(Debug, Clone)]
+struct Expr {
+    terms: Vec<(bool, Term)>, // (negated, term)
+}
+
+#[derive(Debug, Clone)]
+enum Term {
+    Number(i64),
+    Label(String),
+}
+
+/// A parsed operand of an instruction.
+#[derive(Debug)]
+enum Operand {
+    Reg(Reg),
+    Indirect(Reg),
+    Expr(Expr),
+}
+
+impl Assembler {
+    /// Assembles the given source into a rom image.
+    /// The image is as long as the highest address written, gaps are filled with zeros.
+    pub fn assemble(source: &str) -> Result<Vec<u8>, String> {
+        Self::assemble_for(source, Extensions::default())
+    }
+
+    /// Like assemble, but for a machine with the given extensions,
+    /// instructions whose encodings an extension takes over are rejected.
+    pub fn assemble_for(source: &str, extensions: Extensions) -> Result<Vec<u8>, String> {
+        let mut asm = Self { statements: Vec::new(), labels: HashMap::new(), extensions };
+        asm.first_pass(source)?;
+        asm.second_pass()
+    }
+
+    /// Parses every line, assigns addresses to the statements and collects the labels.
+    fn first_pass(&mut self, source: &str) -> Result<(), String> {
+        let mut address: usize = 0;
+        // (size, windows) from .banks
+        let mut layout: Option<(usize, usize)> = None;
+        let mut bank: Option<BankCursor> = None;
+
+        for (i, raw_line) in source.lines().enumerate() {
+            let line_nr = i + 1;
+            let mut line = strip_comment(raw_line).trim();
+
+            // Labels
+            while let Some((label, rest)) = split_label(line) {
+                if !is_identifier(label) || parse_register(label).is_some() {
+                    return Err(format!("line {}: invalid label name '{}'", line_nr, label));
+                }
+                let end = bank.as_ref().map_or(IMAGE_SIZE, |bank| bank.window_start + bank.size);
+                if address >= end {
+                    return Err(format!("line {}: label '{}' is past the end of memory", line_nr, label));
+                }
+                if self.labels.insert(label.to_ascii_lowercase(), address as u8).is_some() {
+                    return Err(format!("line {}: label '{}' is already defined", line_nr, label));
+                }
+                line = rest.trim();
+            }
+
+            if line.is_empty() {
+                continue;
+            }
+
+            let (mnemonic, operands) = match line.split_once(char::is_whitespace) {
+                Some((mnemonic, rest)) => (mnemonic, rest.trim()),
+                None => (line, ""),
+            };
+            let operands = split_operands(operands)
+                .map_err(|e| format!("line {}: {}", line_nr, e))?;
+
+            let kind = match mnemonic.to_ascii_lowercase().as_str() {
+                ".org" => {
+                    let [operand] = operands.as_slice() else {
+                        return Err(format!("line {}: .org takes exactly one address", line_nr));
+                    };
+                    let value = parse_expr(operand)
+                        .and_then(|expr| self.eval_now(&expr))
+                        .map_err(|e| format!("line {}: {}", line_nr, e))? as usize;
+                    if let Some(bank) = &bank {
+                        if !(bank.window_start..bank.window_start + bank.size).contains(&value) {
+                            return Err(format!("line {}: .org {:#04X} is outside the window of bank {}", line_nr, value, bank.number));
+                        }
+                    }
+                    address = value;
+                    continue;
+                }
+                ".banks" => {
+                    let values = operands.iter()
+                        .map(|operand| parse_expr(operand).and_then(|expr| self.eval_now(&expr)))
+                        .collect::<Result<Vec<u8>, String>>()
+                        .map_err(|e| format!("line {}: {}", line_nr, e))?;
+                    let [size, windows] = values.as_slice() else {
+                        return Err(format!("line {}: .banks takes the bank size and the window count", line_nr));
+                    };
+                    if *size == 0 || *windows == 0 || *size as usize * *windows as usize >= IMAGE_SIZE {
+                        return Err(format!("line {}: {} windows of {} bytes don't fit", line_nr, windows, size));
+                    }
+                    layout = Some((*size as usize, *windows as usize));
+                    continue;
+                }
+                ".bank" => {
+                    let Some((size, windows)) = layout else {
+                        return Err(format!("line {}: .bank needs the layout from .banks first", line_nr));
+                    };
+                    let values = operands.iter()
+                        .map(|operand| parse_expr(operand).and_then(|expr| self.eval_now(&expr)))
+                        .collect::<Result<Vec<u8>, String>>()
+                        .map_err(|e| format!("line {}: {}", line_nr, e))?;
+                    let (number, window) = match values.as_slice() {
+                        [number] => (*number, 0),
+                        [number, window] => (*number, *window as usize),
+                        _ => return Err(format!("line {}: .bank takes a bank number and an optional window", line_nr)),
+                    };
+                    if window >= windows {
+                        return Err(format!("line {}: there are only {} windows", line_nr, windows));
+                    }
+
+                    let fixed = IMAGE_SIZE - windows * size;
+                    let cursor = BankCursor {
+                        number,
+                        window_start: fixed + window * size,
+                        image_start: fixed + number as usize * size,
+                        size,
+                    };
+                    address = cursor.window_start;
+                    bank = Some(cursor);
+                    continue;
+                }
+                ".byte" => {
+                    let bytes = parse_byte_list(&operands)
+                        .map_err(|e| format!("line {}: {}", line_nr, e))?;
+                    StatementKind::Bytes(bytes)
+                }
+                _ => {
+                    let (instruction, imm) = parse_instruction(mnemonic, &operands)
+                        .map_err(|e| format!("line {}: {}", line_nr, e))?;
+                    if let Some(taken) = self.taken_by_extension(instruction) {
+                        return Err(format!("line {}: {} {} has the encoding of {} with the stack extension",
+                                           line_nr, mnemonic.to_ascii_uppercase(), operands.join(", "), taken.mnemonic()));
+                    }
+                    StatementKind::Instruction { instruction, imm }
+                }
+            };
+
+            let size = match &kind {
+                StatementKind::Instruction { instruction, .. } => instruction.size() as usize,
+                StatementKind::Bytes(bytes) => bytes.len(),
+            };
+
+            let offset = match &bank {
+                Some(bank) if address + size > bank.window_start + bank.size => {
+                    return Err(format!("line {}: bank {} exceeds its {} bytes", line_nr, bank.number, bank.size));
+                }
+                Some(bank) => bank.offset(address),
+                None if address + size > IMAGE_SIZE => {
+                    return Err(format!("line {}: program exceeds the {} byte limit", line_nr, IMAGE_SIZE));
+                }
+                None => address,
+            };
+
+            self.statements.push(Statement { line: line_nr, address: address as u8, offset, kind });
+            address += size;
+        }
+        Ok(())
+    }
+
+    /// The instruction the CPU runs instead, if an enabled extension took over the encoding.
+    fn taken_by_extension(&self, instruction: Instruction) -> Option<Instruction> {
+        if !self.extensions.stack {
+            return None;
+        }
+        Instruction::decode_with(instruction.opcode(), self.extensions).filter(|decoded| *decoded != instruction)
+    }
+
+    /// Resolves every operand and writes the bytes into the image.
+    fn second_pass(&self) -> Result<Vec<u8>, String> {
+        let mut image: Vec<Option<u8>> = Vec::new();
+
+        for statement in &self.statements {
+            let mut bytes: Vec<u8> = Vec::new();
+
+            match &statement.kind {
+                StatementKind::Instruction { instruction, imm } => {
+                    bytes.push(instruction.opcode());
+
+                    match imm {
+                        Some(Imm::Value(expr)) => bytes.push(self.eval_byte(expr, statement.line)?),
+                        Some(Imm::Relative(expr)) => {
+                            let target = self.eval_byte(expr, statement.line)?;
+                            let next = statement.address.wrapping_add(instruction.size());
+                            bytes.push(target.wrapping_sub(next));
+                        }
+                        None => {}
+                    }
+                }
+                StatementKind::Bytes(exprs) => {
+                    for expr in exprs {
+                        bytes.push(self.eval_byte(expr, statement.line)?);
+                    }
+                }
+            }
+
+            for (offset, byte) in bytes.into_iter().enumerate() {
+                let position = statement.offset + offset;
+                if image.len() <= position {
+                    image.resize(position + 1, None);
+                }
+                if image[position].is_some() {
+                    return Err(format!("line {}: overwrites already assembled byte at {:#04X}", statement.line, position));
+                }
+                image[position] = Some(byte);
+            }
+        }
+
+        Ok(image.into_iter().map(|byte| byte.unwrap_or(0)).collect())
+    }
+
+    /// Evaluates an expression which has to fit in a byte, negative values are stored as two's complement.
+    fn eval_byte(&self, expr: &Expr, line: usize) -> Result<u8, String> {
+        let value = self.eval(expr).map_err(|e| format!("line {}: {}", line, e))?;
+        if !(-128..=255).contains(&value) {
+            return Err(format!("line {}: value {} doesn't fit in a byte", line, value));
+        }
+        Ok(value as u8)
+    }
+
+    /// Evaluates an expression which has to be resolvable in the first pass (for directives like .org).
+    fn eval_now(&self, expr: &Expr) -> Result<u8, String> {
+        let value = self.eval(expr)?;
+        u8::try_from(value).map_err(|_| format!("address {} is out of range", value))
+    }
+
+    fn eval(&self, expr: &Expr) -> Result<i64, String> {
+        let mut sum: i64 = 0;
+        for (negated, term) in &expr.terms {
+            let value = match term {
+                Term::Number(n) => *n,
+                Term::Label(name) => *self.labels.get(&name.to_ascii_lowercase())
+                    .ok_or_else(|| format!("undefined label '{}'", name))? as i64,
+            };
+            sum = if *negated { sum - value } else { sum + value };
+        }
+        Ok(sum)
+    }
+}
+
+/// Parses a mnemonic and its operands into an instruction.
+fn parse_instruction(mnemonic: &str, operands: &[String]) -> Result<(Instruction, Option<Imm>), String> {
+    let operands = operands.iter()
+        .map(|op| parse_operand(op))
+        .collect::<Result<Vec<Operand>, String>>()?;
+    let mnemonic = mnemonic.to_ascii_uppercase();
+
+    let no_operands = |instruction: Instruction| {
+        match operands.as_slice() {
+            [] => Ok((instruction, None)),
+            _ => Err(format!("{} takes no operands", mnemonic)),
+        }
+    };
+    let single_reg = |make: fn(Reg) -> Instruction| {
+        match operands.as_slice() {
+            [Operand::Reg(reg)] => Ok((make(*reg), None)),
+            _ => Err(format!("{} takes a single register", mnemonic)),
+        }
+    };
+    let reg_pair = |make: fn(Reg, Reg) -> Instruction| {
+        match operands.as_slice() {
+            [Operand::Reg(a), Operand::Reg(b)] => Ok((make(*a, *b), None)),
+            _ => Err(format!("{} takes two registers", mnemonic)),
+        }
+    };
+    let reg_imm = |make: fn(Reg) -> Instruction, make_indirect: Option<fn(Reg) -> Instruction>| {
+        match (operands.as_slice(), make_indirect) {
+            ([Operand::Reg(reg), Operand::Expr(expr)], _) => {
+                Ok((make(*reg), Some(Imm::Value(expr.clone()))))
+            }
+            ([Operand::Reg(reg), Operand::Indirect(addr_reg)], Some(make_indirect)) => {
+                Ok((make_indirect(*reg), Some(Imm::Value(Expr::number(*addr_reg)))))
+            }
+            _ => Err(format!("{} takes a register and an address", mnemonic)),
+        }
+    };
+
+    match mnemonic.as_str() {
+        "HLT" | "HALT" => no_operands(Instruction::Halt),
+        "EI" => no_operands(Instruction::EnableInterrupt),
+        "CI" => no_operands(Instruction::ClearInterrupt),
+        "DI" => no_operands(Instruction::DisableInterrupt),
+        "RETI" => no_operands(Instruction::ReturnFromInterrupt),
+        "INT" => no_operands(Instruction::CallInterrupt),
+        "GIC" => no_operands(Instruction::GetInterruptCause),
+        "RST" => no_operands(Instruction::Reset),
+        "NOP" => no_operands(Instruction::NoOp),
+        "RET" => no_operands(Instruction::Return),
+
+        "FSWAP" => single_reg(Instruction::FlagSwap),
+        "SHR" => single_reg(Instruction::ShiftRight),
+        "SHL" => single_reg(Instruction::ShiftLeft),
+        "SIA" => single_reg(Instruction::SetInterruptAddr),
+        "LPC" => single_reg(Instruction::LoadPc),
+        "ROR" => single_reg(Instruction::RotateRight),
+        "PUSH" => single_reg(Instruction::Push),
+        "POP" => single_reg(Instruction::Pop),
+        "XSP" => single_reg(Instruction::StackSwap),
+
+        "LDI" => reg_imm(Instruction::LoadImm, None),
+        "LD" => reg_imm(Instruction::Load, None),
+        "ST" => reg_imm(Instruction::Store, None),
+        "IN" => reg_imm(Instruction::In, Some(Instruction::InReg)),
+        "OUT" => reg_imm(Instruction::Out, Some(Instruction::OutReg)),
+
+        "ADD" => reg_pair(Instruction::Add),
+        "SUB" => reg_pair(Instruction::Sub),
+        "CMN" => reg_pair(Instruction::CompareAdd),
+        "CMP" => reg_pair(Instruction::Compare),
+        "AND" => reg_pair(Instruction::And),
+        "XOR" => reg_pair(Instruction::Xor),
+        "OR" => reg_pair(Instruction::Or),
+        "MOV" => reg_pair(Instruction::Move),
+        "LDR" => reg_pair(Instruction::LoadReg),
+        "STR" => reg_pair(Instruction::StoreReg),
+
+        "CALL" => match operands.as_slice() {
+            [Operand::Expr(target)] => Ok((Instruction::Call, Some(Imm::Value(target.clone())))),
+            _ => Err(String::from("CALL takes a target address")),
+        },
+
+        "JMP" | "JMPR" => {
+            let relative = mnemonic == "JMPR";
+
+            let (condition, target) = match operands.as_slice() {
+                [target] => (Condition::Always, target),
+                [Operand::Expr(cond), target] => {
+                    let condition = cond.as_condition()
+                        .ok_or_else(|| format!("unknown condition in {}", mnemonic))?;
+                    (condition, target)
+                }
+                _ => return Err(format!("{} takes an optional condition and a target", mnemonic)),
+            };
+
+            let (is_reg, imm) = match target {
+                Operand::Indirect(reg) => (true, Imm::Value(Expr::number(*reg))),
+                Operand::Expr(expr) if relative => (false, Imm::Relative(expr.clone())),
+                Operand::Expr(expr) => (false, Imm::Value(expr.clone())),
+                Operand::Reg(_) => return Err(format!("register targets are written as [rX] for {}", mnemonic)),
+            };
+
+            let instruction = if relative {
+                Instruction::JumpRel(condition, is_reg)
+            } else {
+                Instruction::Jump(condition, is_reg)
+            };
+            Ok((instruction, Some(imm)))
+        }
+
+        _ => Err(format!("unknown mnemonic '{}'", mnemonic)),
+    }
+}
+
+impl Expr {
+    /// An expression of a single constant.
+    fn number(value: u8) -> Self {
+        Self { terms: vec![(false, Term::Number(value as i64))] }
+    }
+
+    /// If the expression is a single bare word which is a condition name, returns the condition.
+    fn as_condition(&self) -> Option<Condition> {
+        match self.terms.as_slice() {
+            [(false, Term::Label(name))] => Condition::from_mnemonic(name),
+            _ => None,
+        }
+    }
+}
+
+/// Parses a register (r0 - r3), a [register] or an expression.
+fn parse_operand(text: &str) -> Result<Operand, String> {
+    if let Some(inner) = text.strip_prefix('[').and_then(|t| t.strip_suffix(']')) {
+        return parse_register(inner.trim())
+            .map(Operand::Indirect)
+            .ok_or_else(|| format!("expected a register inside brackets, found '{}'", inner));
+    }
+    if let Some(reg) = parse_register(text) {
+        return Ok(Operand::Reg(reg));
+    }
+    parse_expr(text).map(Operand::Expr)
+}
+
+fn parse_register(text: &str) -> Option<Reg> {
+    match text.to_ascii_lowercase().as_str() {
+        "r0" => Some(0),
+        "r1" => Some(1),
+        "r2" => Some(2),
+        "r3" => Some(3),
+        _ => None,
+    }
+}
+
+/// Parses the operands of .byte, strings get expanded into a byte per char.
+fn parse_byte_list(operands: &[String]) -> Result<Vec<Expr>, String> {
+    let mut bytes = Vec::new();
+    for operand in operands {
+        if let Some(text) = operand.strip_prefix('"').and_then(|t| t.strip_suffix('"')) {
+            for byte in unescape(text)? {
+                bytes.push(Expr::number(byte));
+            }
+        } else {
+            bytes.push(parse_expr(operand)?);
+        }
+    }
+    if bytes.is_empty() {
+        return Err(String::from(".byte needs at least one value"));
+    }
+    Ok(bytes)
+}
+
+/// Parses terms separated by + and -.
+fn parse_expr(text: &str) -> Result<Expr, String> {
+    let mut terms = Vec::new();
+    let mut negated = false;
+    let mut rest = text.trim();
+
+    // A leading sign
+    if let Some(stripped) = rest.strip_prefix('-') {
+        negated = true;
+        rest = stripped.trim_start();
+    }
+
+    loop {
+        // Char literals can contain + or -, so those get cut out first.
+        let end = match rest.strip_prefix('\'') {
+            Some(literal) => literal.find('\'').map(|i| i + 2).unwrap_or(rest.len()),
+            None => 0,
+        };
+        let split = rest[end..].find(['+', '-']).map(|i| i + end);
+
+        let (term, next) = match split {
+            Some(i) => (&rest[..i], Some(&rest[i..])),
+            None => (rest, None),
+        };
+        terms.push((negated, parse_term(term.trim())?));
+
+        match next {
+            Some(next) => {
+                negated = next.starts_with('-');
+                rest = next[1..].trim_start();
+            }
+            None => break,
+        }
+    }
+    Ok(Expr { terms })
+}
+
+fn parse_term(text: &str) -> Result<Term, String> {
+    if text.is_empty() {
+        return Err(String::from("missing value"));
+    }
+
+    if let Some(inner) = text.strip_prefix('\'').and_then(|t| t.strip_suffix('\'')) {
+        let bytes = unescape(inner)?;
+        let [byte] = bytes.as_slice() else {
+            return Err(format!("char literal {} must be a single byte", text));
+        };
+        return Ok(Term::Number(*byte as i64));
+    }
+
+    let lower = text.to_ascii_lowercase();
+    let number = if let Some(hex) = lower.strip_prefix("0x") {
+        i64::from_str_radix(&hex.replace('_', ""), 16)
+    } else if let Some(bin) = lower.strip_prefix("0b") {
+        i64::from_str_radix(&bin.replace('_', ""), 2)
+    } else if text.starts_with(|c: char| c.is_ascii_digit()) {
+        lower.replace('_', "").parse::<i64>()
+    } else if is_identifier(text) {
+        return Ok(Term::Label(text.to_string()));
+    } else {
+        return Err(format!("invalid value '{}'", text));
+    };
+
+    number.map(Term::Number).map_err(|_| format!("invalid number '{}'", text))
+}
+
+/// Turns the escapes (\n, \r, \t, \0, \\, \", \') into bytes, only ASCII is allowed.
+fn unescape(text: &str) -> Result<Vec<u8>, String> {
+    let mut out = Vec::new();
+    let mut chars = text.chars();
+    while let Some(ch) = chars.next() {
+        let byte = match ch {
+            '\\' => match chars.next() {
+                Some('n') => b'\n',
+                Some('r') => b'\r',
+                Some('t') => b'\t',
+                Some('0') => 0,
+                Some(c @ ('\\' | '"' | '\'')) => c as u8,
+                other => return Err(format!("invalid escape sequence \\{}", other.map(String::from).unwrap_or_default())),
+            },
+            c if c.is_ascii() => c as u8,
+            c => return Err(format!("'{}' is not an ASCII character", c)),
+        };
+        out.push(byte);
+    }
+    Ok(out)
+}
+
+/// Cuts the comment off the line, ignoring semicolons in strings and char literals.
+fn strip_comment(line: &str) -> &str {
+    let mut quote: Option<char> = None;
+    let mut escaped = false;
+    for (i, ch) in line.char_indices() {
+        match (quote, ch) {
+            (Some(_), '\\') if !escaped => { escaped = true; continue; }
+            (Some(q), c) if c == q && !escaped => quote = None,
+            (None, '"' | '\'') => quote = Some(ch),
+            (None, ';') => return &line[..i],
+            _ => {}
+        }
+        escaped = false;
+    }
+    line
+}
+
+/// Splits a leading `label:` off the line.
+fn split_label(line: &str) -> Option<(&str, &str)> {
+    let (label, rest) = line.split_once(':')?;
+    if label.contains(|c: char| c.is_whitespace() || c == '"' || c == '\'') {
+        return None;
+    }
+    Some((label, rest))
+}
+
+/// Splits the operands at the commas which are outside of strings and char literals.
+fn split_operands(text: &str) -> Result<Vec<String>, String> {
+    let mut operands = Vec::new();
+    if text.is_empty() {
+        return Ok(operands);
+    }
+
+    let mut current = String::new();
+    let mut quote: Option<char> = None;
+    let mut escaped = false;
+    for ch in text.chars() {
+        match (quote, ch) {
+            (Some(_), '\\') if !escaped => { escaped = true; current.push(ch); continue; }
+            (Some(q), c) if c == q && !escaped => quote = None,
+            (None, '"' | '\'') => quote = Some(ch),
+            (None, ',') => {
+                operands.push(current.trim().to_string());
+                current.clear();
+                continue;
+            }
+            _ => {}
+        }
+        escaped = false;
+        current.push(ch);
+    }
+    if quote.is_some() {
+        return Err(String::from("unterminated string"));
+    }
+    operands.push(current.trim().to_string());
+
+    if operands.iter().any(|op| op.is_empty()) {
+        return Err(String::from("empty operand"));
+    }
+    Ok(operands)
+}
+
+fn is_identifier(text: &str) -> bool {
+    let mut chars = text.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_' || c == '.')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(source: &str) -> String {
+        Assembler::assemble(source).expect_err("the source shouldn't assemble")
+    }
+
+    #[test]
+    fn encodes_the_operands_in_encoding_order() {
+        let source = "
+start:  LDI r0, 'H'         ; a comment
+        OUT r0, [r1]
+        ADD r0, r1
+        STR r0, r1
+        JMP NZ, Start
+        HLT";
+        assert_eq!(Assembler::assemble(source).unwrap(), [0x04, 0x48, 0x1C, 0x01, 0x41, 0xD1, 0xEC, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn jmpr_encodes_the_distance_from_the_next_instruction() {
+        assert_eq!(Assembler::assemble("loop: NOP\nJMPR NZ, loop").unwrap(), [0x37, 0xFC, 0xFD]);
+        assert_eq!(Assembler::assemble("JMPR end\nNOP\nend: HLT").unwrap(), [0xF0, 0x01, 0x37, 0x00]);
+    }
+
+    #[test]
+    fn org_fills_the_gap_with_zeros() {
+        let image = Assembler::assemble(".org 2\ntext: .byte \"HI\", 0, text+1").unwrap();
+        assert_eq!(image, [0, 0, b'H', b'I', 0, 3]);
+    }
+
+    #[test]
+    fn banks_go_where_they_are_in_the_image() {
+        let image = Assembler::assemble("JMP far\n.banks 0x40, 1\n.bank 3\nfar: LDI r0, 1").unwrap();
+        assert_eq!(image.len(), 0x182);
+        assert_eq!(image[..2], [0xE0, 0xC0]);
+        assert_eq!(image[0x180..], [0x04, 0x01]);
+    }
+
+    #[test]
+    fn lpc_takes_every_register() {
+        assert_eq!(Assembler::assemble("LPC r0\nLPC r3").unwrap(), [0x38, 0x3B]);
+        assert_eq!(error("CLF"), "line 1: unknown mnemonic 'CLF'");
+    }
+
+    #[test]
+    fn the_stack_extension_takes_encodings() {
+        let stack = Extensions { stack: true, ..Extensions::default() };
+        assert_eq!(Assembler::assemble("OR r1, r1\nSTR r2, r2").unwrap(), [0xA5, 0xDA]);
+
+        let error = |source| Assembler::assemble_for(source, stack).expect_err("the source shouldn't assemble");
+        assert_eq!(error("NOP\nor r1, r1"), "line 2: OR r1, r1 has the encoding of XSP with the stack extension");
+        assert!(error("STR r2, r2").contains("POP"));
+        assert!(error("MOV r0, r0").contains("PUSH"));
+        assert!(error("CMN r1, r1").contains("RET"));
+        assert_eq!(Assembler::assemble_for("OR r1, r2\nXSP r1\nCALL 0\nRET", stack).unwrap(), [0xA6, 0xA5, 0x60, 0x00, 0x65]);
+    }
+
+    #[test]
+    fn reports_the_line_of_an_error() {
+        assert_eq!(error("NOP\nJMP nowhere"), "line 2: undefined label 'nowhere'");
+        assert!(error("a: NOP\na: NOP").contains("already defined"));
+        assert!(error(".byte 256").contains("doesn't fit in a byte"));
+        assert!(error("NOP\n.org 0\nHLT").starts_with("line 3: overwrites"));
+        assert!(error(".org 0xFF\nLDI r0, 1").contains("byte limit"));
+    }
+}
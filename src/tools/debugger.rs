@@ -0,0 +1,563 @@
+use std::collections::BTreeSet;
+use std::io::{stdin, stdout, Write};
+use std::path::Path;
+use owo_colors::OwoColorize;
+use crate::helium::cpu::{Flag, StackFault, CPU};
+use crate::helium::isa::Instruction;
+use crate::helium::memory::{WatchHit, WatchKind};
+use crate::snapshot::Snapshot;
+use crate::tools::disassembler;
+use crate::tools::history::History;
+
+const HELP: &str = "\
+Commands:
+  s, step [n]          execute n instructions (default 1)
+  n, next              run until the instruction after the current one (steps over calls, jumps and interrupts)
+  c, continue          run until a breakpoint is hit
+  bs, back [n]         step back n instructions (default 1)
+  rc, rcontinue        run backwards until a breakpoint is hit
+  rw, rwatch <addr>    run backwards until the instruction which changed the address
+  b, break <addr>      set a breakpoint
+  d, delete [addr]     remove a breakpoint (or all of them)
+  bl, breakpoints      list the breakpoints
+  r, regs              print the registers and flags
+  set <name> <value>   set r0-r3, pc, sc, ia, sp or a flag (ze, si, ca, ov)
+  w, watch <addr> [k]  stop after a read (r), write (w, default) or value change (c) of the address
+  unwatch <addr> [k]   remove the watchpoints (of the given kind) from the address
+  wl, watchpoints      list the watchpoints
+  x, peek <addr> [len] print memory
+  poke <addr> <byte>.. write memory (writes into the ROM get rejected)
+  dis [addr] [count]   disassemble (default: from the PC)
+  save <file>          save a snapshot of the machine
+  load <file>          restore a snapshot
+  q, quit              stop the CPU
+An empty line repeats the last command.
+After stepping back, s, n and c replay the recorded instructions until they run out.
+Stepping back doesn't undo what left the machine, like disk sectors written into the image file.
+Changing the state with set, poke or load forgets the history.";
+
+/// Something that controls the run loop, it gets asked before every instruction and after the CPU halts.
+/// Implemented by the interactive debugger and the GDB stub.
+pub trait Supervisor {
+    /// Has to be called before every instruction, blocks while the CPU is stopped.
+    /// The CPU gets turned off if the user quits.
+    fn before_step(&mut self, cpu: &mut CPU);
+
+    /// Has to be called right after every instruction.
+    fn after_step(&mut self, _cpu: &mut CPU) {}
+
+    /// Has to be called after the CPU halted.
+    fn after_halt(&mut self, cpu: &mut CPU);
+}
+
+/// What the debugger waits for before stopping the CPU again.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum RunMode {
+    /// Waiting for commands.
+    Paused,
+    /// Executes the given amount of instructions.
+    Step(u32),
+    /// Runs until the PC reaches the address (or a breakpoint).
+    Until(u8),
+    /// Runs until a breakpoint.
+    Continue,
+}
+
+/// An interactive step debugger which wraps the run loop,
+/// before every instruction it decides if the CPU has to stop, while stopped it reads commands from stdin.
+#[derive(Debug)]
+pub struct Debugger {
+    breakpoints: BTreeSet<u8>,
+    mode: RunMode,
+    /// The PC the CPU was resumed at, so a breakpoint there doesn't stop it again immediately.
+    resumed_at: Option<u8>,
+    last_command: String,
+    quit: bool,
+    history: History,
+}
+
+impl Debugger {
+    /// Creates a debugger which stops before the first instruction,
+    /// it can step back over the last `history` instructions.
+    pub fn new(breakpoints: &[u8], history: usize) -> Self {
+        Self {
+            breakpoints: breakpoints.iter().copied().collect(),
+            mode: RunMode::Paused,
+            resumed_at: None,
+            last_command: String::new(),
+            quit: false,
+            history: History::new(history),
+        }
+    }
+
+    /// Reads and executes commands until the CPU gets resumed.
+    fn prompt(&mut self, cpu: &mut CPU) {
+        if cpu.is_on {
+            self.print_location(cpu);
+        }
+
+        while self.mode == RunMode::Paused && !self.quit {
+            print!("{} ", "(hdb)".bold().green());
+            stdout().flush().expect("Failed to flush stdout");
+
+            let mut line = String::new();
+            match stdin().read_line(&mut line) {
+                Ok(0) | Err(_) => {
+                    // stdin closed, nothing can resume us anymore.
+                    self.quit(cpu);
+                    return;
+                }
+                Ok(_) => {}
+            }
+
+            let mut line = line.trim().to_string();
+            if line.is_empty() {
+                line = self.last_command.clone();
+            } else {
+                self.last_command = line.clone();
+            }
+
+            if let Err(msg) = self.execute(&line, cpu) {
+                println!("{}", msg.red());
+            }
+        }
+        self.resumed_at = Some(cpu.program_counter());
+    }
+
+    /// Executes a single command.
+    fn execute(&mut self, line: &str, cpu: &mut CPU) -> Result<(), String> {
+        let mut words = line.split_whitespace();
+        let Some(command) = words.next() else { return Ok(()) };
+        let args: Vec<&str> = words.collect();
+
+        if matches!(command, "s" | "step" | "n" | "next" | "c" | "continue") && !cpu.is_on {
+            return Err(String::from("The CPU is halted, use quit to exit"));
+        }
+
+        match command {
+            "s" | "step" => {
+                let count = match args.first() {
+                    Some(arg) => arg.parse::<u32>().map_err(|_| format!("invalid count '{}'", arg))?,
+                    None => 1,
+                };
+                let mut left = count;
+                while left > 0 && self.history.redo(cpu)? {
+                    left -= 1;
+                    if !cpu.is_on {
+                        println!("{} at {:02X}", "CPU halted".bright_red(), cpu.program_counter());
+                        return Ok(());
+                    }
+                }
+
+                if left > 0 {
+                    self.mode = RunMode::Step(left);
+                } else {
+                    self.print_location(cpu);
+                }
+            }
+            "n" | "next" => {
+                let pc = cpu.program_counter();
+                let size = Instruction::decode_with(cpu.memory.get(pc), cpu.extensions()).map_or(1, |i| i.size());
+                let target = pc.wrapping_add(size);
+
+                if !self.replay(cpu, |cpu| cpu.program_counter() == target)? {
+                    self.mode = RunMode::Until(target);
+                }
+            }
+            "c" | "continue" => {
+                if !self.replay(cpu, |_| false)? {
+                    self.mode = RunMode::Continue;
+                }
+            }
+
+            "bs" | "back" => {
+                let count = match args.first() {
+                    Some(arg) => arg.parse::<u32>().map_err(|_| format!("invalid count '{}'", arg))?,
+                    None => 1,
+                };
+                for _ in 0..count {
+                    if !self.history.undo(cpu)? {
+                        println!("Reached the start of the history");
+                        break;
+                    }
+                }
+                self.print_location(cpu);
+            }
+            "rc" | "rcontinue" => loop {
+                if !self.history.undo(cpu)? {
+                    println!("Reached the start of the history");
+                    self.print_location(cpu);
+                    break;
+                }
+                if self.breakpoints.contains(&cpu.program_counter()) {
+                    println!("{} at {:02X}", "Breakpoint hit".bright_red(), cpu.program_counter());
+                    self.print_location(cpu);
+                    break;
+                }
+            },
+            "rw" | "rwatch" => {
+                let address = parse_byte(args.first().copied())?;
+                loop {
+                    if !self.history.undo(cpu)? {
+                        println!("Reached the start of the history");
+                        break;
+                    }
+                    let written = cpu.memory.write_address(address).and_then(|physical| self.history.undone_write(physical));
+                    if let Some((old, new)) = written {
+                        println!("{:02X} was changed from {:02X} to {:02X} by:", address, old, new);
+                        break;
+                    }
+                }
+                self.print_location(cpu);
+            }
+
+            "b" | "break" => {
+                let address = parse_byte(args.first().copied())?;
+                self.breakpoints.insert(address);
+                println!("Breakpoint set at {:02X}", address);
+            }
+            "d" | "delete" => match args.first() {
+                Some(arg) => {
+                    let address = parse_byte(Some(arg))?;
+                    if !self.breakpoints.remove(&address) {
+                        return Err(format!("No breakpoint at {:02X}", address));
+                    }
+                }
+                None => self.breakpoints.clear(),
+            },
+            "bl" | "breakpoints" => {
+                if self.breakpoints.is_empty() {
+                    println!("No breakpoints");
+                }
+                for address in &self.breakpoints {
+                    println!("{:02X}: {}", address, Self::disassemble(cpu, *address).0);
+                }
+            }
+
+            "w" | "watch" => {
+                let address = parse_byte(args.first().copied())?;
+                let kind = parse_watch_kind(args.get(1).copied().unwrap_or("w"))?;
+                cpu.memory.add_watchpoint(address, kind);
+                println!("Watchpoint ({}) set on {:02X}", watch_kind_name(kind), address);
+            }
+            "unwatch" => {
+                let address = parse_byte(args.first().copied())?;
+                match args.get(1) {
+                    Some(kind) => {
+                        if !cpu.memory.remove_watchpoint(address, parse_watch_kind(kind)?) {
+                            return Err(format!("No such watchpoint on {:02X}", address));
+                        }
+                    }
+                    None => cpu.memory.clear_watchpoints(address),
+                }
+            }
+            "wl" | "watchpoints" => {
+                if cpu.memory.watchpoints().is_empty() {
+                    println!("No watchpoints");
+                }
+                for (address, kind) in cpu.memory.watchpoints() {
+                    println!("{:02X}: {}", address, watch_kind_name(*kind));
+                }
+            }
+
+            "r" | "regs" => Self::print_registers(cpu),
+            "set" => {
+                let name = args.first().ok_or("set needs a name and a value")?;
+                let value = parse_byte(args.get(1).copied())?;
+                Self::set(cpu, name, value)?;
+                self.history.clear();
+            }
+
+            "x" | "peek" => {
+                let address = parse_byte(args.first().copied())?;
+                let len = match args.get(1) {
+                    Some(arg) => arg.parse::<usize>().map_err(|_| format!("invalid length '{}'", arg))?,
+                    None => 16,
+                };
+                Self::print_memory(cpu, address, len);
+            }
+            "poke" => {
+                let address = parse_byte(args.first().copied())?;
+                if args.len() < 2 {
+                    return Err(String::from("poke needs an address and at least one byte"));
+                }
+                self.history.clear();
+                for (offset, arg) in args[1..].iter().enumerate() {
+                    let target = address.wrapping_add(offset as u8);
+                    let accepted = cpu.memory.set(target, parse_byte(Some(arg))?);
+                    // Our own writes are not something to stop on.
+                    cpu.memory.take_watch_hits();
+
+                    if !accepted {
+                        return Err(format!("Write to {:02X} rejected, it's not writable memory", target));
+                    }
+                }
+            }
+            "dis" => {
+                let mut address = match args.first() {
+                    Some(arg) => parse_byte(Some(arg))?,
+                    None => cpu.program_counter(),
+                };
+                let count = match args.get(1) {
+                    Some(arg) => arg.parse::<usize>().map_err(|_| format!("invalid count '{}'", arg))?,
+                    None => 8,
+                };
+                for _ in 0..count {
+                    let (text, size) = Self::disassemble(cpu, address);
+                    println!("{}", Self::location_line(cpu, address, &text));
+                    address = address.wrapping_add(size);
+                }
+            }
+
+            "save" => {
+                let path = args.first().ok_or("missing file name")?;
+                Snapshot::capture(cpu).save(Path::new(path))?;
+                println!("Snapshot saved to {}", path);
+            }
+            "load" => {
+                let path = args.first().ok_or("missing file name")?;
+                Snapshot::load(Path::new(path))?.restore(cpu)?;
+                self.history.clear();
+                self.print_location(cpu);
+            }
+
+            "q" | "quit" => self.quit(cpu),
+            "h" | "help" => println!("{}", HELP),
+            _ => return Err(format!("Unknown command '{}', try help", command)),
+        }
+        Ok(())
+    }
+
+    /// Redoes recorded instructions until `stop` returns true, a breakpoint is hit or the CPU halts.
+    /// Returns false if the history ran out, then the rest has to be executed.
+    fn replay(&mut self, cpu: &mut CPU, stop: impl Fn(&CPU) -> bool) -> Result<bool, String> {
+        if !self.history.can_redo() {
+            return Ok(false);
+        }
+
+        while self.history.redo(cpu)? {
+            let pc = cpu.program_counter();
+
+            if !cpu.is_on {
+                println!("{} at {:02X}", "CPU halted".bright_red(), pc);
+                return Ok(true);
+            }
+            if self.breakpoints.contains(&pc) {
+                println!("{} at {:02X}", "Breakpoint hit".bright_red(), pc);
+                self.print_location(cpu);
+                return Ok(true);
+            }
+            if stop(cpu) {
+                self.print_location(cpu);
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Stops the CPU and leaves the prompt.
+    fn quit(&mut self, cpu: &mut CPU) {
+        self.quit = true;
+        cpu.is_on = false;
+    }
+
+    /// Tells the user which instruction triggered a watchpoint.
+    fn report_hit(cpu: &CPU, hit: &WatchHit) {
+        let address = cpu.instruction_addr();
+        let (text, _) = Self::disassemble(cpu, address);
+
+        let access = match hit.kind {
+            WatchKind::Read => format!("read {:02X}", hit.new_value),
+            _ => format!("{:02X} -> {:02X}", hit.old_value, hit.new_value),
+        };
+
+        println!("{} ({}) on {:02X}: {}, by {:02X}: {}",
+                 "Watchpoint hit".bright_red(), watch_kind_name(hit.kind), hit.address, access, address, text);
+    }
+
+    /// Tells the user which instruction overflowed or underflowed the stack.
+    fn report_stack_fault(cpu: &CPU, fault: &StackFault) {
+        let address = cpu.instruction_addr();
+        let (text, _) = Self::disassemble(cpu, address);
+
+        let problem = match fault {
+            StackFault::Overflow { address } => format!("Stack overflow, the push to {:02X} hit the ROM", address),
+            StackFault::Underflow => String::from("Stack underflow, popped from an empty stack"),
+        };
+        println!("{}, by {:02X}: {}", problem.bright_red(), address, text);
+    }
+
+    /// Prints the instruction at the PC.
+    fn print_location(&self, cpu: &CPU) {
+        let pc = cpu.program_counter();
+        let (text, _) = Self::disassemble(cpu, pc);
+        println!("{}", Self::location_line(cpu, pc, &text));
+    }
+
+    /// Formats a disassembled line, marking breakpoints and the PC.
+    fn location_line(cpu: &CPU, address: u8, text: &str) -> String {
+        let marker = if address == cpu.program_counter() { "=>" } else { "  " };
+        format!("{} {:02X}: {}", marker.bright_green(), address.bold(), text)
+    }
+
+    /// Returns the disassembled text and the size of the instruction at the address.
+    fn disassemble(cpu: &CPU, address: u8) -> (String, u8) {
+        let line = disassembler::disassemble_at(address, cpu.extensions(), |addr| Some(cpu.memory.get(addr)));
+        (line.text, line.bytes.len() as u8)
+    }
+
+    fn print_registers(cpu: &CPU) {
+        println!("r0: {:02X}  r1: {:02X}  r2: {:02X}  r3: {:02X}",
+                 cpu.register(0), cpu.register(1), cpu.register(2), cpu.register(3));
+        println!("PC: {:02X}  SC: {:02X}  IA: {:02X}  IR: {:02X}",
+                 cpu.program_counter(), cpu.secondary_counter(), cpu.interrupt_addr(), cpu.instruction_reg());
+        if cpu.extensions().stack {
+            println!("SP: {:02X}", cpu.stack_pointer());
+        }
+        if let Some(depth) = cpu.extensions().interrupt_nesting {
+            println!("Interrupt level: {}/{}", cpu.interrupt_level(), depth);
+        }
+        if cpu.memory.banks().is_some() {
+            println!("Banks: {:?}", cpu.memory.selected_banks());
+        }
+        if cpu.memory.rom_control().is_some() {
+            println!("ROM: {}", if cpu.memory.rom_mapped() { "mapped" } else { "unmapped" });
+        }
+        println!("ZE: {}  SI: {}  CA: {}  OV: {}",
+                 cpu.flag(Flag::Zero) as u8, cpu.flag(Flag::Signed) as u8,
+                 cpu.flag(Flag::Carry) as u8, cpu.flag(Flag::Overflow) as u8);
+    }
+
+    fn print_memory(cpu: &CPU, address: u8, len: usize) {
+        for row in 0..len.div_ceil(8) {
+            let start = address.wrapping_add((row * 8) as u8);
+            let bytes = (0..8.min(len - row * 8))
+                .map(|i| format!("{:02X}", cpu.memory.get(start.wrapping_add(i as u8))))
+                .collect::<Vec<String>>()
+                .join(" ");
+            println!("{:02X}: {}", start.bold(), bytes);
+        }
+    }
+
+    /// Sets a register or a flag by name.
+    fn set(cpu: &mut CPU, name: &str, value: u8) -> Result<(), String> {
+        match name.to_ascii_lowercase().as_str() {
+            "r0" => cpu.set_register(0, value),
+            "r1" => cpu.set_register(1, value),
+            "r2" => cpu.set_register(2, value),
+            "r3" => cpu.set_register(3, value),
+            "pc" => cpu.set_program_counter(value),
+            "sc" => cpu.set_secondary_counter(value),
+            "ia" => cpu.set_interrupt_addr(value),
+            "sp" => cpu.set_stack_pointer(value),
+            "ze" => cpu.set_flag(Flag::Zero, value != 0),
+            "si" => cpu.set_flag(Flag::Signed, value != 0),
+            "ca" => cpu.set_flag(Flag::Carry, value != 0),
+            "ov" => cpu.set_flag(Flag::Overflow, value != 0),
+            _ => return Err(format!("Unknown register or flag '{}'", name)),
+        }
+        Ok(())
+    }
+}
+
+impl Supervisor for Debugger {
+    fn before_step(&mut self, cpu: &mut CPU) {
+        let pc = cpu.program_counter();
+        let resumed_at = self.resumed_at.take();
+
+        // Hits of the previous instruction
+        let hits = cpu.memory.take_watch_hits();
+        for hit in &hits {
+            Self::report_hit(cpu, hit);
+        }
+        if !hits.is_empty() {
+            self.mode = RunMode::Paused;
+        }
+
+        let faults = cpu.take_stack_faults();
+        for fault in &faults {
+            Self::report_stack_fault(cpu, fault);
+        }
+        if !faults.is_empty() {
+            self.mode = RunMode::Paused;
+        }
+
+        let faults = cpu.take_faults();
+        for fault in &faults {
+            println!("{} {}", "Fault:".bright_red(), fault);
+        }
+        if !faults.is_empty() {
+            self.mode = RunMode::Paused;
+        }
+
+        if self.breakpoints.contains(&pc) && resumed_at != Some(pc) && self.mode != RunMode::Paused {
+            println!("{} at {:02X}", "Breakpoint hit".bright_red(), pc);
+            self.mode = RunMode::Paused;
+        }
+
+        match self.mode {
+            RunMode::Step(0) => self.mode = RunMode::Paused,
+            RunMode::Until(address) if address == pc => self.mode = RunMode::Paused,
+            _ => {}
+        }
+
+        if self.mode == RunMode::Paused {
+            self.prompt(cpu);
+        }
+
+        if let RunMode::Step(left) = self.mode {
+            self.mode = RunMode::Step(left - 1);
+        }
+
+        if cpu.is_on {
+            self.history.before_step(cpu);
+        }
+    }
+
+    fn after_step(&mut self, cpu: &mut CPU) {
+        self.history.after_step(cpu);
+    }
+
+    /// Allows inspecting the final state.
+    fn after_halt(&mut self, cpu: &mut CPU) {
+        println!("{} at {:02X}", "CPU halted".bright_red(), cpu.program_counter());
+        if let Some(fault) = cpu.halt_fault() {
+            println!("{} {}", "Halted by a fault:".bright_red(), fault);
+        }
+        self.mode = RunMode::Paused;
+        self.prompt(cpu);
+    }
+}
+
+fn parse_watch_kind(text: &str) -> Result<WatchKind, String> {
+    match text {
+        "r" | "read" => Ok(WatchKind::Read),
+        "w" | "write" => Ok(WatchKind::Write),
+        "c" | "change" => Ok(WatchKind::Change),
+        _ => Err(format!("Unknown watchpoint kind '{}', expected r, w or c", text)),
+    }
+}
+
+fn watch_kind_name(kind: WatchKind) -> &'static str {
+    match kind {
+        WatchKind::Read => "read",
+        WatchKind::Write => "write",
+        WatchKind::Change => "change",
+        WatchKind::RomWrite => "ROM write",
+    }
+}
+
+/// Parses a byte given in decimal, 0x hex or 0b binary.
+pub fn parse_byte(text: Option<&str>) -> Result<u8, String> {
+    let text = text.ok_or("missing value")?;
+    let lower = text.to_ascii_lowercase();
+
+    let value = if let Some(hex) = lower.strip_prefix("0x") {
+        u8::from_str_radix(hex, 16)
+    } else if let Some(bin) = lower.strip_prefix("0b") {
+        u8::from_str_radix(bin, 2)
+    } else {
+        lower.parse::<u8>()
+    };
+    value.map_err(|_| format!("invalid byte '{}'", text))
+}
@@ -0,0 +1,176 @@
+use crate::helium::isa::{self, Extensions, Instruction};
+
+/// A single decoded instruction (or an undecodable byte).
+#[derive(Debug, Clone)]
+pub struct DisassembledLine {
+    pub address: u8,
+    /// The opcode and the imm8 if there is one.
+    pub bytes: Vec<u8>,
+    pub text: String,
+}
+
+/// Walks a rom image from address 0 and decodes every instruction, the imm8 operands are consumed too.
+pub fn disassemble(image: &[u8], extensions: Extensions) -> Vec<DisassembledLine> {
+    disassemble_from(image, 0, extensions)
+}
+
+/// Like disassemble, but the image is seen at the given address, like a bank in its window.
+pub fn disassemble_from(image: &[u8], start: u8, extensions: Extensions) -> Vec<DisassembledLine> {
+    let mut lines = Vec::new();
+    let mut offset: usize = 0;
+
+    while offset < image.len() {
+        let address = start.wrapping_add(offset as u8);
+        let line = disassemble_at(address, extensions, |addr| image.get(addr.wrapping_sub(start) as usize).copied());
+        offset += line.bytes.len().max(1);
+        lines.push(line);
+    }
+    lines
+}
+
+/// Decodes the instruction at the given address, bytes are fetched through the given function,
+/// None means the byte is outside the image (the imm8 of the last instruction can be cut off).
+pub fn disassemble_at<F>(address: u8, extensions: Extensions, fetch: F) -> DisassembledLine
+where F: Fn(u8) -> Option<u8> {
+    let opcode = fetch(address).unwrap_or(0);
+
+    let Some(instruction) = Instruction::decode_with(opcode, extensions) else {
+        let text = format!(".byte {:#04X}      ; {}", opcode, isa::unknown_instruction(opcode));
+        return DisassembledLine { address, bytes: vec![opcode], text };
+    };
+
+    if !instruction.has_imm8() {
+        return DisassembledLine { address, bytes: vec![opcode], text: format_instruction(instruction, 0, address) };
+    }
+
+    match fetch(address.wrapping_add(1)) {
+        Some(imm) => DisassembledLine {
+            address,
+            bytes: vec![opcode, imm],
+            text: format_instruction(instruction, imm, address),
+        },
+        None => DisassembledLine {
+            address,
+            bytes: vec![opcode],
+            text: format!(".byte {:#04X}      ; {} without its imm8", opcode, instruction.mnemonic()),
+        },
+    }
+}
+
+/// Formats the instruction in the syntax of the assembler, so the output can be assembled again.
+/// `imm` is ignored for instructions without an imm8, `address` is used to resolve JMPR targets.
+/// Register indirect forms with an index past r3 have no syntax, they become a `.byte` line.
+pub fn format_instruction(instruction: Instruction, imm: u8, address: u8) -> String {
+    let mnemonic = instruction.mnemonic();
+
+    let register_index = matches!(instruction,
+        Instruction::InReg(_) | Instruction::OutReg(_) | Instruction::Jump(_, true) | Instruction::JumpRel(_, true));
+    if register_index && imm > 3 {
+        return format!(".byte {:#04X}, {:#04X} ; {} with the register index {}", instruction.opcode(), imm, mnemonic, imm);
+    }
+
+    match instruction {
+        Instruction::LoadImm(reg)
+        | Instruction::Load(reg)
+        | Instruction::Store(reg)
+        | Instruction::In(reg)
+        | Instruction::Out(reg) => format!("{} r{}, {:#04X}", mnemonic, reg, imm),
+
+        Instruction::InReg(reg)
+        | Instruction::OutReg(reg) => format!("{} r{}, {}", mnemonic, reg, indirect(imm)),
+
+        Instruction::FlagSwap(reg)
+        | Instruction::ShiftRight(reg)
+        | Instruction::ShiftLeft(reg)
+        | Instruction::SetInterruptAddr(reg)
+        | Instruction::LoadPc(reg)
+        | Instruction::RotateRight(reg)
+        | Instruction::Push(reg)
+        | Instruction::Pop(reg)
+        | Instruction::StackSwap(reg) => format!("{} r{}", mnemonic, reg),
+
+        Instruction::Call => format!("{} {:#04X}", mnemonic, imm),
+
+        Instruction::Add(a, b)
+        | Instruction::Sub(a, b)
+        | Instruction::CompareAdd(a, b)
+        | Instruction::Compare(a, b)
+        | Instruction::And(a, b)
+        | Instruction::Xor(a, b)
+        | Instruction::Or(a, b)
+        | Instruction::Move(a, b)
+        | Instruction::LoadReg(a, b)
+        | Instruction::StoreReg(a, b) => format!("{} r{}, r{}", mnemonic, a, b),
+
+        Instruction::Jump(condition, is_reg)
+        | Instruction::JumpRel(condition, is_reg) => {
+            let target = if is_reg {
+                indirect(imm)
+            } else if let Instruction::JumpRel(..) = instruction {
+                format!("{:#04X}", address.wrapping_add(2).wrapping_add(imm))
+            } else {
+                format!("{:#04X}", imm)
+            };
+
+            match condition {
+                isa::Condition::Always => format!("{} {}", mnemonic, target),
+                _ => format!("{} {}, {}", mnemonic, condition.mnemonic(), target),
+            }
+        }
+
+        _ => mnemonic.to_string(),
+    }
+}
+
+/// The imm8 of the register indirect forms is a register index.
+fn indirect(index: u8) -> String {
+    format!("[r{}]", index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tools::assembler::Assembler;
+
+    fn reassemble(image: &[u8], extensions: Extensions) -> Vec<u8> {
+        let source = disassemble(image, extensions).into_iter()
+            .map(|line| line.text)
+            .collect::<Vec<String>>()
+            .join("\n");
+        Assembler::assemble(&source).unwrap_or_else(|e| panic!("{} in:\n{}", e, source))
+    }
+
+    #[test]
+    fn prints_the_syntax_of_the_assembler() {
+        let image = Assembler::assemble("loop: LDI r0, 1\nOUT r0, [r1]\nJMPR NZ, loop\nHLT").unwrap();
+        let text = disassemble(&image, Extensions::default()).into_iter().map(|line| line.text).collect::<Vec<String>>();
+        assert_eq!(text, ["LDI r0, 0x01", "OUT r0, [r1]", "JMPR NZ, 0x00", "HLT"]);
+    }
+
+    #[test]
+    fn every_opcode_assembles_again() {
+        let stack = Extensions { stack: true, ..Extensions::default() };
+
+        for extensions in [Extensions::default(), stack] {
+            for opcode in 0..=255u8 {
+                for imm in [0x00, 0x02, 0x07, 0xFE] {
+                    assert_eq!(reassemble(&[opcode, imm], extensions), [opcode, imm], "opcode {:#04X}", opcode);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn a_cut_off_imm8_stays_a_byte() {
+        let lines = disassemble(&[0x04], Extensions::default());
+        assert_eq!(lines[0].bytes, [0x04]);
+        assert_eq!(reassemble(&[0x04], Extensions::default()), [0x04]);
+    }
+
+    #[test]
+    fn disassemble_from_sees_the_image_at_the_start() {
+        let lines = disassemble_from(&[0xF0, 0xFE], 0xC0, Extensions::default());
+        assert_eq!(lines[0].address, 0xC0);
+        assert_eq!(lines[0].text, "JMPR 0xC0");
+    }
+}
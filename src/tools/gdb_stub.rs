@@ -0,0 +1,457 @@
+use std::collections::BTreeSet;
+use std::io::{Read, Write};
+use std::io::ErrorKind::WouldBlock;
+use std::net::{TcpListener, TcpStream};
+use crate::helium::cpu::CPU;
+use crate::helium::memory::WatchKind;
+use crate::tools::debugger::Supervisor;
+
+/// Describes the registers to GDB, the order is the one used by the g/G/p/P packets.
+/// With the stack extension [`SP_XML`] goes in front of [`TARGET_XML_END`].
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.helium.core">
+    <flags id="helium_flags" size="1">
+      <field name="ZE" start="0" end="0"/>
+      <field name="OV" start="1" end="1"/>
+      <field name="CA" start="2" end="2"/>
+      <field name="SI" start="3" end="3"/>
+    </flags>
+    <reg name="r0" bitsize="8" type="uint8" regnum="0"/>
+    <reg name="r1" bitsize="8" type="uint8"/>
+    <reg name="r2" bitsize="8" type="uint8"/>
+    <reg name="r3" bitsize="8" type="uint8"/>
+    <reg name="pc" bitsize="8" type="code_ptr"/>
+    <reg name="sc" bitsize="8" type="code_ptr"/>
+    <reg name="ia" bitsize="8" type="code_ptr"/>
+    <reg name="flags" bitsize="8" type="helium_flags"/>
+"#;
+const SP_XML: &str = r#"    <reg name="sp" bitsize="8" type="data_ptr"/>
+"#;
+const TARGET_XML_END: &str = r#"  </feature>
+</target>
+"#;
+
+/// The amount of registers in the target description, without SP.
+const REGISTER_COUNT: usize = 8;
+
+/// Reported for every stop, SIGTRAP.
+const STOP_REPLY: &str = "S05";
+
+/// The state of the target as seen by GDB.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum State {
+    /// GDB is in control, packets are handled before anything runs.
+    Stopped,
+    /// Runs a single instruction and then stops.
+    Stepping,
+    /// Runs until a breakpoint, a halt or a break (Ctrl+C) from GDB.
+    Running,
+    /// GDB detached or the connection died, the CPU runs freely.
+    Detached,
+}
+
+/// A stub for the GDB remote serial protocol, it lets gdb/lldb control the CPU over a TCP socket.
+///
+/// # Registers (target.xml):
+/// 0-3: r0 - r3, 4: pc, 5: sc (secondary counter), 6: ia (interrupt address),
+/// 7: flags (packed like FSWAP does it, 0b0000_SCOZ), 8: sp (only with the stack extension).
+///
+/// Supports reading and writing registers and memory, single stepping, continuing,
+/// software/hardware breakpoints (Z0/Z1), write/read/access watchpoints (Z2/Z3/Z4), detaching and killing.
+#[derive(Debug)]
+pub struct GdbStub {
+    stream: TcpStream,
+    state: State,
+    breakpoints: BTreeSet<u8>,
+    /// Addresses with an access watchpoint (Z4), those are a read and a write watchpoint in the memory.
+    access_watchpoints: BTreeSet<u8>,
+    /// The PC the CPU was resumed at, so a breakpoint there doesn't stop it again immediately.
+    resumed_at: Option<u8>,
+}
+
+impl GdbStub {
+    /// Listens on 127.0.0.1:port and blocks until GDB connects.
+    pub fn listen(port: u16) -> Result<Self, String> {
+        let listener = TcpListener::bind(format!("127.0.0.1:{}", port))
+            .map_err(|e| format!("Failed to bind the GDB port: {}", e))?;
+
+        println!("Waiting for GDB on 127.0.0.1:{} (target remote :{})", port, port);
+        let (stream, addr) = listener.accept()
+            .map_err(|e| format!("Failed to accept the GDB connection: {}", e))?;
+        println!("GDB connected from {}", addr);
+
+        stream.set_nodelay(true).map_err(|e| format!("Failed to configure the GDB socket: {}", e))?;
+
+        Ok(Self {
+            stream,
+            state: State::Stopped,
+            breakpoints: BTreeSet::new(),
+            access_watchpoints: BTreeSet::new(),
+            resumed_at: None,
+        })
+    }
+
+    /// Handles packets until GDB resumes the CPU.
+    fn serve(&mut self, cpu: &mut CPU) {
+        while self.state == State::Stopped {
+            let Some(packet) = self.read_packet() else {
+                self.state = State::Detached;
+                return;
+            };
+
+            if let Some(reply) = self.handle(&packet, cpu) {
+                self.send(&reply);
+            }
+        }
+        self.resumed_at = Some(cpu.program_counter());
+    }
+
+    /// Handles a single packet, returns the reply (None if the reply comes later, like for continue).
+    fn handle(&mut self, packet: &str, cpu: &mut CPU) -> Option<String> {
+        let (command, args) = packet.split_at(packet.chars().next().map_or(0, char::len_utf8));
+
+        let reply = match command {
+            "?" => STOP_REPLY.to_string(),
+
+            "g" => (0..Self::register_count(cpu))
+                .map(|reg| format!("{:02x}", Self::read_register(cpu, reg)))
+                .collect(),
+            "G" => match decode_hex(args) {
+                Some(values) if values.len() == Self::register_count(cpu) => {
+                    for (reg, value) in values.into_iter().enumerate() {
+                        Self::write_register(cpu, reg, value);
+                    }
+                    "OK".to_string()
+                }
+                _ => "E01".to_string(),
+            },
+            "p" => match usize::from_str_radix(args, 16) {
+                Ok(reg) if reg < Self::register_count(cpu) => format!("{:02x}", Self::read_register(cpu, reg)),
+                _ => "E01".to_string(),
+            },
+            "P" => {
+                let count = Self::register_count(cpu);
+                let parsed = args.split_once('=').and_then(|(reg, value)| {
+                    let reg = usize::from_str_radix(reg, 16).ok()?;
+                    let value = decode_hex(value)?;
+                    (reg < count && value.len() == 1).then_some((reg, value[0]))
+                });
+                match parsed {
+                    Some((reg, value)) => {
+                        Self::write_register(cpu, reg, value);
+                        "OK".to_string()
+                    }
+                    None => "E01".to_string(),
+                }
+            }
+
+            "m" => match parse_addr_len(args) {
+                Some((addr, len)) => (addr..(addr + len).min(256))
+                    .map(|a| format!("{:02x}", cpu.memory.get(a as u8)))
+                    .collect(),
+                None => "E01".to_string(),
+            },
+            "M" => {
+                let parsed = args.split_once(':')
+                    .and_then(|(range, data)| Some((parse_addr_len(range)?, decode_hex(data)?)));
+                match parsed {
+                    Some(((addr, len), data)) if data.len() == len && addr + len <= 256 => {
+                        // Writes into the ROM are rejected by the memory.
+                        let accepted = data.iter().enumerate()
+                            .all(|(offset, byte)| cpu.memory.set((addr + offset) as u8, *byte));
+                        // GDB's own writes don't trigger watchpoints.
+                        cpu.memory.take_watch_hits();
+
+                        if accepted { "OK".to_string() } else { "E02".to_string() }
+                    }
+                    _ => "E01".to_string(),
+                }
+            }
+
+            "s" => {
+                self.state = State::Stepping;
+                return None;
+            }
+            "c" => {
+                self.state = State::Running;
+                return None;
+            }
+
+            "Z" | "z" => {
+                let mut parts = args.split(',');
+                let kind = parts.next();
+                let addr = parts.next().and_then(|a| usize::from_str_radix(a, 16).ok());
+
+                match (kind, addr) {
+                    (Some("0" | "1"), Some(addr)) if addr < 256 => {
+                        if command == "Z" {
+                            self.breakpoints.insert(addr as u8);
+                        } else {
+                            self.breakpoints.remove(&(addr as u8));
+                        }
+                        "OK".to_string()
+                    }
+                    (Some(kind @ ("2" | "3" | "4")), Some(addr)) if addr < 256 => {
+                        let addr = addr as u8;
+                        let kinds: &[WatchKind] = match kind {
+                            "2" => &[WatchKind::Write],
+                            "3" => &[WatchKind::Read],
+                            _ => &[WatchKind::Read, WatchKind::Write],
+                        };
+
+                        for kind in kinds {
+                            if command == "Z" {
+                                cpu.memory.add_watchpoint(addr, *kind);
+                            } else {
+                                cpu.memory.remove_watchpoint(addr, *kind);
+                            }
+                        }
+                        if kind == "4" && command == "Z" {
+                            self.access_watchpoints.insert(addr);
+                        } else if kind == "4" {
+                            self.access_watchpoints.remove(&addr);
+                        }
+                        "OK".to_string()
+                    }
+                    _ => String::new(),
+                }
+            }
+
+            "k" => {
+                cpu.is_on = false;
+                self.state = State::Detached;
+                return None;
+            }
+            "D" => {
+                self.send("OK");
+                self.state = State::Detached;
+                return None;
+            }
+
+            "H" => "OK".to_string(),
+            "q" => Self::handle_query(args, cpu),
+
+            // Unsupported packets get an empty reply.
+            _ => String::new(),
+        };
+        Some(reply)
+    }
+
+    /// Handles the q packets.
+    fn handle_query(query: &str, cpu: &CPU) -> String {
+        if query.starts_with("Supported") {
+            return "PacketSize=400;qXfer:features:read+;swbreak+;hwbreak+".to_string();
+        }
+        if query == "Attached" {
+            return "1".to_string();
+        }
+        if query == "C" {
+            return "QC1".to_string();
+        }
+        if query == "fThreadInfo" {
+            return "m1".to_string();
+        }
+        if query == "sThreadInfo" {
+            return "l".to_string();
+        }
+        if let Some(range) = query.strip_prefix("Xfer:features:read:target.xml:") {
+            let Some((offset, len)) = parse_addr_len(range) else { return "E01".to_string() };
+            let xml = Self::target_xml(cpu);
+            let xml = xml.as_bytes();
+
+            let start = offset.min(xml.len());
+            let end = (offset + len).min(xml.len());
+            let prefix = if end == xml.len() { 'l' } else { 'm' };
+
+            return format!("{}{}", prefix, escape_binary(&xml[start..end]));
+        }
+        String::new()
+    }
+
+    /// SP is only there with the stack extension.
+    fn register_count(cpu: &CPU) -> usize {
+        REGISTER_COUNT + cpu.extensions().stack as usize
+    }
+
+    fn target_xml(cpu: &CPU) -> String {
+        let sp = if cpu.extensions().stack { SP_XML } else { "" };
+        format!("{}{}{}", TARGET_XML, sp, TARGET_XML_END)
+    }
+
+    fn read_register(cpu: &CPU, reg: usize) -> u8 {
+        match reg {
+            0..=3 => cpu.register(reg),
+            4 => cpu.program_counter(),
+            5 => cpu.secondary_counter(),
+            6 => cpu.interrupt_addr(),
+            7 => cpu.flags_into_u8(),
+            8 => cpu.stack_pointer(),
+            _ => 0,
+        }
+    }
+
+    fn write_register(cpu: &mut CPU, reg: usize, value: u8) {
+        match reg {
+            0..=3 => cpu.set_register(reg, value),
+            4 => cpu.set_program_counter(value),
+            5 => cpu.set_secondary_counter(value),
+            6 => cpu.set_interrupt_addr(value),
+            7 => cpu.flags_from_u8(value),
+            8 => cpu.set_stack_pointer(value),
+            _ => {}
+        }
+    }
+
+    /// Reads the next packet (blocking), acknowledges it and returns its content.
+    /// Returns None if the connection was closed.
+    fn read_packet(&mut self) -> Option<String> {
+        let mut byte = [0u8; 1];
+
+        // Wait for the start of a packet, acks and stray breaks are skipped.
+        loop {
+            self.stream.read_exact(&mut byte).ok()?;
+            if byte[0] == b'$' {
+                break;
+            }
+        }
+
+        let mut data = Vec::new();
+        loop {
+            self.stream.read_exact(&mut byte).ok()?;
+            if byte[0] == b'#' {
+                break;
+            }
+            data.push(byte[0]);
+        }
+
+        let mut checksum = [0u8; 2];
+        self.stream.read_exact(&mut checksum).ok()?;
+
+        let expected = std::str::from_utf8(&checksum).ok()
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        let actual = data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+
+        if expected != Some(actual) {
+            self.stream.write_all(b"-").ok()?;
+            return self.read_packet();
+        }
+        self.stream.write_all(b"+").ok()?;
+
+        Some(String::from_utf8_lossy(&data).into_owned())
+    }
+
+    /// Sends a packet, failures mean GDB is gone.
+    fn send(&mut self, data: &str) {
+        let checksum = data.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+        let packet = format!("${}#{:02x}", data, checksum);
+
+        if self.stream.write_all(packet.as_bytes()).is_err() {
+            self.state = State::Detached;
+        }
+    }
+
+    /// Checks if GDB sent a break (Ctrl+C) without blocking.
+    fn break_requested(&mut self) -> bool {
+        let mut byte = [0u8; 1];
+        if self.stream.set_nonblocking(true).is_err() {
+            return false;
+        }
+
+        let result = match self.stream.peek(&mut byte) {
+            Ok(1) if byte[0] == 0x03 => {
+                let _ = self.stream.read_exact(&mut byte);
+                true
+            }
+            Ok(0) => {
+                // Connection closed
+                self.state = State::Detached;
+                false
+            }
+            Err(e) if e.kind() == WouldBlock => false,
+            _ => false,
+        };
+
+        let _ = self.stream.set_nonblocking(false);
+        result
+    }
+}
+
+impl Supervisor for GdbStub {
+    fn before_step(&mut self, cpu: &mut CPU) {
+        let pc = cpu.program_counter();
+        let resumed_at = self.resumed_at.take();
+
+        // Watchpoint hits of the previous instruction
+        let hit = cpu.memory.take_watch_hits().into_iter().next();
+
+        let stopped_now = match self.state {
+            State::Detached => return,
+            State::Stepping => true,
+            State::Running => {
+                let on_breakpoint = self.breakpoints.contains(&pc) && resumed_at != Some(pc);
+                on_breakpoint || hit.is_some() || self.break_requested()
+            }
+            State::Stopped => false,
+        };
+
+        if stopped_now {
+            self.state = State::Stopped;
+
+            match hit {
+                Some(hit) => {
+                    let name = match hit.kind {
+                        _ if self.access_watchpoints.contains(&hit.address) => "awatch",
+                        WatchKind::Read => "rwatch",
+                        _ => "watch",
+                    };
+                    self.send(&format!("T05{}:{:02x};", name, hit.address));
+                }
+                None => self.send(STOP_REPLY),
+            }
+        }
+
+        if self.state == State::Stopped {
+            self.serve(cpu);
+        }
+    }
+
+    /// Tells GDB that the "process" exited.
+    fn after_halt(&mut self, _cpu: &mut CPU) {
+        if self.state != State::Detached {
+            self.send("W00");
+            self.state = State::Detached;
+        }
+    }
+}
+
+/// Parses the `addr,len` part of m/M/qXfer packets.
+fn parse_addr_len(text: &str) -> Option<(usize, usize)> {
+    let (addr, len) = text.split_once(',')?;
+    Some((usize::from_str_radix(addr, 16).ok()?, usize::from_str_radix(len, 16).ok()?))
+}
+
+fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Escapes the characters which have a meaning in the binary replies of qXfer.
+fn escape_binary(data: &[u8]) -> String {
+    let mut out = String::new();
+    for &byte in data {
+        if matches!(byte, b'$' | b'#' | b'}' | b'*') {
+            out.push('}');
+            out.push((byte ^ 0x20) as char);
+        } else {
+            out.push(byte as char);
+        }
+    }
+    out
+}
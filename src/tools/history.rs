@@ -0,0 +1,198 @@
+use std::collections::VecDeque;
+use crate::helium::cpu::{CpuState, CPU};
+use crate::helium::io_controller::DeviceState;
+use crate::helium::memory::MemoryState;
+
+/// What one instruction changed, enough to undo and redo it without executing it again.
+#[derive(Debug, Clone)]
+struct StepRecord {
+    before: CpuState,
+    after: CpuState,
+    /// (physical address, old, new) of every changed memory byte.
+    writes: Vec<(usize, u8, u8)>,
+    /// The selected banks before and after, only kept if the instruction switched banks.
+    banks: Option<(Vec<u8>, Vec<u8>)>,
+    /// The used write-once bytes before and after, only kept if the instruction used one.
+    written_once: Option<(Vec<usize>, Vec<usize>)>,
+    /// If the ROM was mapped before and after, only kept if the instruction used the ROM control register.
+    rom_mapped: Option<(bool, bool)>,
+    /// The device states before and after, only kept if the instruction changed them.
+    devices: Option<(Vec<DeviceState>, Vec<DeviceState>)>,
+    /// The step counter of the IO controller before and after, so input records and replays stay in line.
+    steps: (u64, u64),
+}
+
+/// A bounded undo/redo log of the executed instructions, used by the debugger to step back.
+/// Redoing applies the recorded results, so device reads (like a TelnetTerminal char) are replayed from the log
+/// instead of asking the devices again.
+///
+/// Only the machine steps back, effects outside of it stay: sectors a disk wrote into its image file,
+/// bytes sent to a terminal client and input which was written into a recording.
+#[derive(Debug)]
+pub struct History {
+    records: VecDeque<StepRecord>,
+    /// How many records are applied, less than the record count after stepping back.
+    position: usize,
+    limit: usize,
+    /// The state before the instruction which is being executed.
+    pending: Option<(CpuState, MemoryState, Vec<DeviceState>, u64)>,
+}
+
+impl History {
+    /// Creates a history which remembers the last `limit` instructions.
+    pub fn new(limit: usize) -> Self {
+        Self { records: VecDeque::new(), position: 0, limit, pending: None }
+    }
+
+    /// Has to be called right before `CPU::next`.
+    pub fn before_step(&mut self, cpu: &CPU) {
+        if self.limit != 0 {
+            self.pending = Some((cpu.save_state(), cpu.memory.save_state(), cpu.io_ctl.save_state(), cpu.io_ctl.steps()));
+        }
+    }
+
+    /// Has to be called right after `CPU::next`, records what the instruction changed.
+    /// Executing an instruction drops the steps which could have been redone.
+    pub fn after_step(&mut self, cpu: &CPU) {
+        let Some((before, memory, devices, steps)) = self.pending.take() else { return };
+
+        let memory_after = cpu.memory.save_state();
+        let writes = memory.contents.iter()
+            .zip(&memory_after.contents)
+            .enumerate()
+            .filter(|(_, (old, new))| old != new)
+            .map(|(address, (old, new))| (address, *old, *new))
+            .collect();
+        let banks = (memory.selected_banks != memory_after.selected_banks)
+            .then_some((memory.selected_banks, memory_after.selected_banks));
+        let written_once = (memory.written_once != memory_after.written_once)
+            .then_some((memory.written_once, memory_after.written_once));
+        let rom_mapped = (memory.rom_mapped != memory_after.rom_mapped)
+            .then_some((memory.rom_mapped, memory_after.rom_mapped));
+
+        let devices_after = cpu.io_ctl.save_state();
+        let devices = (devices != devices_after).then_some((devices, devices_after));
+
+        self.records.truncate(self.position);
+        self.records.push_back(StepRecord {
+            before,
+            after: cpu.save_state(),
+            writes,
+            banks,
+            written_once,
+            rom_mapped,
+            devices,
+            steps: (steps, cpu.io_ctl.steps()),
+        });
+        if self.records.len() > self.limit {
+            self.records.pop_front();
+        }
+        self.position = self.records.len();
+    }
+
+    /// Forgets everything, needed when the machine state is changed from the outside.
+    pub fn clear(&mut self) {
+        self.records.clear();
+        self.position = 0;
+        self.pending = None;
+    }
+
+    /// True if there are undone steps.
+    pub fn can_redo(&self) -> bool {
+        self.position < self.records.len()
+    }
+
+    /// Puts the machine back to the state before the last step, returns false at the start of the history.
+    /// Fails if a device doesn't take its old state anymore.
+    pub fn undo(&mut self, cpu: &mut CPU) -> Result<bool, String> {
+        if self.position == 0 {
+            return Ok(false);
+        }
+        self.position -= 1;
+        let record = &self.records[self.position];
+
+        for (address, old, _) in record.writes.iter().rev() {
+            cpu.memory.overwrite(*address, *old);
+        }
+        if let Some((before, _)) = &record.banks {
+            for (window, bank) in before.iter().enumerate() {
+                cpu.memory.select_bank(window, *bank);
+            }
+        }
+        if let Some((before, _)) = &record.written_once {
+            cpu.memory.set_written_once(before);
+        }
+        if let Some((before, _)) = record.rom_mapped {
+            cpu.memory.map_rom(before);
+        }
+        if let Some((before, _)) = &record.devices {
+            cpu.io_ctl.load_state(before)?;
+        }
+        cpu.io_ctl.set_steps(record.steps.0);
+        cpu.load_state(&record.before);
+        Ok(true)
+    }
+
+    /// Applies the next undone step again, returns false if there is none.
+    /// Fails if a device doesn't take the recorded state.
+    pub fn redo(&mut self, cpu: &mut CPU) -> Result<bool, String> {
+        let Some(record) = self.records.get(self.position) else { return Ok(false) };
+        self.position += 1;
+
+        for (address, _, new) in &record.writes {
+            cpu.memory.overwrite(*address, *new);
+        }
+        if let Some((_, after)) = &record.banks {
+            for (window, bank) in after.iter().enumerate() {
+                cpu.memory.select_bank(window, *bank);
+            }
+        }
+        if let Some((_, after)) = &record.written_once {
+            cpu.memory.set_written_once(after);
+        }
+        if let Some((_, after)) = record.rom_mapped {
+            cpu.memory.map_rom(after);
+        }
+        if let Some((_, after)) = &record.devices {
+            cpu.io_ctl.load_state(after)?;
+        }
+        cpu.io_ctl.set_steps(record.steps.1);
+        cpu.load_state(&record.after);
+        Ok(true)
+    }
+
+    /// The old and new value if the last undone step changed the memory at the physical address,
+    /// see [`MemoryControl::write_address`](crate::helium::memory::MemoryControl::write_address).
+    pub fn undone_write(&self, address: usize) -> Option<(u8, u8)> {
+        self.records.get(self.position)?
+            .writes.iter()
+            .find(|(written, _, _)| *written == address)
+            .map(|(_, old, new)| (*old, *new))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine::Machine;
+    use crate::tools::assembler::Assembler;
+
+    #[test]
+    fn undone_writes_into_the_ram_under_the_rom_are_found() {
+        let rom = Assembler::assemble("LDI r0, 9\nST r0, 0x00\nHLT").unwrap();
+        let mut machine = Machine::builder(rom).rom_control(0x5F).build().unwrap();
+        let mut history = History::new(10);
+        while machine.cpu.is_on {
+            history.before_step(&machine.cpu);
+            machine.step();
+            history.after_step(&machine.cpu);
+        }
+
+        history.undo(&mut machine.cpu).unwrap();
+        assert_eq!(history.undone_write(machine.cpu.memory.write_address(0x00).unwrap()), None);
+        history.undo(&mut machine.cpu).unwrap();
+        assert_eq!(history.undone_write(machine.cpu.memory.write_address(0x00).unwrap()), Some((0, 9)));
+        assert_eq!(history.undone_write(machine.cpu.memory.physical_address(0x00)), None);
+        assert_eq!(machine.cpu.program_counter(), 2);
+    }
+}
@@ -0,0 +1,16 @@
+/// Turns Helium assembly into rom images.
+pub mod assembler;
+/// Turns rom images back into Helium assembly.
+pub mod disassembler;
+/// The interactive step debugger used by --debug.
+pub mod debugger;
+/// The undo/redo log which lets the debugger step back.
+pub mod history;
+/// Lets GDB control the CPU over the remote serial protocol.
+pub mod gdb_stub;
+/// Runs ROM tests described by TOML spec files.
+pub mod test_runner;
+/// Writes a record of every executed instruction into a file.
+pub mod tracer;
+/// The full-screen terminal UI used by --tui.
+pub mod tui;
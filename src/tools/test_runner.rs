@@ -0,0 +1,279 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use serde::Deserialize;
+use crate::boot;
+use crate::devices::stdout_ascii_buffer::CharIOBuffer;
+use crate::devices::telnet_terminal::TelnetTerminal;
+use crate::config::DeviceType;
+use crate::helium::cpu::{Flag, CPU};
+use crate::helium::faults::{FaultKind, FaultPolicy};
+use crate::helium::io_controller::IOController;
+use crate::helium::isa::Extensions;
+use crate::helium::memory::{BankLayout, MemoryRegion};
+use crate::machine::{load_banked_rom, load_rom, Machine};
+use crate::tools::assembler::Assembler;
+use crate::tools::debugger::parse_byte;
+
+/// A ROM test, read from a TOML file like this:
+/// ```toml
+/// rom = "hello.bin"             # or `source = "hello.s"`, paths are relative to the spec file
+///                               # or `boot = true` for the boot ROM, which boots the disk image
+/// max_steps = 10000
+/// stack = true                  # enables the stack extension
+/// nested_interrupts = 2
+/// devices = ["char-buffer", "term-link"]
+/// terminal_input = "hi\r"       # typed into the term-link
+/// disk_image = "data.img"       # for the disk device, mounted read only so tests can't change it
+/// rom_control = 0x5F            # the ROM control register, always there with boot
+///
+/// [banks]                       # optional, like in a machine config
+/// size = 0x40
+/// count = 4
+/// select = 0x50
+///
+/// [faults]                      # optional, like in a machine config
+/// rom_write = "halt"
+///
+/// [[region]]                    # optional memory map, like in a machine config
+/// type = "unmapped"
+/// start = 0xF0
+/// end = 0xFF
+///
+/// [expect]
+/// halted = true                 # the default
+/// fault = "rom-write"           # the fault which halted it, halting by a fault fails the test without this
+/// char_buffer = "HELLO WORLD"
+/// terminal_output = "hi\r"
+///
+/// [expect.registers]
+/// r1 = 0x0D
+///
+/// [expect.flags]
+/// zero = true
+///
+/// [expect.memory]
+/// "0x80" = [1, 1, 2, 3]
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TestSpec {
+    rom: Option<PathBuf>,
+    source: Option<PathBuf>,
+    #[serde(default)]
+    boot: bool,
+    #[serde(default = "default_max_steps")]
+    max_steps: u64,
+    #[serde(default)]
+    stack: bool,
+    nested_interrupts: Option<u8>,
+    banks: Option<BankLayout>,
+    #[serde(default, rename = "region")]
+    regions: Vec<MemoryRegion>,
+    #[serde(default)]
+    faults: FaultPolicy,
+    #[serde(default)]
+    devices: Vec<DeviceType>,
+    terminal_input: Option<String>,
+    disk_image: Option<PathBuf>,
+    rom_control: Option<u8>,
+    #[serde(default)]
+    expect: Expectations,
+}
+
+/// What should be true after the run.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Expectations {
+    halted: Option<bool>,
+    fault: Option<FaultKind>,
+    #[serde(default)]
+    registers: BTreeMap<String, u8>,
+    #[serde(default)]
+    flags: BTreeMap<String, bool>,
+    #[serde(default)]
+    memory: BTreeMap<String, Vec<u8>>,
+    char_buffer: Option<String>,
+    terminal_output: Option<String>,
+}
+
+fn default_max_steps() -> u64 { 100_000 }
+
+/// The result of a test which could be run.
+#[derive(Debug)]
+pub struct TestOutcome {
+    pub steps: u64,
+    /// One line for every expectation that didn't hold, empty if the test passed.
+    pub failures: Vec<String>,
+}
+
+impl TestSpec {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Could not read test spec: {}", e))?;
+
+        toml::from_str(&text).map_err(|e| format!("Invalid test spec: {}", e))
+    }
+
+    /// Loads (or assembles) the ROM, runs it and checks the expectations.
+    /// Paths in the spec are relative to `base_dir`.
+    pub fn run(&self, base_dir: &Path) -> Result<TestOutcome, String> {
+        let rom = match (&self.rom, &self.source) {
+            (None, None) if self.boot => boot::rom(),
+            (Some(rom), None) if !self.boot => match &self.banks {
+                Some(layout) => load_banked_rom(&base_dir.join(rom), layout)?,
+                None => load_rom(&base_dir.join(rom))?,
+            },
+            (None, Some(source)) => {
+                let text = fs::read_to_string(base_dir.join(source))
+                    .map_err(|e| format!("Could not read source file: {}", e))?;
+                Assembler::assemble_for(&text, self.extensions())?
+            }
+            _ => return Err("A test needs either a rom, a source or boot".to_string()),
+        };
+
+        let mut io_ctl = IOController::new(false);
+
+        for device in &self.devices {
+            let mut config = device.default_config();
+
+            if *device == DeviceType::TermLink {
+                // The terminal gets its input from the spec instead of TCP.
+                let input = self.terminal_input.as_deref().unwrap_or("");
+                let code = config.interrupt_code.unwrap_or(0);
+                config.mount_as(&mut io_ctl, TelnetTerminal::scripted(code, input.as_bytes()))?;
+            } else {
+                if *device == DeviceType::Disk {
+                    config.image = self.disk_image.as_ref().map(|image| base_dir.join(image));
+                    config.read_only = Some(true);
+                }
+                config.mount(&mut io_ctl)?;
+            }
+        }
+
+        if self.terminal_input.is_some() && !self.devices.contains(&DeviceType::TermLink) {
+            return Err("terminal_input needs the term-link device".to_string());
+        }
+        if self.disk_image.is_some() && !self.devices.contains(&DeviceType::Disk) {
+            return Err("disk_image needs the disk device".to_string());
+        }
+        if self.nested_interrupts == Some(0) {
+            return Err("nested_interrupts has to be at least 1".to_string());
+        }
+
+        let mut builder = Machine::builder(rom)
+            .io_controller(io_ctl)
+            .regions(self.regions.clone())
+            .fault_policy(self.faults)
+            .extensions(self.extensions());
+        if let Some(layout) = self.banks {
+            builder = builder.banks(layout);
+        }
+        match (self.boot, self.rom_control) {
+            (true, Some(address)) if address != boot::ROM_CONTROL => {
+                return Err(format!("The boot ROM needs the ROM control register at {:02X}", boot::ROM_CONTROL));
+            }
+            (true, _) => builder = builder.rom_control(boot::ROM_CONTROL),
+            (false, Some(address)) => builder = builder.rom_control(address),
+            (false, None) => {}
+        }
+        let mut machine = builder.build()?;
+        let halted = machine.run(self.max_steps);
+
+        let failures = self.expect.check(&machine, halted)?;
+        Ok(TestOutcome { steps: machine.steps, failures })
+    }
+
+    fn extensions(&self) -> Extensions {
+        Extensions { stack: self.stack, interrupt_nesting: self.nested_interrupts }
+    }
+}
+
+impl Expectations {
+    fn check(&self, machine: &Machine, halted: bool) -> Result<Vec<String>, String> {
+        let cpu = &machine.cpu;
+        let mut failures = Vec::new();
+
+        let expected_halt = self.halted.unwrap_or(true);
+        if halted != expected_halt {
+            failures.push(if halted {
+                format!("halted after {} steps", machine.steps)
+            } else {
+                format!("didn't halt within {} steps", machine.steps)
+            });
+        }
+
+        let fault = cpu.halt_fault();
+        match (fault, self.fault) {
+            (Some(fault), Some(expected)) if fault.kind == expected => {}
+            (Some(fault), _) => failures.push(format!("halted by a fault: {}", fault)),
+            (None, Some(expected)) => failures.push(format!("expected to be halted by a {:?} fault", expected)),
+            (None, None) => {}
+        }
+
+        for (name, expected) in &self.registers {
+            let actual = register(cpu, name)?;
+            if actual != *expected {
+                failures.push(format!("{}: expected {:#04X}, got {:#04X}", name, expected, actual));
+            }
+        }
+
+        for (name, expected) in &self.flags {
+            let flag = match name.as_str() {
+                "zero" => Flag::Zero,
+                "signed" => Flag::Signed,
+                "carry" => Flag::Carry,
+                "overflow" => Flag::Overflow,
+                _ => return Err(format!("Unknown flag '{}'", name)),
+            };
+            if cpu.flag(flag) != *expected {
+                failures.push(format!("{} flag: expected {}, got {}", name, expected, cpu.flag(flag)));
+            }
+        }
+
+        for (address, expected) in &self.memory {
+            let start = parse_byte(Some(address)).map_err(|e| format!("Memory address: {}", e))?;
+            let actual = (0..expected.len())
+                .map(|offset| cpu.memory.get(start.wrapping_add(offset as u8)))
+                .collect::<Vec<u8>>();
+
+            if actual != *expected {
+                failures.push(format!("mem[{:#04X}..]: expected {:02X?}, got {:02X?}", start, expected, actual));
+            }
+        }
+
+        if let Some(expected) = &self.char_buffer {
+            let buffer = machine.find_device::<CharIOBuffer>()
+                .ok_or("char_buffer needs the char-buffer device")?;
+            let actual = buffer.as_ascii_str();
+            let actual = actual.trim_end_matches('\0');
+
+            if actual != expected {
+                failures.push(format!("char buffer: expected {:?}, got {:?}", expected, actual));
+            }
+        }
+
+        if let Some(expected) = &self.terminal_output {
+            let terminal = machine.find_device::<TelnetTerminal>()
+                .ok_or("terminal_output needs the term-link device")?;
+            let actual = String::from_utf8_lossy(terminal.output());
+
+            if actual != *expected {
+                failures.push(format!("terminal output: expected {:?}, got {:?}", expected, actual));
+            }
+        }
+
+        Ok(failures)
+    }
+}
+
+fn register(cpu: &CPU, name: &str) -> Result<u8, String> {
+    match name {
+        "r0" | "r1" | "r2" | "r3" => Ok(cpu.register((name.as_bytes()[1] - b'0') as usize)),
+        "pc" => Ok(cpu.program_counter()),
+        "sc" => Ok(cpu.secondary_counter()),
+        "ia" => Ok(cpu.interrupt_addr()),
+        "sp" => Ok(cpu.stack_pointer()),
+        _ => Err(format!("Unknown register '{}', expected r0-r3, pc, sc, ia or sp", name)),
+    }
+}
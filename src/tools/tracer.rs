@@ -0,0 +1,167 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use clap::ValueEnum;
+use serde::Serialize;
+use crate::helium::cpu::{BusEvent, CpuState, CPU};
+use crate::tools::disassembler;
+
+/// The magic bytes at the start of a binary trace, followed by the format version.
+const BINARY_MAGIC: &[u8; 4] = b"HTRC";
+const BINARY_VERSION: u8 = 2;
+
+/// How the trace gets written.
+#[derive(ValueEnum, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TraceFormat {
+    /// One JSON object per instruction and line.
+    Jsonl,
+    /// A compact binary record per instruction, see [`Tracer`].
+    #[value(help = "A compact binary record per instruction, the layout is in the docs of Tracer")]
+    Binary,
+}
+
+/// Writes a record of every executed instruction: the address, the opcode and its operands,
+/// the disassembly, the changed registers and flags, and the bus events (memory writes, IO, interrupts).
+///
+/// # Binary format:
+/// The file starts with "HTRC" and the version byte, then every record is
+/// ```text
+/// step: u64 (LE) | pc | opcode | operand count | operands..
+/// | change count | (register, old, new)..   registers: 0-3: r0-r3, 4: sc, 5: ia, 6: flags, 7: sp
+/// | event count | (tag, a, b, c)..          tags: 0: memory write (address, old, new), 1: IO read (address, value, 0),
+///                                                 2: IO write (address, value, 0), 3: interrupt entry (code, software, return address),
+///                                                 4: interrupt exit (return address, 0, 0)
+/// ```
+/// The disassembly is left out of the binary format, it can be made from the opcode and the operands.
+#[derive(Debug)]
+pub struct Tracer {
+    writer: BufWriter<File>,
+    format: TraceFormat,
+    step: u64,
+    /// The CPU state before the instruction which is being executed.
+    before: Option<CpuState>,
+    /// The first write error, the trace stops there and finish reports it.
+    error: Option<String>,
+}
+
+/// An instruction in the JSON trace.
+#[derive(Serialize)]
+struct TraceRecord<'a> {
+    step: u64,
+    pc: u8,
+    opcode: u8,
+    operands: &'a [u8],
+    text: &'a str,
+    /// The registers (and flags) which changed, as [old, new].
+    changes: BTreeMap<&'static str, [u8; 2]>,
+    events: &'a [BusEvent],
+}
+
+const CHANGE_NAMES: [&str; 8] = ["r0", "r1", "r2", "r3", "sc", "ia", "flags", "sp"];
+
+impl Tracer {
+    pub fn create(path: &Path, format: TraceFormat) -> Result<Self, String> {
+        let file = File::create(path)
+            .map_err(|e| format!("Could not create trace file: {}", e))?;
+
+        let mut tracer = Self { writer: BufWriter::new(file), format, step: 0, before: None, error: None };
+        if format == TraceFormat::Binary {
+            tracer.write(&[BINARY_MAGIC.as_slice(), &[BINARY_VERSION]].concat());
+        }
+        Ok(tracer)
+    }
+
+    /// Has to be called right before `CPU::next`.
+    pub fn before_step(&mut self, cpu: &mut CPU) {
+        cpu.set_bus_logging(true);
+        self.before = Some(cpu.save_state());
+    }
+
+    /// Has to be called right after `CPU::next`, writes the record of the instruction.
+    pub fn after_step(&mut self, cpu: &mut CPU) {
+        let Some(before) = self.before.take() else { return };
+        let events = cpu.take_bus_events();
+        let after = cpu.save_state();
+
+        let old = Self::traced_values(&before);
+        let new = Self::traced_values(&after);
+        let changes = (0..CHANGE_NAMES.len())
+            .filter(|i| old[*i] != new[*i])
+            .map(|i| (i, old[i], new[i]))
+            .collect::<Vec<(usize, u8, u8)>>();
+
+        let pc = cpu.instruction_addr();
+        let line = disassembler::disassemble_at(pc, cpu.extensions(), |address| Some(cpu.memory.get(address)));
+        let operands = &line.bytes[1..];
+
+        match self.format {
+            TraceFormat::Jsonl => {
+                let record = TraceRecord {
+                    step: self.step,
+                    pc,
+                    opcode: cpu.instruction_reg(),
+                    operands,
+                    text: &line.text,
+                    changes: changes.iter().map(|(i, old, new)| (CHANGE_NAMES[*i], [*old, *new])).collect(),
+                    events: &events,
+                };
+
+                match serde_json::to_string(&record) {
+                    Ok(json) => self.write(format!("{}\n", json).as_bytes()),
+                    Err(e) => self.error = Some(format!("Could not serialize trace record: {}", e)),
+                }
+            }
+            TraceFormat::Binary => {
+                let mut record = self.step.to_le_bytes().to_vec();
+                record.extend([pc, cpu.instruction_reg(), operands.len() as u8]);
+                record.extend(operands);
+
+                record.push(changes.len() as u8);
+                for (i, old, new) in &changes {
+                    record.extend([*i as u8, *old, *new]);
+                }
+
+                record.push(events.len() as u8);
+                for event in &events {
+                    record.extend(Self::encode_event(event));
+                }
+                self.write(&record);
+            }
+        }
+        self.step += 1;
+    }
+
+    /// Flushes the trace, returns the first error which happened while tracing.
+    pub fn finish(mut self) -> Result<(), String> {
+        if let Some(error) = self.error.take() {
+            return Err(error);
+        }
+        self.writer.flush().map_err(|e| format!("Could not write trace: {}", e))
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        if self.error.is_some() {
+            return;
+        }
+        if let Err(e) = self.writer.write_all(bytes) {
+            self.error = Some(format!("Could not write trace: {}", e));
+        }
+    }
+
+    /// The values in the order of CHANGE_NAMES.
+    fn traced_values(state: &CpuState) -> [u8; 8] {
+        let [r0, r1, r2, r3] = state.registers;
+        [r0, r1, r2, r3, state.secondary_counter, state.interrupt_addr, state.flags, state.stack_pointer]
+    }
+
+    fn encode_event(event: &BusEvent) -> [u8; 4] {
+        match *event {
+            BusEvent::MemoryWrite { address, old_value, new_value } => [0, address, old_value, new_value],
+            BusEvent::IoRead { address, value } => [1, address, value, 0],
+            BusEvent::IoWrite { address, value } => [2, address, value, 0],
+            BusEvent::InterruptEntry { code, software, return_address } => [3, code, software as u8, return_address],
+            BusEvent::InterruptExit { return_address } => [4, return_address, 0, 0],
+        }
+    }
+}
@@ -0,0 +1,418 @@
+use std::collections::BTreeSet;
+use std::time::Duration;
+use crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Paragraph, Wrap};
+use ratatui::{DefaultTerminal, Frame};
+use crate::helium::cpu::{Flag, CPU};
+use crate::scheduler::Scheduler;
+use crate::tools::disassembler::{self, DisassembledLine};
+
+const HELP: &str = "[space] run/pause [s] step [t] turbo [+/-] speed [tab] pane [arrows] move [b] breakpoint [f] follow PC [q] quit";
+
+/// The width of the memory pane, 16 bytes per row.
+const MEMORY_WIDTH: u16 = 4 + 16 * 3 + 2;
+
+/// Which pane the arrow keys move the cursor in.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Focus {
+    Disassembly,
+    Memory,
+}
+
+/// A full-screen terminal UI with panes for the registers, the disassembly around the PC, the memory,
+/// the breakpoints and every device. Only the changed cells get redrawn, so it doesn't flicker.
+///
+/// The CPU is paced by a [`Scheduler`], the UI pauses it on breakpoints and steps it on request.
+pub struct Tui {
+    terminal: DefaultTerminal,
+    view: View,
+    /// The first drawing error, the UI stops drawing there and leave reports it.
+    error: Option<String>,
+}
+
+/// Everything the panes show besides the machine.
+#[derive(Debug)]
+struct View {
+    breakpoints: BTreeSet<u8>,
+    /// The PC the CPU was resumed at, so a breakpoint there doesn't stop it again immediately.
+    resumed_at: Option<u8>,
+    focus: Focus,
+    /// The selected disassembly line, None follows the PC.
+    code_cursor: Option<u8>,
+    memory_cursor: u8,
+    /// Why the CPU stopped, shown in the status line.
+    message: String,
+}
+
+impl Tui {
+    /// Switches the terminal to the alternate screen, it gets restored when the UI is dropped.
+    pub fn enter(breakpoints: &[u8]) -> Result<Self, String> {
+        let terminal = ratatui::try_init()
+            .map_err(|e| format!("Could not set up the terminal: {}", e))?;
+
+        let view = View {
+            breakpoints: breakpoints.iter().copied().collect(),
+            resumed_at: None,
+            focus: Focus::Disassembly,
+            code_cursor: None,
+            memory_cursor: 0,
+            message: String::new(),
+        };
+        Ok(Self { terminal, view, error: None })
+    }
+
+    /// Has to be called before every instruction, true if the CPU is on a breakpoint and has to be paused.
+    pub fn breakpoint_hit(&mut self, cpu: &CPU) -> bool {
+        let pc = cpu.program_counter();
+        let resumed_at = self.view.resumed_at.take();
+
+        if self.view.breakpoints.contains(&pc) && resumed_at != Some(pc) {
+            self.view.message = format!("Breakpoint hit at {:02X}", pc);
+            self.view.code_cursor = None;
+            return true;
+        }
+        false
+    }
+
+    /// Shows the logged faults of the last instruction and the fault which halted the CPU in the status line.
+    pub fn report_faults(&mut self, cpu: &mut CPU) {
+        if let Some(fault) = cpu.take_faults().last() {
+            self.view.message = format!("Fault: {}", fault);
+        }
+        if let Some(fault) = cpu.halt_fault() {
+            self.view.message = format!("Halted by a fault: {}", fault);
+        }
+    }
+
+    /// Waits up to the timeout for key presses and applies them, returns true if the user wants to quit.
+    pub fn handle_keys(&mut self, cpu: &CPU, scheduler: &mut Scheduler, timeout: Duration) -> bool {
+        let mut timeout = timeout;
+
+        while event::poll(timeout).unwrap_or(false) {
+            timeout = Duration::ZERO;
+            let Ok(Event::Key(key)) = event::read() else { continue };
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+
+            match key.code {
+                KeyCode::Char('q') | KeyCode::Esc => return true,
+                KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => return true,
+
+                KeyCode::Char(' ') => {
+                    scheduler.toggle_pause();
+                    self.view.resume(cpu);
+                }
+                KeyCode::Char('s') => {
+                    scheduler.pause();
+                    scheduler.step_once();
+                    self.view.resume(cpu);
+                }
+                KeyCode::Char('t') => scheduler.toggle_turbo(),
+                KeyCode::Char('+') | KeyCode::Char('=') => scheduler.faster(),
+                KeyCode::Char('-') => scheduler.slower(),
+
+                KeyCode::Tab => {
+                    self.view.focus = match self.view.focus {
+                        Focus::Disassembly => Focus::Memory,
+                        Focus::Memory => Focus::Disassembly,
+                    };
+                }
+                KeyCode::Char('b') => {
+                    let address = match self.view.focus {
+                        Focus::Disassembly => self.view.code_cursor.unwrap_or(cpu.program_counter()),
+                        Focus::Memory => self.view.memory_cursor,
+                    };
+                    if !self.view.breakpoints.remove(&address) {
+                        self.view.breakpoints.insert(address);
+                    }
+                }
+                KeyCode::Char('f') => self.view.code_cursor = None,
+
+                KeyCode::Up => self.view.move_cursor(cpu, -1),
+                KeyCode::Down => self.view.move_cursor(cpu, 1),
+                KeyCode::PageUp => self.view.move_cursor(cpu, -8),
+                KeyCode::PageDown => self.view.move_cursor(cpu, 8),
+                KeyCode::Left if self.view.focus == Focus::Memory => self.view.memory_cursor = self.view.memory_cursor.wrapping_sub(1),
+                KeyCode::Right if self.view.focus == Focus::Memory => self.view.memory_cursor = self.view.memory_cursor.wrapping_add(1),
+                _ => {}
+            }
+        }
+        false
+    }
+
+    /// Redraws the panes, `status` is the scheduler status.
+    pub fn draw(&mut self, cpu: &mut CPU, status: &str) {
+        if self.error.is_some() {
+            return;
+        }
+
+        let devices = cpu.io_ctl.draw_device_uis(false, true);
+        let status = match cpu.is_on {
+            true => format!("{} {}", status, self.view.message),
+            false => format!("halted at {:02X}", cpu.program_counter()),
+        };
+
+        let result = self.terminal.draw(|frame| {
+            let [main, status_area] = Layout::vertical([Constraint::Min(0), Constraint::Length(1)]).areas(frame.area());
+            let [left, right] = Layout::horizontal([Constraint::Length(MEMORY_WIDTH), Constraint::Min(0)]).areas(main);
+            let [registers, memory, breakpoints] = Layout::vertical([
+                Constraint::Length(10),
+                Constraint::Length(18),
+                Constraint::Min(0),
+            ]).areas(left);
+
+            let mut right_constraints = vec![Constraint::Min(5)];
+            right_constraints.extend(devices.iter().map(|(_, ui)| {
+                let lines = ui.as_deref().map_or(1, |ui| ui.lines().count().max(1));
+                Constraint::Length(lines as u16 + 2)
+            }));
+            let right_areas = Layout::vertical(right_constraints).split(right);
+
+            let view = &self.view;
+            draw_registers(frame, registers, cpu);
+            view.draw_memory(frame, memory, cpu);
+            view.draw_breakpoints(frame, breakpoints, cpu);
+            view.draw_disassembly(frame, right_areas[0], cpu);
+
+            for ((title, ui), area) in devices.iter().zip(right_areas.iter().skip(1)) {
+                let text = ui.as_deref().map_or("(no UI)".to_string(), sanitize);
+                let pane = Paragraph::new(text)
+                    .wrap(Wrap { trim: false })
+                    .block(Block::bordered().title(format!(" {} ", title)));
+                frame.render_widget(pane, *area);
+            }
+
+            let status_line = Line::from(vec![
+                Span::styled(status, Style::new().fg(Color::Yellow)),
+                Span::raw(" | "),
+                Span::styled(HELP, Style::new().add_modifier(Modifier::DIM)),
+            ]);
+            frame.render_widget(Paragraph::new(status_line), status_area);
+        });
+
+        if let Err(e) = result {
+            self.error = Some(format!("Could not draw the UI: {}", e));
+        }
+    }
+
+    /// Restores the terminal and reports the first drawing error.
+    pub fn leave(self) -> Result<(), String> {
+        match &self.error {
+            Some(error) => Err(error.clone()),
+            None => Ok(()),
+        }
+    }
+}
+
+impl View {
+    fn resume(&mut self, cpu: &CPU) {
+        self.resumed_at = Some(cpu.program_counter());
+        self.message.clear();
+    }
+
+    /// Moves the cursor of the focused pane by lines.
+    fn move_cursor(&mut self, cpu: &CPU, lines: i32) {
+        match self.focus {
+            Focus::Memory => self.memory_cursor = self.memory_cursor.wrapping_add((lines * 16) as u8),
+            Focus::Disassembly => {
+                let listing = listing(cpu);
+                let current = self.code_cursor.unwrap_or(cpu.program_counter());
+                let index = listing.iter().rposition(|line| line.address <= current).unwrap_or(0);
+                let index = (index as i32 + lines).clamp(0, listing.len() as i32 - 1);
+                self.code_cursor = Some(listing[index as usize].address);
+            }
+        }
+    }
+
+    fn draw_memory(&self, frame: &mut Frame, area: Rect, cpu: &CPU) {
+        let focused = self.focus == Focus::Memory;
+
+        let lines = (0..16u8).map(|row| {
+            let mut spans = vec![Span::styled(format!("{:02X}: ", row * 16), Style::new().fg(Color::Green))];
+
+            for column in 0..16u8 {
+                let address = row * 16 + column;
+                let value = cpu.memory.get(address);
+
+                let mut style = Style::new();
+                if value == 0 {
+                    style = style.add_modifier(Modifier::DIM);
+                }
+                if address == cpu.program_counter() {
+                    style = style.fg(Color::LightGreen).add_modifier(Modifier::BOLD);
+                }
+                if self.breakpoints.contains(&address) {
+                    style = style.fg(Color::Red);
+                }
+                if address == self.memory_cursor && focused {
+                    style = style.add_modifier(Modifier::REVERSED);
+                }
+                spans.push(Span::styled(format!("{:02X}", value), style));
+                spans.push(Span::raw(" "));
+            }
+            Line::from(spans)
+        }).collect::<Vec<Line>>();
+
+        let mut title = format!(" Memory [{:02X}] = {:02X} ", self.memory_cursor, cpu.memory.get(self.memory_cursor));
+        if cpu.memory.banks().is_some() {
+            title.push_str(&format!("banks {:?} ", cpu.memory.selected_banks()));
+        }
+        if !cpu.memory.rom_mapped() {
+            title.push_str("rom unmapped ");
+        }
+        frame.render_widget(Paragraph::new(lines).block(pane(title, focused)), area);
+    }
+
+    fn draw_breakpoints(&self, frame: &mut Frame, area: Rect, cpu: &CPU) {
+        let lines = match self.breakpoints.is_empty() {
+            true => vec![Line::styled("none, press b to set one", Style::new().add_modifier(Modifier::DIM))],
+            false => self.breakpoints.iter().map(|address| {
+                let line = disassembler::disassemble_at(*address, cpu.extensions(), |addr| Some(cpu.memory.get(addr)));
+                Line::raw(format!("{:02X}: {}", address, line.text))
+            }).collect(),
+        };
+
+        frame.render_widget(Paragraph::new(lines).block(Block::bordered().title(" Breakpoints ")), area);
+    }
+
+    fn draw_disassembly(&self, frame: &mut Frame, area: Rect, cpu: &CPU) {
+        let focused = self.focus == Focus::Disassembly;
+        let pc = cpu.program_counter();
+        let selected = self.code_cursor.unwrap_or(pc);
+
+        let listing = listing(cpu);
+        let selected_index = listing.iter().rposition(|line| line.address <= selected).unwrap_or(0);
+        let height = area.height.saturating_sub(2) as usize;
+        let first = selected_index.saturating_sub(height / 3);
+
+        let lines = listing.iter().skip(first).take(height).map(|line| {
+            let marker = if line.address == pc { "=>" } else { "  " };
+            let breakpoint = if self.breakpoints.contains(&line.address) { "●" } else { " " };
+            let bytes = line.bytes.iter().map(|byte| format!("{:02X}", byte)).collect::<Vec<String>>().join(" ");
+
+            let mut style = Style::new();
+            if line.address == pc {
+                style = style.fg(Color::LightGreen).add_modifier(Modifier::BOLD);
+            }
+            if line.address == selected && focused {
+                style = style.add_modifier(Modifier::REVERSED);
+            }
+
+            Line::from(vec![
+                Span::styled(breakpoint, Style::new().fg(Color::Red)),
+                Span::styled(format!("{} {:02X}: {:<6} {}", marker, line.address, bytes, line.text), style),
+            ])
+        }).collect::<Vec<Line>>();
+
+        frame.render_widget(Paragraph::new(lines).block(pane(" Disassembly ".to_string(), focused)), area);
+    }
+}
+
+impl Drop for Tui {
+    fn drop(&mut self) {
+        ratatui::restore();
+    }
+}
+
+impl std::fmt::Debug for Tui {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Tui")
+            .field("view", &self.view)
+            .finish_non_exhaustive()
+    }
+}
+
+fn draw_registers(frame: &mut Frame, area: Rect, cpu: &CPU) {
+    let name = Style::new().fg(Color::Green).add_modifier(Modifier::BOLD);
+
+    let mut lines = (0..4).map(|i| {
+        let value = cpu.register(i);
+        Line::from(vec![
+            Span::styled(format!("r{}: ", i), name),
+            Span::raw(format!("{:02X}  {:08b}  {:>3}", value, value, value)),
+        ])
+    }).collect::<Vec<Line>>();
+
+    let pair = |label: &str, value: u8| vec![
+        Span::styled(format!("{}: ", label), name),
+        Span::raw(format!("{:02X}  ", value)),
+    ];
+    lines.push(Line::from([pair("PC", cpu.program_counter()), pair("SC", cpu.secondary_counter()),
+                           pair("IA", cpu.interrupt_addr())].concat()));
+    let mut third = [pair("IR", cpu.instruction_reg()), pair("IC", cpu.interrupt_code())].concat();
+    if cpu.extensions().stack {
+        third.extend(pair("SP", cpu.stack_pointer()));
+    }
+    if let Some(depth) = cpu.extensions().interrupt_nesting {
+        third.push(Span::styled("IL: ", name));
+        third.push(Span::raw(format!("{}/{}", cpu.interrupt_level(), depth)));
+    }
+    lines.push(Line::from(third));
+
+    let flag = |label: &'static str, set: bool| match set {
+        true => Span::styled(format!("{} ", label), Style::new().fg(Color::LightGreen)),
+        false => Span::styled(format!("{} ", label), Style::new().add_modifier(Modifier::DIM)),
+    };
+    lines.push(Line::from(vec![
+        flag("ZE", cpu.flag(Flag::Zero)), flag("SI", cpu.flag(Flag::Signed)),
+        flag("CA", cpu.flag(Flag::Carry)), flag("OV", cpu.flag(Flag::Overflow)),
+        flag("IE", cpu.interrupt_enabled()), flag("IN", cpu.in_interrupt()),
+        flag("IQ", cpu.interrupt_requested()), flag("ON", cpu.is_on),
+    ]));
+    lines.push(Line::from(vec![Span::styled("Cycles: ", name), Span::raw(cpu.cycles().to_string())]));
+
+    frame.render_widget(Paragraph::new(lines).block(Block::bordered().title(" Registers ")), area);
+}
+
+/// A bordered pane, the focused one is highlighted.
+fn pane(title: String, focused: bool) -> Block<'static> {
+    let block = Block::bordered().title(title);
+    match focused {
+        true => block.border_style(Style::new().fg(Color::Cyan)),
+        false => block,
+    }
+}
+
+/// Disassembles the whole memory from address 0, an instruction which would swallow the PC
+/// is cut there, so the instruction at the PC always gets its own line.
+fn listing(cpu: &CPU) -> Vec<DisassembledLine> {
+    let pc = cpu.program_counter() as usize;
+    let mut lines = Vec::new();
+    let mut address: usize = 0;
+
+    while address < 256 {
+        let line = disassembler::disassemble_at(address as u8, cpu.extensions(), |addr| Some(cpu.memory.get(addr)));
+        let end = address + line.bytes.len().max(1);
+        lines.push(line);
+
+        address = if address < pc && pc < end { pc } else { end };
+    }
+    lines
+}
+
+/// Drops the ANSI escapes and control characters of a device UI, ratatui does the styling.
+fn sanitize(text: &str) -> String {
+    let mut out = String::new();
+    let mut chars = text.chars();
+
+    while let Some(ch) = chars.next() {
+        match ch {
+            '\x1b' => {
+                // Skips "ESC [ ... letter"
+                for ch in chars.by_ref() {
+                    if ch.is_ascii_alphabetic() {
+                        break;
+                    }
+                }
+            }
+            '\n' => out.push('\n'),
+            ch if ch.is_control() => out.push(' '),
+            ch => out.push(ch),
+        }
+    }
+    out
+}